@@ -25,6 +25,13 @@ def convert_matrix(m):
 def rename(name):
     return name.replace(" ", "_").lower()
 
+def socket_key(prefix, socket, sockets):
+    # Sockets sharing a name, like the two inputs of a Mix Shader, are told apart by their
+    # identifiers, e.g. Shader and Shader_001
+    names = [s.name for s in sockets]
+    name = socket.identifier if names.count(socket.name) > 1 else socket.name
+    return prefix + "_" + rename(name)
+
 def main():
     if "--" in sys.argv:
         args = sys.argv[sys.argv.index("--") + 1:]
@@ -47,6 +54,7 @@ def main():
     eprint()
 
     out_objects = {} 
+    exported_meshes = set()
     depsgraph = bpy.context.evaluated_depsgraph_get()
     for object_inst in depsgraph.object_instances:
         object = object_inst.object
@@ -55,38 +63,66 @@ def main():
         out_object = dict()
         out_object["name"] = object.name
         out_object["type"] = object.type
+        if object_inst.is_instance:
+            out_object["name"] += "." + ".".join(str(i) for i in object_inst.persistent_id)
 
         if object.type == "MESH":
-            out_object["matrix"] = convert_matrix(object.matrix_world)
-
+            out_object["matrix"] = convert_matrix(object_inst.matrix_world)
+            # Modifiers make the evaluated mesh differ from other users of the same data
+            mesh_key = object.data.name
+            if object.modifiers:
+                mesh_key += "@" + object.name
+            out_object["mesh"] = mesh_key
+
+        if object.type == "MESH" and mesh_key in exported_meshes:
+            eprint(f"\tinstance of {mesh_key}")
+        elif object.type == "MESH":
+            exported_meshes.add(mesh_key)
             mesh = object.to_mesh()
             mesh.calc_loop_triangles()
             eprint(f"\t{len(mesh.loop_triangles)} triangles")
             uv_layer = mesh.uv_layers.active.data
+            try:
+                mesh.calc_tangents()
+                has_tangents = True
+            except RuntimeError:
+                # Only possible for triangles and quads, the renderer derives them from the UVs then
+                eprint("\tno tangents")
+                has_tangents = False
             triangles = []
             for t in mesh.loop_triangles:
                 for loop_index in t.loops:
-                    vertex_index = mesh.loops[loop_index].vertex_index
-                    triangles.append({
+                    loop = mesh.loops[loop_index]
+                    vertex_index = loop.vertex_index
+                    vertex = {
                         "p": convert_vector(mesh.vertices[vertex_index].co),
                         "n": convert_vector(mesh.vertices[vertex_index].normal if t.use_smooth else t.normal),
-                        "t": convert_vector(uv_layer[loop_index].uv)
-                    })
+                        "t": convert_vector(uv_layer[loop_index].uv),
+                        "s": t.use_smooth
+                    }
+                    if has_tangents:
+                        vertex["g"] = convert_vector(loop.tangent) + [loop.bitangent_sign]
+                    triangles.append(vertex)
             object.to_mesh_clear()
             out_object["triangles"] = triangles
 
+        if object.type == "MESH":
             material = object.active_material
             out_material = dict()
             out_object["material"] = out_material
-            out_material["name"] = material.name
+            out_material["name"] = material.name if material else ""
+            out_material["backface_culling"] = material.use_backface_culling if material else False
             out_material["nodes"] = {}
-            for (node_name, node) in material.node_tree.nodes.items():
+            # Without a node tree the importer falls back to a default material
+            node_tree = material.node_tree if material else None
+            for (node_name, node) in (node_tree.nodes.items() if node_tree else []):
                 out_node = {}
                 out_node["name"] = node_name
                 out_node["type"] = node.type
-                for prefix, items in (("in", node.inputs.items()), ("out", node.outputs.items())):
-                    for (name, value) in items:
-                        key = prefix + "_" + rename(name)
+                for prefix, sockets in (("in", node.inputs), ("out", node.outputs)):
+                    for value in sockets:
+                        name = value.name
+                        key = socket_key(prefix, value, sockets)
                         if value.type == "VALUE":
                             out_node[key] = { "type": "VALUE", "value": value.default_value }
                         elif value.type == "RGBA":
@@ -105,8 +141,8 @@ def main():
                     out_node["filepath"] = node.image.filepath
                     out_node["colorspace"] = node.image.colorspace_settings.name
                 out_material["nodes"][out_node["name"]] = out_node
-            for link in material.node_tree.links:
-                out_material["nodes"][link.to_node.name]["in_" + rename(link.to_socket.name)] = {
+            for link in (node_tree.links if node_tree else []):
+                out_material["nodes"][link.to_node.name][socket_key("in", link.to_socket, link.to_node.inputs)] = {
                     "type": "LINK",
                     "from_node": link.from_node.name,
                     "from_socket": rename(link.from_socket.name),
@@ -118,19 +154,25 @@ def main():
             out_object["specular"] = object.data.specular_factor
             out_object["radius"] = object.data.shadow_soft_size
             out_object["attenuation"] = [0.00111109, 0.0, 1.0]
-            out_object["matrix"] = convert_matrix(object.matrix_world)
+            out_object["matrix"] = convert_matrix(object_inst.matrix_world)
         elif object.type == "CAMERA":
-            out_object["matrix"] = convert_matrix(object.matrix_world)
+            out_object["matrix"] = convert_matrix(object_inst.matrix_world)
             out_object["xfov"] = object.data.angle_x
             out_object["yfov"] = object.data.angle_y
+            out_object["sensor_fit"] = object.data.sensor_fit
             out_object["znear"] = object.data.clip_start
             out_object["zfar"] = object.data.clip_end
+            render = bpy.context.scene.render
+            out_object["pixel_aspect"] = render.pixel_aspect_x / render.pixel_aspect_y
             out_object["camera_type"] = object.data.type
+            out_object["ortho_scale"] = object.data.ortho_scale
 
-        out_objects[object.name] = out_object
+        out_objects[out_object["name"]] = out_object
 
+    active_camera = bpy.context.scene.camera
     out = {
         "objects": out_objects,
+        "active_camera": active_camera.name if active_camera else None,
     }
 
     json_str = json.dumps(out, check_circular=False, indent=(2 if pretty else None))
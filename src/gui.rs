@@ -1,12 +1,12 @@
-use crate::math::Vec4;
+use crate::math::{Mat4, Quat, Vec3, Vec4};
+use crate::scene::Camera;
+use crate::tracing::{Cancel, RenderMessage};
 use gl::types::*;
 use sdl2::event::Event;
 use sdl2::keyboard::{Keycode, Mod};
 use sdl2::video::{GLProfile, SwapInterval};
 use std::ffi::c_void;
 use std::mem::size_of_val;
-use std::sync::atomic::AtomicBool;
-use std::sync::atomic::Ordering::Relaxed;
 
 const VERTEX_SHADER: &str = r#"
     #version 330
@@ -26,10 +26,23 @@ const FRAGMENT_SHADER: &str = r#"
 
     layout(location = 0) uniform sampler2D tex;
     layout(location = 1) uniform float exposure;
+    layout(location = 2) uniform vec2 window_size;
+    // Gains per channel to correct color casts
+    layout(location = 3) uniform vec3 white_balance;
 
     void main() {
         ivec2 resolution = textureSize(tex, 0);
-        ivec2 pixel = ivec2(gl_FragCoord.x, resolution.y - int(gl_FragCoord.y) - 1);
+        // The image is scaled to fit the window, keeping its aspect ratio
+        float scale = min(window_size.x / resolution.x, window_size.y / resolution.y);
+        vec2 offset = (window_size - vec2(resolution) * scale) / 2.0;
+        vec2 position = (vec2(gl_FragCoord.x, window_size.y - gl_FragCoord.y) - offset) / scale;
+        if (any(lessThan(position, vec2(0.0)))
+                || any(greaterThanEqual(position, vec2(resolution)))) {
+            // Bars around images of another aspect ratio
+            out_color = vec4(0.0, 0.0, 0.0, 1.0);
+            return;
+        }
+        ivec2 pixel = ivec2(position);
 
         vec4 colora = vec4(0.0);
         for (int power_of_two = 0;; ++power_of_two) {
@@ -42,7 +55,7 @@ const FRAGMENT_SHADER: &str = r#"
         }
 
         vec3 color = colora.xyz / colora.w;
-        color = color * exp(exposure); // exposure
+        color = color * white_balance * exp(exposure); // white balance and exposure
         color = color / vec3(1.0 + max(color.x, max(color.y, color.z))); // tone mapping (Reinhard)        
         // gamma correction is enabled in the framebuffer
 
@@ -50,17 +63,71 @@ const FRAGMENT_SHADER: &str = r#"
     }
 "#;
 
+// One pass of a separable gaussian blur over source. The first pass blurs only what is brighter
+// than the threshold, the second one adds the blurred light onto the unblurred image in base.
+const BLOOM_SHADER: &str = r#"
+    #version 330
+    #extension GL_ARB_explicit_uniform_location : enable
+
+    out vec4 out_color;
+
+    layout(location = 0) uniform sampler2D source;
+    layout(location = 1) uniform sampler2D base;
+    // One step of the blur in texture coordinates
+    layout(location = 2) uniform vec2 direction;
+    layout(location = 3) uniform float threshold;
+    layout(location = 4) uniform float base_weight;
+    layout(location = 5) uniform float intensity;
+
+    const float weights[5] = float[](0.227027, 0.1945946, 0.1216216, 0.054054, 0.016216);
+
+    vec3 bright(vec2 position) {
+        vec3 color = texture(source, position).rgb;
+        return max(color - vec3(threshold), vec3(0.0));
+    }
+
+    void main() {
+        vec2 position = gl_FragCoord.xy / vec2(textureSize(source, 0));
+        vec3 blurred = bright(position) * weights[0];
+        for (int i = 1; i < 5; ++i) {
+            blurred += bright(position + direction * float(i)) * weights[i];
+            blurred += bright(position - direction * float(i)) * weights[i];
+        }
+        vec3 color = texture(base, position).rgb * base_weight + blurred * intensity;
+        out_color = vec4(color, 1.0);
+    }
+"#;
+
 const QUAD: &[f32] = &[-1.0, -1.0, -1.0, 1.0, 1.0, -1.0, -1.0, 1.0, 1.0, 1.0, 1.0, -1.0];
 
+// Distance the camera moves per key press, a tenth of it with shift
+const MOVE_STEP: f64 = 0.25;
+// Angle in radians the camera turns per pixel the mouse is dragged
+const TURN_STEP: f64 = 0.005;
+// Tone mapped brightness above which pixels glow with bloom, and how strongly
+const BLOOM_THRESHOLD: f32 = 0.7;
+const BLOOM_INTENSITY: f32 = 0.8;
+// Pixels between the taps of the blur, which widens the glow
+const BLOOM_SPREAD: f32 = 2.0;
+
 pub fn main_loop(
-    window_w: usize,
-    window_h: usize,
+    (image_w, image_h): (usize, usize),
+    // The image is scaled to fit into a window of this size
+    (window_w, window_h): (usize, usize),
     exposure: f64,
-    receiver: crossbeam_channel::Receiver<(usize, usize, Vec4)>,
-    want_quit: &AtomicBool,
+    white_balance: Vec3,
+    // The camera together with where its movements are sent to. None if there is no scene to
+    // move around in, e.g. when viewing an image.
+    mut camera: Option<(Camera, &crossbeam_channel::Sender<Camera>)>,
+    receiver: crossbeam_channel::Receiver<RenderMessage>,
+    cancel: &Cancel,
 ) {
+    // Turning left and right happens around the initial up vector, so the horizon stays level
+    let world_up = camera.map_or(Vec3([0.0; 3]), |(camera, _)| -camera.down_vector);
     let mut exposure = exposure as f32;
-    let mut display_buffer = vec![0.0f32; window_w * window_h * 4];
+    let mut display_buffer = vec![0.0f32; image_w * image_h * 4];
+    // Pixels which only show a preview sample yet
+    let mut is_preview = vec![false; image_w * image_h];
     let mut buffer_changed = true;
 
     let sdl_context = sdl2::init().unwrap();
@@ -81,66 +148,12 @@ pub fn main_loop(
     video_subsystem.gl_set_swap_interval(SwapInterval::VSync).unwrap();
     gl::load_with(|s| video_subsystem.gl_get_proc_address(s) as *const std::ffi::c_void);
 
-    let vertex_shader = unsafe {
-        let shader = gl::CreateShader(gl::VERTEX_SHADER);
-        let source_ptr = VERTEX_SHADER.as_ptr() as *const GLchar;
-        let source_len = VERTEX_SHADER.len() as GLint;
-        gl::ShaderSource(shader, 1, &source_ptr, &source_len);
-        gl::CompileShader(shader);
-        let mut result = 0;
-        gl::GetShaderiv(shader, gl::COMPILE_STATUS, &mut result);
-        if result != 1 {
-            let mut buf = vec![0u8; 10000];
-            gl::GetShaderInfoLog(
-                shader,
-                buf.len() as GLsizei,
-                std::ptr::null_mut(),
-                buf.as_mut_ptr() as *mut GLchar,
-            );
-            panic!("GLSL output: {}", String::from_utf8_lossy(&buf[..]));
-        }
-        shader
-    };
-
-    let fragment_shader = unsafe {
-        let shader = gl::CreateShader(gl::FRAGMENT_SHADER);
-        let source_ptr = FRAGMENT_SHADER.as_ptr() as *const GLchar;
-        let source_len = FRAGMENT_SHADER.len() as GLint;
-        gl::ShaderSource(shader, 1, &source_ptr, &source_len);
-        gl::CompileShader(shader);
-        let mut result = 0;
-        gl::GetShaderiv(shader, gl::COMPILE_STATUS, &mut result);
-        if result != 1 {
-            let mut buf = vec![0u8; 10000];
-            gl::GetShaderInfoLog(
-                shader,
-                buf.len() as GLsizei,
-                std::ptr::null_mut(),
-                buf.as_mut_ptr() as *mut GLchar,
-            );
-            panic!("GLSL output: {}", String::from_utf8_lossy(&buf[..]));
-        }
-        shader
-    };
-
-    let program = unsafe {
-        let program = gl::CreateProgram();
-        gl::AttachShader(program, vertex_shader);
-        gl::AttachShader(program, fragment_shader);
-        gl::LinkProgram(program);
-        let mut result = 0;
-        gl::GetProgramiv(program, gl::LINK_STATUS, &mut result);
-        if result != 1 {
-            let mut buf = vec![0u8; 10000];
-            gl::GetProgramInfoLog(
-                program,
-                buf.len() as GLsizei,
-                std::ptr::null_mut(),
-                buf.as_mut_ptr() as *mut GLchar,
-            );
-            panic!("GLSL output: {}", String::from_utf8_lossy(&buf[..]));
-        }
-        program
+    let (program, bloom_program) = unsafe {
+        let vertex_shader = compile_shader(gl::VERTEX_SHADER, VERTEX_SHADER);
+        (
+            link_program(vertex_shader, compile_shader(gl::FRAGMENT_SHADER, FRAGMENT_SHADER)),
+            link_program(vertex_shader, compile_shader(gl::FRAGMENT_SHADER, BLOOM_SHADER)),
+        )
     };
 
     let buffer = unsafe {
@@ -176,8 +189,8 @@ pub fn main_loop(
             gl::TEXTURE_2D,
             0,
             gl::RGBA32F as GLint,
-            window_w as GLsizei,
-            window_h as GLsizei,
+            image_w as GLsizei,
+            image_h as GLsizei,
             0,
             gl::RGBA,
             gl::FLOAT,
@@ -191,10 +204,29 @@ pub fn main_loop(
         gl::UseProgram(program);
         gl::Uniform1i(0, 0);
         gl::Uniform1f(1, exposure);
+        gl::Uniform2f(2, window_w as f32, window_h as f32);
+        gl::Uniform3f(
+            3,
+            white_balance.x() as f32,
+            white_balance.y() as f32,
+            white_balance.z() as f32,
+        );
+        gl::UseProgram(bloom_program);
+        gl::Uniform1i(1, 1);
     }
 
+    // The tone mapped image and its horizontally blurred highlights, on texture units 1 and 2
+    let (scene_target, blur_target) = unsafe {
+        let scene_target = create_target(gl::TEXTURE1, window_w, window_h);
+        let blur_target = create_target(gl::TEXTURE2, window_w, window_h);
+        gl::ActiveTexture(gl::TEXTURE0);
+        (scene_target, blur_target)
+    };
+    let mut bloom = false;
+
     let mut event_pump = sdl_context.event_pump().unwrap();
     'running: loop {
+        let mut camera_moved = false;
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit { .. } | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
@@ -207,9 +239,6 @@ pub fn main_loop(
                         } else {
                             1.0
                         };
-                    unsafe {
-                        gl::Uniform1f(1, exposure);
-                    }
                     window.set_title(&format!("Photon: exposure={:+.1}", exposure)).unwrap();
                 }
                 Event::KeyDown { keycode: Some(Keycode::F4), keymod, .. } => {
@@ -219,21 +248,91 @@ pub fn main_loop(
                         } else {
                             1.0
                         };
-                    unsafe {
-                        gl::Uniform1f(1, exposure);
-                    }
                     window.set_title(&format!("Photon: exposure={:+.1}", exposure)).unwrap();
                 }
+                Event::KeyDown { keycode: Some(Keycode::B), .. } => {
+                    bloom = !bloom;
+                    window
+                        .set_title(&format!("Photon: bloom {}", if bloom { "on" } else { "off" }))
+                        .unwrap();
+                }
+                Event::KeyDown { keycode: Some(keycode), keymod, .. } => {
+                    let camera = match &mut camera {
+                        Some((camera, _)) => camera,
+                        None => continue,
+                    };
+                    let direction = match keycode {
+                        Keycode::W => camera.look(),
+                        Keycode::S => -camera.look(),
+                        Keycode::D => camera.right_vector,
+                        Keycode::A => -camera.right_vector,
+                        Keycode::E => -camera.down_vector,
+                        Keycode::Q => camera.down_vector,
+                        _ => continue,
+                    };
+                    let step = if keymod.contains(Mod::LSHIFTMOD) || keymod.contains(Mod::RSHIFTMOD)
+                    {
+                        MOVE_STEP / 10.0
+                    } else {
+                        MOVE_STEP
+                    };
+                    *camera = camera.transformed(direction * step, Mat4::identity());
+                    camera_moved = true;
+                }
+                Event::MouseMotion { mousestate, xrel, yrel, .. } if mousestate.left() => {
+                    let camera = match &mut camera {
+                        Some((camera, _)) => camera,
+                        None => continue,
+                    };
+                    let rotation = Quat::from_axis_angle(world_up, -f64::from(xrel) * TURN_STEP)
+                        * Quat::from_axis_angle(camera.right_vector, -f64::from(yrel) * TURN_STEP);
+                    *camera = camera.transformed(Vec3([0.0; 3]), Mat4::from(rotation));
+                    camera_moved = true;
+                }
                 _ => {}
             }
         }
+        if let (true, Some((camera, camera_sender))) = (camera_moved, camera) {
+            // The renderer answers with a restart, which clears the display buffer
+            camera_sender.send(camera).ok();
+        }
 
-        while let Ok((x, y, Vec4([r, g, b, a]))) = receiver.try_recv() {
+        for message in receiver.try_iter() {
             buffer_changed = true;
-            display_buffer[(y * window_w + x) * 4] += r as f32;
-            display_buffer[(y * window_w + x) * 4 + 1] += g as f32;
-            display_buffer[(y * window_w + x) * 4 + 2] += b as f32;
-            display_buffer[(y * window_w + x) * 4 + 3] += a as f32;
+            match message {
+                RenderMessage::Preview(x, y, Vec4([r, g, b, a])) => {
+                    // Never cover up real samples
+                    if display_buffer[(y * image_w + x) * 4 + 3] == 0.0 {
+                        display_buffer[(y * image_w + x) * 4] = r as f32;
+                        display_buffer[(y * image_w + x) * 4 + 1] = g as f32;
+                        display_buffer[(y * image_w + x) * 4 + 2] = b as f32;
+                        display_buffer[(y * image_w + x) * 4 + 3] = a as f32;
+                        is_preview[y * image_w + x] = true;
+                    }
+                }
+                RenderMessage::Pixel(x, y, Vec4([r, g, b, a]), _) => {
+                    if is_preview[y * image_w + x] {
+                        is_preview[y * image_w + x] = false;
+                        for value in &mut display_buffer[(y * image_w + x) * 4..][..4] {
+                            *value = 0.0;
+                        }
+                    }
+                    display_buffer[(y * image_w + x) * 4] += r as f32;
+                    display_buffer[(y * image_w + x) * 4 + 1] += g as f32;
+                    display_buffer[(y * image_w + x) * 4 + 2] += b as f32;
+                    display_buffer[(y * image_w + x) * 4 + 3] += a as f32;
+                }
+                // Only written to files
+                RenderMessage::Aov(..) => {}
+                RenderMessage::Restart => {
+                    for value in display_buffer.iter_mut() {
+                        *value = 0.0;
+                    }
+                    for preview in is_preview.iter_mut() {
+                        *preview = false;
+                    }
+                }
+            }
         }
         if buffer_changed {
             unsafe {
@@ -241,8 +340,8 @@ pub fn main_loop(
                     gl::TEXTURE_2D,
                     0,
                     gl::RGBA32F as GLint,
-                    window_w as GLsizei,
-                    window_h as GLsizei,
+                    image_w as GLsizei,
+                    image_h as GLsizei,
                     0,
                     gl::RGBA,
                     gl::FLOAT,
@@ -253,11 +352,108 @@ pub fn main_loop(
         }
 
         unsafe {
-            gl::Clear(gl::COLOR_BUFFER_BIT);
-            gl::DrawArrays(gl::TRIANGLES, 0, QUAD.len() as GLsizei);
+            gl::UseProgram(program);
+            gl::Uniform1f(1, exposure);
+            if bloom {
+                // Tone map into a texture, blur its highlights horizontally into another one and
+                // then vertically onto the screen, adding the sharp image back in
+                gl::BindFramebuffer(gl::FRAMEBUFFER, scene_target);
+                gl::Clear(gl::COLOR_BUFFER_BIT);
+                gl::DrawArrays(gl::TRIANGLES, 0, QUAD.len() as GLsizei);
+
+                gl::UseProgram(bloom_program);
+                gl::BindFramebuffer(gl::FRAMEBUFFER, blur_target);
+                gl::Uniform1i(0, 1);
+                gl::Uniform2f(2, BLOOM_SPREAD / window_w as f32, 0.0);
+                gl::Uniform1f(3, BLOOM_THRESHOLD);
+                gl::Uniform1f(4, 0.0);
+                gl::Uniform1f(5, 1.0);
+                gl::DrawArrays(gl::TRIANGLES, 0, QUAD.len() as GLsizei);
+
+                gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+                gl::Uniform1i(0, 2);
+                gl::Uniform2f(2, 0.0, BLOOM_SPREAD / window_h as f32);
+                gl::Uniform1f(3, 0.0);
+                gl::Uniform1f(4, 1.0);
+                gl::Uniform1f(5, BLOOM_INTENSITY);
+                gl::DrawArrays(gl::TRIANGLES, 0, QUAD.len() as GLsizei);
+            } else {
+                gl::Clear(gl::COLOR_BUFFER_BIT);
+                gl::DrawArrays(gl::TRIANGLES, 0, QUAD.len() as GLsizei);
+            }
         }
         window.gl_swap_window();
     }
 
-    want_quit.store(true, Relaxed);
+    cancel.cancel();
+}
+
+unsafe fn compile_shader(kind: GLenum, source: &str) -> GLuint {
+    let shader = gl::CreateShader(kind);
+    let source_ptr = source.as_ptr() as *const GLchar;
+    let source_len = source.len() as GLint;
+    gl::ShaderSource(shader, 1, &source_ptr, &source_len);
+    gl::CompileShader(shader);
+    let mut result = 0;
+    gl::GetShaderiv(shader, gl::COMPILE_STATUS, &mut result);
+    if result != 1 {
+        let mut buf = vec![0u8; 10000];
+        gl::GetShaderInfoLog(
+            shader,
+            buf.len() as GLsizei,
+            std::ptr::null_mut(),
+            buf.as_mut_ptr() as *mut GLchar,
+        );
+        panic!("GLSL output: {}", String::from_utf8_lossy(&buf[..]));
+    }
+    shader
+}
+
+unsafe fn link_program(vertex_shader: GLuint, fragment_shader: GLuint) -> GLuint {
+    let program = gl::CreateProgram();
+    gl::AttachShader(program, vertex_shader);
+    gl::AttachShader(program, fragment_shader);
+    gl::LinkProgram(program);
+    let mut result = 0;
+    gl::GetProgramiv(program, gl::LINK_STATUS, &mut result);
+    if result != 1 {
+        let mut buf = vec![0u8; 10000];
+        gl::GetProgramInfoLog(
+            program,
+            buf.len() as GLsizei,
+            std::ptr::null_mut(),
+            buf.as_mut_ptr() as *mut GLchar,
+        );
+        panic!("GLSL output: {}", String::from_utf8_lossy(&buf[..]));
+    }
+    program
+}
+
+// A framebuffer drawing into a floating point texture bound to the given texture unit
+unsafe fn create_target(unit: GLenum, w: usize, h: usize) -> GLuint {
+    let mut texture = 0;
+    gl::ActiveTexture(unit);
+    gl::GenTextures(1, &mut texture);
+    gl::BindTexture(gl::TEXTURE_2D, texture);
+    gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MIN_FILTER, gl::LINEAR as GLint);
+    gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_MAG_FILTER, gl::LINEAR as GLint);
+    gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_S, gl::CLAMP_TO_EDGE as GLint);
+    gl::TexParameteri(gl::TEXTURE_2D, gl::TEXTURE_WRAP_T, gl::CLAMP_TO_EDGE as GLint);
+    gl::TexImage2D(
+        gl::TEXTURE_2D,
+        0,
+        gl::RGBA16F as GLint,
+        w as GLsizei,
+        h as GLsizei,
+        0,
+        gl::RGBA,
+        gl::FLOAT,
+        std::ptr::null(),
+    );
+    let mut framebuffer = 0;
+    gl::GenFramebuffers(1, &mut framebuffer);
+    gl::BindFramebuffer(gl::FRAMEBUFFER, framebuffer);
+    gl::FramebufferTexture2D(gl::FRAMEBUFFER, gl::COLOR_ATTACHMENT0, gl::TEXTURE_2D, texture, 0);
+    gl::BindFramebuffer(gl::FRAMEBUFFER, 0);
+    framebuffer
 }
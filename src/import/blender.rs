@@ -1,30 +1,265 @@
 use super::{Import, ImportError};
-use crate::math::{AlmostEq, Mat4, Vec2, Vec3, Vec4};
+use crate::math::{AlmostEq, Mat4, Vec2, Vec3, Vec4, EPS, ORTHO_TOLERANCE};
 use crate::scene::{
-    bsdf_principled, output_material, tex_image, Bsdf, Camera, Graph, Image, Link, LinkType,
-    PointLight, Scene, Triangle, Vertex,
+    bsdf_principled, mix_shader, output_material, separate_rgb, tex_coord, tex_image, Bsdf, Camera,
+    FovAxis, Graph, GraphBuilder, Image, Instance, Link, LinkType, Material, PointLight,
+    Projection, Scene, Triangle, Vertex,
 };
 use serde::Deserialize;
+use std::cell::Cell;
 use std::collections::BTreeMap;
+use std::f64::consts::PI;
 use std::fmt::Debug;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Copy, Clone)]
+pub struct BlenderSettings {
+    // Fail the import on unreadable textures instead of substituting a placeholder
+    pub strict_textures: bool,
+    // Larger textures count as unreadable
+    pub max_texture_pixels: u64,
+    // Fail the import on meshes without a material output instead of rendering them gray
+    pub strict_materials: bool,
+    // Number of threads decoding textures
+    pub thread_count: usize,
+    // Multiplies all distances in the scene, to bring it into a range of units that suits the
+    // renderer's fixed tolerances and light falloff
+    pub scale: f64,
+}
+
+// The index of each decoded image by its resolved path and whether it is sRGB
+type ImageIndices = BTreeMap<(String, bool), usize>;
 
 pub struct Blender<'a> {
     pwd: &'a str,
     string: &'a str,
     w: usize,
     h: usize,
+    // The camera to render instead of the active one
+    camera: Option<&'a str>,
+    settings: BlenderSettings,
+    // How long the last import spent decoding textures
+    image_time: Cell<Duration>,
 }
 
 impl<'a> Blender<'a> {
-    pub fn new(pwd: &'a str, string: &'a str, w: usize, h: usize) -> Blender<'a> {
-        Blender { pwd, string, w, h }
+    pub fn new(
+        pwd: &'a str,
+        string: &'a str,
+        w: usize,
+        h: usize,
+        camera: Option<&'a str>,
+        settings: BlenderSettings,
+    ) -> Blender<'a> {
+        Blender {
+            pwd,
+            string,
+            w,
+            h,
+            camera,
+            settings,
+            image_time: Cell::new(Duration::from_secs(0)),
+        }
+    }
+
+    pub fn image_time(&self) -> Duration {
+        self.image_time.get()
+    }
+
+    // Decodes every texture used by the scene once, spread over thread_count threads. Returns the
+    // images together with the index of each image by its resolved path and whether it is sRGB.
+    fn load_images(&self, json: &BlenderJson) -> Result<(Vec<Image>, ImageIndices), ImportError> {
+        let mut image_indices = BTreeMap::new();
+        let mut paths = vec![];
+        for object in json.objects.values() {
+            if let BlenderObjectData::Mesh(mesh) = &object.object {
+                for node in mesh.material.nodes.values() {
+                    if let BlenderNode::TexImage(node) = node {
+                        let key = (self.resolve_path(&node.filepath), node.is_srgb());
+                        if !image_indices.contains_key(&key) {
+                            image_indices.insert(key.clone(), paths.len());
+                            paths.push(key);
+                        }
+                    }
+                }
+            }
+        }
+
+        let (path_sender, path_receiver) = crossbeam_channel::unbounded();
+        let (image_sender, image_receiver) = crossbeam_channel::unbounded();
+        for (i, path) in paths.iter().enumerate() {
+            path_sender.send((i, path.clone())).unwrap();
+        }
+        drop(path_sender);
+
+        let decode_threads: Vec<_> = (0..self.settings.thread_count.max(1).min(paths.len()))
+            .map(|_| {
+                let path_receiver = path_receiver.clone();
+                let image_sender = image_sender.clone();
+                let max_pixels = self.settings.max_texture_pixels;
+                thread::spawn(move || {
+                    for (i, (path, srgb)) in path_receiver.iter() {
+                        let image = Image::from_path(&path, srgb, max_pixels);
+                        image_sender.send((i, image)).unwrap();
+                    }
+                })
+            })
+            .collect();
+        drop(image_sender);
+        let mut results: Vec<_> = image_receiver.iter().collect();
+        for decode_thread in decode_threads {
+            decode_thread.join().unwrap();
+        }
+        results.sort_by_key(|(i, _)| *i);
+
+        let mut images = Vec::with_capacity(results.len());
+        for (_, result) in results {
+            images.push(match result {
+                Ok(image) => image,
+                Err(e) if !self.settings.strict_textures => {
+                    log_warning!("{}; using a placeholder texture instead.", e);
+                    Image::missing()
+                }
+                Err(e) => return Err(ImportError::from(e)),
+            });
+        }
+
+        Ok((images, image_indices))
+    }
+
+    // mesh_aabb is the AABB of the mesh using the material in object space, object_name names the
+    // mesh in warnings
+    fn import_material(
+        &self,
+        object_name: &str,
+        material: &BlenderMaterial,
+        image_indices: &BTreeMap<(String, bool), usize>,
+        mesh_aabb: (Vec3, Vec3),
+    ) -> Result<Material, ImportError> {
+        let mut nodes = BTreeMap::<&str, (usize, &BlenderNode)>::new();
+        let mut output_index = None;
+        for (i, (node_name, node)) in material.nodes.iter().enumerate() {
+            if let BlenderNode::OutputMaterial(_) = node {
+                if output_index.is_none() {
+                    output_index = Some(i);
+                } else {
+                    return Err(ImportError::from(format!(
+                        "Duplicate OUTPUT_MATERIAL in material {}",
+                        material.name
+                    )));
+                }
+            }
+            nodes.insert(node_name, (i, node));
+        }
+        let mesh_material_name = material.name.as_str();
+        let output_index = match output_index {
+            Some(output_index) => output_index,
+            None if !self.settings.strict_materials => {
+                log_warning!(
+                    "Mesh {} has no material output; using a default gray material instead.",
+                    object_name
+                );
+                return Ok(default_material());
+            }
+            None => {
+                return Err(ImportError::from(format!(
+                    "Missing OUTPUT_MATERIAL in material {}",
+                    mesh_material_name
+                )))
+            }
+        };
+
+        let mut node_graph = Graph::new();
+        for node in material.nodes.values() {
+            node_graph.add_node(match node {
+                BlenderNode::OutputMaterial(node) => Box::new(output_material::Node {
+                    surface: node.in_surface.to_link(&nodes, |_| Bsdf {
+                        color: Vec3([1.0, 1.0, 1.0]),
+                        specular: 0.0,
+                        specular_tint: 0.0,
+                        metallic: 0.0,
+                        emission: Vec3([0.0, 0.0, 0.0]),
+                    })?,
+                }),
+                BlenderNode::BsdfPrincipled(node) => Box::new(bsdf_principled::Node {
+                    base_color: node.in_base_color.to_link(&nodes, |v| to_vec4(*v))?,
+                    specular: node.in_specular.to_link(&nodes, |v| *v)?,
+                    specular_tint: node.in_specular_tint.to_link(&nodes, |v| *v)?,
+                    metallic: node.in_metallic.to_link(&nodes, |v| *v)?,
+                    emission: node.in_emission.to_link(&nodes, |v| to_vec4(*v))?,
+                    emission_strength: node.in_emission_strength.to_link(&nodes, |v| *v)?,
+                }),
+                BlenderNode::TexImage(node) => {
+                    if node.interpolation != "Linear" {
+                        return Err(ImportError::from(
+                            "Textures only support linear interpolation",
+                        ));
+                    }
+                    if node.projection != "FLAT" {
+                        return Err(ImportError::from("Textures only support flat projection"));
+                    }
+                    if node.extension != "REPEAT" {
+                        return Err(ImportError::from("Textures only support repeat extension"));
+                    }
+                    if node.source != "FILE" {
+                        return Err(ImportError::from("Textures may only come from files"));
+                    }
+                    if !["sRGB", "Non-Color", "Linear", "Raw"].contains(&node.colorspace.as_str()) {
+                        return Err(ImportError::from(
+                            "Textures only support sRGB, Non-Color, Linear and Raw color-spaces",
+                        ));
+                    }
+
+                    let image_index =
+                        image_indices[&(self.resolve_path(&node.filepath), node.is_srgb())];
+
+                    // Unlinked, Blender uses the UV coordinates
+                    let vector = match &node.in_vector {
+                        BlenderSocket::Value(_) => None,
+                        socket => Some(socket.to_link(&nodes, |v| to_vec3(*v).xyz1())?),
+                    };
+
+                    Box::new(tex_image::Node { image: image_index, vector })
+                }
+                BlenderNode::MixShader(node) => {
+                    // An unlinked shader reflects and emits nothing
+                    let black = |_: &Option<()>| Bsdf {
+                        color: Vec3([0.0, 0.0, 0.0]),
+                        specular: 0.0,
+                        specular_tint: 0.0,
+                        metallic: 0.0,
+                        emission: Vec3([0.0, 0.0, 0.0]),
+                    };
+                    Box::new(mix_shader::Node {
+                        fac: node.in_fac.to_link(&nodes, |v| *v)?,
+                        shader1: node.in_shader.to_link(&nodes, black)?,
+                        shader2: node.in_shader_001.to_link(&nodes, black)?,
+                    })
+                }
+                BlenderNode::TexCoord => Box::new(tex_coord::Node { aabb: mesh_aabb }),
+                BlenderNode::SeparateRgb(node) => Box::new(separate_rgb::Node {
+                    image: node.in_image.to_link(&nodes, |v| to_vec4(*v))?,
+                }),
+            });
+        }
+
+        Ok(Material {
+            output: output_index,
+            graph: node_graph,
+            double_sided: !material.backface_culling,
+        })
+    }
+
+    // The transformation of an object into the scaled world
+    fn world_matrix(&self, matrix: BlenderMat4) -> Mat4 {
+        Mat4::scaling(self.settings.scale) * to_mat4(matrix)
     }
 
     fn resolve_path(&self, path: &'a str) -> String {
-        if path.starts_with("//") {
-            format!("{}/{}", self.pwd, &path[2..])
-        } else {
-            path.to_owned()
+        match path.strip_prefix("//") {
+            Some(relative) => format!("{}/{}", self.pwd, relative),
+            None => path.to_owned(),
         }
     }
 }
@@ -32,6 +267,9 @@ impl<'a> Blender<'a> {
 #[derive(Deserialize, Debug)]
 struct BlenderJson {
     objects: BTreeMap<String, BlenderObject>,
+    // Older exports do not contain this
+    #[serde(default)]
+    active_camera: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -55,7 +293,13 @@ enum BlenderObjectData {
 
 #[derive(Deserialize, Debug)]
 struct BlenderMesh {
-    triangles: Vec<BlenderTriangle>,
+    // Name of the mesh data, which may be shared between objects. Objects with modifiers have
+    // their own evaluated mesh, so the exporter adds their name to it.
+    #[serde(default)]
+    mesh: Option<String>,
+    // Only present for the first object using the mesh data
+    #[serde(default)]
+    triangles: Option<Vec<BlenderTriangle>>,
     material: BlenderMaterial,
     matrix: BlenderMat4,
 }
@@ -64,6 +308,8 @@ struct BlenderMesh {
 struct BlenderLight {
     color: (f64, f64, f64),
     power: f64,
+    // Lights are not reflected in specular highlights yet
+    #[allow(dead_code)]
     specular: f64,
     radius: f64,
     attenuation: (f64, f64, f64),
@@ -77,6 +323,31 @@ struct BlenderCamera {
     yfov: f64,
     znear: f64,
     zfar: f64,
+    #[serde(default = "default_pixel_aspect")]
+    pixel_aspect: f64,
+    // PERSP, ORTHO or PANO, only perspective cameras can be rendered
+    #[serde(default = "default_camera_type")]
+    camera_type: String,
+    // Width of the view of orthographic cameras
+    #[serde(default)]
+    ortho_scale: Option<f64>,
+    // Which side of the image the sensor spans: AUTO (the longer one), HORIZONTAL or VERTICAL.
+    // xfov is the angle of the sensor width and yfov that of the sensor height, so yfov only
+    // applies to VERTICAL. Older exports do not contain this, they always spanned the width.
+    #[serde(default = "default_sensor_fit")]
+    sensor_fit: String,
+}
+
+fn default_pixel_aspect() -> f64 {
+    1.0
+}
+
+fn default_camera_type() -> String {
+    "PERSP".to_owned()
+}
+
+fn default_sensor_fit() -> String {
+    "HORIZONTAL".to_owned()
 }
 
 #[derive(Deserialize, Debug)]
@@ -84,23 +355,48 @@ struct BlenderTriangle {
     p: (f64, f64, f64),
     n: (f64, f64, f64),
     t: (f64, f64),
+    // The tangent and the sign of the bitangent, if Blender could compute them
+    #[serde(default)]
+    g: Option<(f64, f64, f64, f64)>,
+    // Older exports do not contain this, their flat faces have the face normal at every vertex
+    #[serde(default = "default_smooth")]
+    s: bool,
+}
+
+fn default_smooth() -> bool {
+    true
 }
 
 #[derive(Deserialize, Debug)]
 struct BlenderMaterial {
     name: String,
+    // Older exports do not contain this, they were always rendered single-sided
+    #[serde(default = "default_backface_culling")]
+    backface_culling: bool,
     nodes: BTreeMap<String, BlenderNode>,
 }
 
+fn default_backface_culling() -> bool {
+    true
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(tag = "type")]
 enum BlenderNode {
     #[serde(rename = "OUTPUT_MATERIAL")]
     OutputMaterial(BlenderOutputMaterial),
     #[serde(rename = "BSDF_PRINCIPLED")]
-    BsdfPrincipled(BlenderBsdfPrincipled),
+    // Boxed, as it is by far the largest node
+    BsdfPrincipled(Box<BlenderBsdfPrincipled>),
     #[serde(rename = "TEX_IMAGE")]
     TexImage(BlenderTexImage),
+    #[serde(rename = "SEPRGB")]
+    SeparateRgb(BlenderSeparateRgb),
+    #[serde(rename = "MIX_SHADER")]
+    MixShader(BlenderMixShader),
+    // The outputs only depend on the shaded point, so the sockets exported with it are not needed
+    #[serde(rename = "TEX_COORD")]
+    TexCoord,
 }
 
 impl BlenderNode {
@@ -110,6 +406,13 @@ impl BlenderNode {
             (BsdfPrincipled(_), "bsdf") => Ok(bsdf_principled::outputs::BSDF),
             (TexImage(_), "color") => Ok(tex_image::outputs::COLOR),
             (TexImage(_), "alpha") => Ok(tex_image::outputs::ALPHA),
+            (SeparateRgb(_), "r") => Ok(separate_rgb::outputs::R),
+            (SeparateRgb(_), "g") => Ok(separate_rgb::outputs::G),
+            (SeparateRgb(_), "b") => Ok(separate_rgb::outputs::B),
+            (MixShader(_), "shader") => Ok(mix_shader::outputs::SHADER),
+            (TexCoord, "generated") => Ok(tex_coord::outputs::GENERATED),
+            (TexCoord, "uv") => Ok(tex_coord::outputs::UV),
+            (TexCoord, "object") => Ok(tex_coord::outputs::OBJECT),
             _ => Err(ImportError::from(format!("Unknown output socket {}", socket))),
         }
     }
@@ -153,6 +456,9 @@ struct BlenderValue<T: Debug> {
     value: T,
 }
 
+// The sockets which are not supported yet are still required, so that an export which lacks them
+// is recognized as broken
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 struct BlenderOutputMaterial {
     in_surface: BlenderSocket<Option<()>>,
@@ -160,6 +466,7 @@ struct BlenderOutputMaterial {
     in_displacement: BlenderSocket<(f64, f64, f64)>,
 }
 
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 struct BlenderBsdfPrincipled {
     in_base_color: BlenderSocket<(f64, f64, f64, f64)>,
@@ -180,6 +487,9 @@ struct BlenderBsdfPrincipled {
     in_transmission: BlenderSocket<f64>,
     in_transmission_roughness: BlenderSocket<f64>,
     in_emission: BlenderSocket<(f64, f64, f64, f64)>,
+    // Added in Blender 2.91, before that the emission color was used as is
+    #[serde(default = "default_emission_strength")]
+    in_emission_strength: BlenderSocket<f64>,
     in_alpha: BlenderSocket<f64>,
     in_normal: BlenderSocket<(f64, f64, f64)>,
     in_clearcoat_normal: BlenderSocket<(f64, f64, f64)>,
@@ -187,6 +497,11 @@ struct BlenderBsdfPrincipled {
     out_bsdf: BlenderSocket<Option<()>>,
 }
 
+fn default_emission_strength() -> BlenderSocket<f64> {
+    BlenderSocket::Value(BlenderValue { value: 1.0 })
+}
+
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 struct BlenderTexImage {
     in_vector: BlenderSocket<(f64, f64, f64)>,
@@ -200,6 +515,30 @@ struct BlenderTexImage {
     colorspace: String,
 }
 
+impl BlenderTexImage {
+    // Everything else holds data which must not be gamma corrected
+    fn is_srgb(&self) -> bool {
+        self.colorspace == "sRGB"
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Deserialize, Debug)]
+struct BlenderSeparateRgb {
+    in_image: BlenderSocket<(f64, f64, f64, f64)>,
+    out_r: BlenderSocket<f64>,
+    out_g: BlenderSocket<f64>,
+    out_b: BlenderSocket<f64>,
+}
+
+// The exporter tells the two shader inputs apart by their identifiers
+#[derive(Deserialize, Debug)]
+struct BlenderMixShader {
+    in_fac: BlenderSocket<f64>,
+    in_shader: BlenderSocket<Option<()>>,
+    in_shader_001: BlenderSocket<Option<()>>,
+}
+
 type BlenderMat4 =
     ((f64, f64, f64, f64), (f64, f64, f64, f64), (f64, f64, f64, f64), (f64, f64, f64, f64));
 
@@ -207,16 +546,77 @@ impl<'a> Import for Blender<'a> {
     fn import(&self) -> Result<Scene, ImportError> {
         let json: BlenderJson = serde_json::from_str(self.string).map_err(|e| format!("{}", e))?;
 
-        let mut scene_camera = None;
+        // Cameras which cannot be rendered are only an error if they are picked
+        let mut scene_cameras = BTreeMap::<&str, Result<Camera, String>>::new();
         let mut scene_lights = vec![];
+        let mut scene_light_names = vec![];
         let mut scene_triangles = vec![];
         let mut scene_materials = vec![];
-        let mut scene_images = vec![];
+        let mut scene_meshes = vec![];
+        let mut scene_instances = vec![];
+
+        // Meshes used by more than one object are only imported once and then instanced
+        let mut mesh_users = BTreeMap::<&str, usize>::new();
+        let mut mesh_triangles = BTreeMap::<&str, &Vec<BlenderTriangle>>::new();
+        for object in json.objects.values() {
+            if let BlenderObjectData::Mesh(BlenderMesh { mesh: Some(name), triangles, .. }) =
+                &object.object
+            {
+                *mesh_users.entry(name.as_str()).or_insert(0) += 1;
+                if let Some(triangles) = triangles {
+                    mesh_triangles.insert(name.as_str(), triangles);
+                }
+            }
+        }
+        // The triangles of a mesh carry its material, so objects which use the same mesh with
+        // different materials each get a copy of the mesh, keyed by the names of both
+        let mut scene_mesh_indices = BTreeMap::<(&str, &str), usize>::new();
+
+        let image_start_time = Instant::now();
+        let (scene_images, image_indices) = self.load_images(&json)?;
+        self.image_time.set(image_start_time.elapsed());
 
-        for (_, object) in json.objects {
-            match object.object {
+        for object in json.objects.values() {
+            match &object.object {
+                BlenderObjectData::Camera(camera) if camera.camera_type == "ORTHO" => {
+                    scene_cameras.insert(
+                        object.name.as_str(),
+                        Err(format!(
+                            "Camera {} is orthographic (scale {}), which is not supported yet.",
+                            object.name,
+                            camera.ortho_scale.unwrap_or(f64::NAN)
+                        )),
+                    );
+                }
+                BlenderObjectData::Camera(camera) if camera.camera_type != "PERSP" => {
+                    scene_cameras.insert(
+                        object.name.as_str(),
+                        Err(format!(
+                            "Camera {} has type {}, only perspective cameras are supported.",
+                            object.name, camera.camera_type
+                        )),
+                    );
+                }
                 BlenderObjectData::Camera(camera) => {
-                    let camera_transform = to_mat4(camera.matrix);
+                    // Like Blender, so any image size shows what Blender would render at it
+                    let landscape = self.w as f64 * camera.pixel_aspect >= self.h as f64;
+                    let (fov, fov_axis) = match camera.sensor_fit.as_str() {
+                        "AUTO" if landscape => (camera.xfov, FovAxis::Horizontal),
+                        "AUTO" => (camera.xfov, FovAxis::Vertical),
+                        "HORIZONTAL" => (camera.xfov, FovAxis::Horizontal),
+                        "VERTICAL" => (camera.yfov, FovAxis::Vertical),
+                        sensor_fit => {
+                            scene_cameras.insert(
+                                object.name.as_str(),
+                                Err(format!(
+                                    "Camera {} has unknown sensor fit {}.",
+                                    object.name, sensor_fit
+                                )),
+                            );
+                            continue;
+                        }
+                    };
+                    let camera_transform = self.world_matrix(camera.matrix);
                     let camera_position = (camera_transform * Vec4([0.0, 0.0, 0.0, 1.0])).xyz();
                     let camera_look =
                         (camera_transform * Vec4([0.0, 0.0, -1.0, 0.0])).xyz().normalize();
@@ -224,167 +624,299 @@ impl<'a> Import for Blender<'a> {
                         (camera_transform * Vec4([0.0, 1.0, 0.0, 0.0])).xyz().normalize();
                     let camera_left =
                         (camera_transform * Vec4([-1.0, 0.0, 0.0, 0.0])).xyz().normalize();
-                    if !(camera_look.dot(camera_up).almost_zero()
-                        && camera_look.dot(camera_left).almost_zero()
-                        && camera_left.dot(camera_up).almost_zero())
+                    if !(camera_look.dot(camera_up).almost_zero_within(ORTHO_TOLERANCE)
+                        && camera_look.dot(camera_left).almost_zero_within(ORTHO_TOLERANCE)
+                        && camera_left.dot(camera_up).almost_zero_within(ORTHO_TOLERANCE))
                     {
-                        panic!("Camera is transformed without keeping the angles.");
+                        scene_cameras.insert(
+                            object.name.as_str(),
+                            Err(format!(
+                                "Camera {} is transformed without keeping the angles.",
+                                object.name
+                            )),
+                        );
+                        continue;
                     }
-                    let image_plane_half_width = camera.znear * (camera.xfov / 2.0).tan();
-                    let image_plane_half_height =
-                        image_plane_half_width / (self.w as f64 / self.h as f64);
-                    let image_plane_top_left = camera_position
-                        + camera.znear * camera_look
-                        + image_plane_half_width * camera_left
-                        + image_plane_half_height * camera_up;
-                    scene_camera = Some(Camera {
-                        position: camera_position,
-                        top_left_corner: image_plane_top_left,
-                        plane_width: image_plane_half_width * 2.0,
-                        plane_height: image_plane_half_height * 2.0,
-                        right_vector: -camera_left,
-                        down_vector: -camera_up,
-                    });
+                    scene_cameras.insert(
+                        object.name.as_str(),
+                        Ok(Camera::new(
+                            camera_position,
+                            camera_look,
+                            camera_up,
+                            camera_left,
+                            Projection {
+                                fov,
+                                fov_axis,
+                                znear: camera.znear * self.settings.scale,
+                                zfar: camera.zfar * self.settings.scale,
+                                pixel_aspect: camera.pixel_aspect,
+                            },
+                            self.w,
+                            self.h,
+                        )),
+                    );
                 }
                 BlenderObjectData::Light(light) => {
-                    let position = (to_mat4(light.matrix) * Vec4([0.0, 0.0, 0.0, 1.0])).xyz();
+                    let position =
+                        (self.world_matrix(light.matrix) * Vec4([0.0, 0.0, 0.0, 1.0])).xyz();
                     scene_lights.push(PointLight {
                         position,
-                        color: to_vec3(light.color) * light.power,
-                        radius: light.radius,
+                        // Blender gives the power in watts, emitted evenly in all directions.
+                        // The renderer wants the intensity per steradian, i.e. spread over the
+                        // 4π of the whole sphere.
+                        color: to_vec3(light.color) * (light.power / (4.0 * PI)),
+                        radius: light.radius * self.settings.scale,
                         a: light.attenuation.0,
                         b: light.attenuation.1,
                         c: light.attenuation.2,
                     });
+                    scene_light_names.push(object.name.clone());
                 }
                 BlenderObjectData::Mesh(mesh) => {
-                    let matrix = to_mat4(mesh.matrix);
-                    let nmatrix = matrix.inv().transpose();
-                    let mut triangle = (
-                        Vertex {
-                            position: Vec3([0.0; 3]),
-                            normal: Vec3([0.0; 3]),
-                            tex_coord: Vec2([0.0; 2]),
-                        },
-                        Vertex {
-                            position: Vec3([0.0; 3]),
-                            normal: Vec3([0.0; 3]),
-                            tex_coord: Vec2([0.0; 2]),
-                        },
-                        Vertex {
-                            position: Vec3([0.0; 3]),
-                            normal: Vec3([0.0; 3]),
-                            tex_coord: Vec2([0.0; 2]),
-                        },
-                    );
-                    let mut i = 0;
-                    for t in mesh.triangles {
-                        let vertex = match i {
-                            0 => &mut triangle.0,
-                            1 => &mut triangle.1,
-                            2 => &mut triangle.2,
-                            _ => unreachable!(),
-                        };
-                        vertex.position = (matrix * to_vec3(t.p).xyz1()).xyz();
-                        vertex.normal = (nmatrix * to_vec3(t.n).xyz0()).xyz();
-                        vertex.tex_coord = to_vec2(t.t);
-                        if i == 2 {
-                            scene_triangles.push(Triangle::new(
-                                triangle.0,
-                                triangle.1,
-                                triangle.2,
-                                scene_materials.len(),
-                            ));
-                            i = 0;
-                        } else {
-                            i += 1;
-                        }
-                    }
-
-                    let mut nodes = BTreeMap::<&str, (usize, &BlenderNode)>::new();
-                    let mut output_index = None;
-                    for (i, (node_name, node)) in mesh.material.nodes.iter().enumerate() {
-                        if let BlenderNode::OutputMaterial(_) = node {
-                            if output_index.is_none() {
-                                output_index = Some(i);
-                            } else {
-                                return Err(ImportError::from(format!(
-                                    "Duplicate OUTPUT_MATERIAL in material {}",
-                                    mesh.material.name
-                                )));
+                    let matrix = self.world_matrix(mesh.matrix);
+                    let shared_mesh =
+                        mesh.mesh.as_ref().filter(|name| mesh_users[name.as_str()] > 1);
+                    if let Some(mesh_name) = shared_mesh {
+                        let key = (mesh_name.as_str(), mesh.material.name.as_str());
+                        let mesh_index = match scene_mesh_indices.get(&key) {
+                            Some(mesh_index) => *mesh_index,
+                            None => {
+                                let triangles =
+                                    mesh_triangles.get(mesh_name.as_str()).ok_or_else(|| {
+                                        format!("Mesh {} has no triangles", mesh_name)
+                                    })?;
+                                scene_meshes.push(import_triangles(
+                                    mesh_name,
+                                    triangles,
+                                    Mat4::identity(),
+                                    scene_materials.len(),
+                                )?);
+                                scene_materials.push(self.import_material(
+                                    mesh_name,
+                                    &mesh.material,
+                                    &image_indices,
+                                    local_aabb(triangles),
+                                )?);
+                                scene_mesh_indices.insert(key, scene_meshes.len() - 1);
+                                scene_meshes.len() - 1
                             }
-                        }
-                        nodes.insert(node_name, (i, node));
-                    }
-                    let mesh_material_name = mesh.material.name.as_str();
-                    let output_index = output_index.ok_or_else(|| {
-                        format!("Missing OUTPUT_MATERIAL in material {}", mesh_material_name)
-                    })?;
-
-                    let mut node_graph = Graph::new();
-                    for node in mesh.material.nodes.values() {
-                        node_graph.add_node(match node {
-                            BlenderNode::OutputMaterial(node) => Box::new(output_material::Node {
-                                surface: node.in_surface.to_link(&nodes, |_| Bsdf {
-                                    color: Vec3([1.0, 1.0, 1.0]),
-                                    specular: 0.0,
-                                    metallic: 0.0,
-                                })?,
-                            }),
-                            BlenderNode::BsdfPrincipled(node) => Box::new(bsdf_principled::Node {
-                                base_color: node.in_base_color.to_link(&nodes, |v| to_vec4(*v))?,
-                                specular: node.in_specular.to_link(&nodes, |v| *v)?,
-                                metallic: node.in_metallic.to_link(&nodes, |v| *v)?,
-                            }),
-                            BlenderNode::TexImage(node) => {
-                                if node.interpolation != "Linear" {
-                                    return Err(ImportError::from(
-                                        "Textures only support linear interpolation",
-                                    ));
-                                }
-                                if node.projection != "FLAT" {
-                                    return Err(ImportError::from(
-                                        "Textures only support flat projection",
-                                    ));
-                                }
-                                if node.extension != "REPEAT" {
-                                    return Err(ImportError::from(
-                                        "Textures only support repeat extension",
-                                    ));
-                                }
-                                if node.source != "FILE" {
-                                    return Err(ImportError::from(
-                                        "Textures may only come from files",
-                                    ));
-                                }
-                                if node.colorspace != "sRGB" {
-                                    return Err(ImportError::from(
-                                        "Textures only support sRGB color-space",
-                                    ));
-                                }
-
-                                let image_path = self.resolve_path(&node.filepath);
-                                let image_index = scene_images.len();
-                                scene_images.push(Image::from_path(&image_path)?);
-
-                                Box::new(tex_image::Node { image: image_index })
-                            }
-                        });
+                        };
+                        scene_instances.push(Instance { mesh: mesh_index, transform: matrix });
+                    } else {
+                        let triangles = mesh
+                            .triangles
+                            .as_ref()
+                            .or_else(|| {
+                                mesh.mesh
+                                    .as_ref()
+                                    .and_then(|name| mesh_triangles.get(name.as_str()).copied())
+                            })
+                            .ok_or_else(|| format!("Object {} has no triangles", object.name))?;
+                        scene_triangles.extend(import_triangles(
+                            &object.name,
+                            triangles,
+                            matrix,
+                            scene_materials.len(),
+                        )?);
+                        scene_materials.push(self.import_material(
+                            &object.name,
+                            &mesh.material,
+                            &image_indices,
+                            local_aabb(triangles),
+                        )?);
                     }
-
-                    scene_materials.push((output_index, node_graph));
                 }
             }
         }
 
-        Ok(Scene {
-            camera: scene_camera.ok_or("Scene does not have a camera.")?,
+        let camera = match self.camera.or(json.active_camera.as_deref()) {
+            Some(name) => scene_cameras
+                .get(name)
+                .ok_or_else(|| {
+                    let names: Vec<_> = scene_cameras.keys().copied().collect();
+                    format!("Camera {} not found, the scene has: {}", name, names.join(", "))
+                })?
+                .clone()?,
+            // Without an active camera fall back to the last one
+            None => {
+                scene_cameras.values().last().ok_or("Scene does not have a camera.")?.clone()?
+            }
+        };
+
+        let mut scene = Scene {
+            camera,
             triangles: scene_triangles,
+            meshes: scene_meshes,
+            instances: scene_instances,
             point_lights: scene_lights,
+            light_names: scene_light_names,
             materials: scene_materials,
             images: scene_images,
-        })
+        };
+        prune_unused(&mut scene);
+        remove_duplicate_lights(&mut scene);
+        Ok(scene)
+    }
+}
+
+// Removes the materials no triangle uses and the images no remaining material uses.
+fn prune_unused(scene: &mut Scene) {
+    let mut material_used = vec![false; scene.materials.len()];
+    for triangle in scene.triangles.iter().chain(scene.meshes.iter().flatten()) {
+        material_used[triangle.material()] = true;
+    }
+    let material_indices = retain_used(&mut scene.materials, &material_used);
+    for triangle in scene.triangles.iter_mut().chain(scene.meshes.iter_mut().flatten()) {
+        triangle.set_material(material_indices[triangle.material()]);
+    }
+
+    let mut image_used = vec![false; scene.images.len()];
+    for material in &mut scene.materials {
+        for image in material.graph.images_mut() {
+            image_used[*image] = true;
+        }
+    }
+    let image_indices = retain_used(&mut scene.images, &image_used);
+    for material in &mut scene.materials {
+        for image in material.graph.images_mut() {
+            *image = image_indices[*image];
+        }
+    }
+
+    let unused_materials = material_used.iter().filter(|used| !**used).count();
+    let unused_images = image_used.iter().filter(|used| !**used).count();
+    if unused_materials > 0 || unused_images > 0 {
+        log_warning!(
+            "Removed {} unused materials and {} unused images.",
+            unused_materials,
+            unused_images
+        );
+    }
+}
+
+// Exports sometimes contain the same light twice, e.g. for a light parented to several objects,
+// which doubles its brightness and its shadow rays. Only the first of identical lights is kept.
+fn remove_duplicate_lights(scene: &mut Scene) {
+    let same = |l1: &PointLight, l2: &PointLight| {
+        (0..3).all(|i| {
+            l1.position[i].almost_eq_within(l2.position[i], EPS)
+                && l1.color[i].almost_eq_within(l2.color[i], EPS)
+        }) && l1.radius.almost_eq_within(l2.radius, EPS)
+            && l1.a.almost_eq_within(l2.a, EPS)
+            && l1.b.almost_eq_within(l2.b, EPS)
+            && l1.c.almost_eq_within(l2.c, EPS)
+    };
+    let mut i = 0;
+    while i < scene.point_lights.len() {
+        let original = (0..i).find(|j| same(&scene.point_lights[*j], &scene.point_lights[i]));
+        if let Some(original) = original {
+            log_warning!(
+                "Light {} duplicates light {}, it is left out.",
+                scene.light_names[i],
+                scene.light_names[original]
+            );
+            scene.point_lights.remove(i);
+            scene.light_names.remove(i);
+        } else {
+            i += 1;
+        }
+    }
+}
+
+// Keeps only the used values and returns the new index of every old one.
+fn retain_used<T>(values: &mut Vec<T>, used: &[bool]) -> Vec<usize> {
+    let mut new_indices = Vec::with_capacity(used.len());
+    let mut next_index = 0;
+    for is_used in used {
+        new_indices.push(next_index);
+        if *is_used {
+            next_index += 1;
+        }
+    }
+    let mut i = 0;
+    values.retain(|_| {
+        i += 1;
+        used[i - 1]
+    });
+    new_indices
+}
+
+// Blender's default principled BSDF, which is also how it shows objects without a material
+fn default_material() -> Material {
+    let mut graph = GraphBuilder::new();
+    let bsdf = graph.add(bsdf_principled::Node {
+        base_color: Link::Constant(Vec4([0.8, 0.8, 0.8, 1.0])),
+        specular: Link::Constant(0.5),
+        specular_tint: Link::Constant(0.0),
+        metallic: Link::Constant(0.0),
+        emission: Link::Constant(Vec4([0.0, 0.0, 0.0, 1.0])),
+        emission_strength: Link::Constant(1.0),
+    });
+    graph.material(bsdf.bsdf(), true)
+}
+
+// Every three vertices form a triangle, name names the mesh in errors
+fn import_triangles(
+    name: &str,
+    triangles: &[BlenderTriangle],
+    matrix: Mat4,
+    material: usize,
+) -> Result<Vec<Triangle>, ImportError> {
+    // A broken export would otherwise lose its last triangle without notice
+    if !triangles.len().is_multiple_of(3) {
+        return Err(ImportError::from(format!(
+            "Mesh {} has {} vertices, which does not make whole triangles",
+            name,
+            triangles.len()
+        )));
+    }
+    let nmatrix = matrix.inv().transpose();
+    // Mirroring flips the bitangent relative to normal x tangent
+    let bitangent_sign = matrix.det().signum();
+    let mut result = vec![];
+    let vertex = Vertex {
+        position: Vec3([0.0; 3]),
+        normal: Vec3([0.0; 3]),
+        tex_coord: Vec2([0.0; 2]),
+        object_position: Vec3([0.0; 3]),
+        tangent: None,
+    };
+    let mut triangle = (vertex, vertex, vertex);
+    let mut i = 0;
+    for t in triangles {
+        let vertex = match i {
+            0 => &mut triangle.0,
+            1 => &mut triangle.1,
+            2 => &mut triangle.2,
+            _ => unreachable!(),
+        };
+        vertex.position = (matrix * to_vec3(t.p).xyz1()).xyz();
+        vertex.normal = (nmatrix * to_vec3(t.n).xyz0()).xyz();
+        vertex.tex_coord = to_vec2(t.t);
+        vertex.object_position = to_vec3(t.p);
+        vertex.tangent = t.g.map(|(x, y, z, w)| {
+            let tangent = (matrix * Vec4([x, y, z, 0.0])).xyz();
+            Vec4([tangent.x(), tangent.y(), tangent.z(), w * bitangent_sign])
+        });
+        if i == 2 {
+            result.push(Triangle::new(triangle.0, triangle.1, triangle.2, material, t.s));
+            i = 0;
+        } else {
+            i += 1;
+        }
     }
+    Ok(result)
+}
+
+// The AABB of the untransformed triangles
+fn local_aabb(triangles: &[BlenderTriangle]) -> (Vec3, Vec3) {
+    let mut aabb_min = Vec3([f64::INFINITY; 3]);
+    let mut aabb_max = Vec3([f64::NEG_INFINITY; 3]);
+    for t in triangles {
+        aabb_min = aabb_min.min(to_vec3(t.p));
+        aabb_max = aabb_max.max(to_vec3(t.p));
+    }
+    (aabb_min, aabb_max)
 }
 
 fn to_mat4(mat: BlenderMat4) -> Mat4 {
@@ -407,3 +939,252 @@ fn to_vec3(v: (f64, f64, f64)) -> Vec3 {
 fn to_vec4(v: (f64, f64, f64, f64)) -> Vec4 {
     Vec4([v.0, v.1, v.2, v.3])
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAMERA: &str = r#""Cam": {
+        "name": "Cam", "type": "CAMERA", "xfov": 0.8, "yfov": 0.6, "znear": 0.1, "zfar": 100,
+        "matrix": [[1, 0, 0, 0], [0, 1, 0, 0], [0, 0, 1, 5], [0, 0, 0, 1]]
+    }"#;
+
+    // An object using the named mesh and material, moved by x along the x axis. The triangles of
+    // the mesh are only written if given, like the exporter does for the first user of a mesh.
+    fn mesh_object(
+        name: &str,
+        mesh: &str,
+        material: &str,
+        x: f64,
+        triangles: Option<&str>,
+    ) -> String {
+        format!(
+            r#""{name}": {{
+                "name": "{name}", "type": "MESH", "mesh": "{mesh}",
+                "material": {{"name": "{material}", "nodes": {{}}}},
+                "matrix": [[1, 0, 0, {x}], [0, 1, 0, 0], [0, 0, 1, 0], [0, 0, 0, 1]]
+                {triangles}
+            }}"#,
+            name = name,
+            mesh = mesh,
+            material = material,
+            x = x,
+            triangles = triangles.map_or(String::new(), |t| format!(r#", "triangles": {}"#, t))
+        )
+    }
+
+    const TRIANGLE: &str = r#"[
+        {"p": [0, 0, 0], "n": [0, 0, 1], "t": [0, 0]},
+        {"p": [1, 0, 0], "n": [0, 0, 1], "t": [1, 0]},
+        {"p": [0, 1, 0], "n": [0, 0, 1], "t": [0, 1]}
+    ]"#;
+
+    fn settings() -> BlenderSettings {
+        BlenderSettings {
+            strict_textures: false,
+            max_texture_pixels: 0,
+            strict_materials: false,
+            thread_count: 1,
+            scale: 1.0,
+        }
+    }
+
+    fn import(objects: &[String]) -> Result<Scene, ImportError> {
+        let json = format!(r#"{{"objects": {{{}, {}}}}}"#, CAMERA, objects.join(", "));
+        Blender::new("", &json, 4, 3, None, settings()).import()
+    }
+
+    #[test]
+    fn shared_mesh_keeps_material_per_object() {
+        let scene = import(&[
+            mesh_object("A", "Tri", "Red", 0.0, Some(TRIANGLE)),
+            mesh_object("B", "Tri", "Blue", 2.0, None),
+            mesh_object("C", "Tri", "Red", 4.0, None),
+        ])
+        .unwrap();
+        // One copy of the mesh per material
+        assert_eq!(scene.meshes.len(), 2);
+        assert_eq!(scene.instances.len(), 3);
+        let material = |instance: usize| scene.meshes[scene.instances[instance].mesh][0].material();
+        assert_ne!(material(0), material(1));
+        assert_eq!(scene.instances[0].mesh, scene.instances[2].mesh);
+    }
+
+    // A light instanced from a collection, placed by the matrix of its instance
+    fn light_instance(name: &str, x: f64) -> String {
+        format!(
+            r#""{name}": {{
+                "name": "{name}", "type": "LIGHT", "lamp_type": "POINT", "color": [1, 1, 1],
+                "power": 100, "specular": 1, "radius": 0.1, "attenuation": [0, 0, 1],
+                "matrix": [[1, 0, 0, {x}], [0, 1, 0, 0], [0, 0, 1, 2], [0, 0, 0, 1]]
+            }}"#,
+            name = name,
+            x = x
+        )
+    }
+
+    #[test]
+    fn instanced_lights_keep_their_positions() {
+        let scene =
+            import(&[light_instance("Lamp.1.0", 1.0), light_instance("Lamp.2.0", 3.0)]).unwrap();
+        assert_eq!(scene.light_names, vec!["Lamp.1.0", "Lamp.2.0"]);
+        assert_eq!(scene.point_lights[0].position, Vec3([1.0, 0.0, 2.0]));
+        assert_eq!(scene.point_lights[1].position, Vec3([3.0, 0.0, 2.0]));
+    }
+
+    #[test]
+    fn mirroring_flips_the_bitangent_sign() {
+        let triangles: Vec<BlenderTriangle> = serde_json::from_str(
+            r#"[
+                {"p": [0, 0, 0], "n": [0, 0, 1], "t": [0, 0], "g": [1, 0, 0, 1]},
+                {"p": [1, 0, 0], "n": [0, 0, 1], "t": [1, 0], "g": [1, 0, 0, 1]},
+                {"p": [0, 1, 0], "n": [0, 0, 1], "t": [0, 1], "g": [1, 0, 0, 1]}
+            ]"#,
+        )
+        .unwrap();
+        // Mirrors x. Shared meshes are imported untransformed, their instances handle the sign.
+        let mirror = Mat4([
+            [-1.0, 0.0, 0.0, 0.0],
+            [0.0, 1.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        for (matrix, sign) in [(Mat4::identity(), 1.0), (mirror, -1.0)].iter() {
+            let triangles = import_triangles("A", &triangles, *matrix, 0).unwrap();
+            let tangent = triangles[0].a().tangent;
+            // With the tangent mirrored, the sign keeps the bitangent w * (normal x tangent) at y
+            assert_eq!(tangent, Some(Vec4([*sign, 0.0, 0.0, *sign])));
+        }
+    }
+
+    #[test]
+    fn partial_triangle_is_rejected() {
+        // A triangle and the first vertex of another one
+        let four_vertices = r#"[
+            {"p": [0, 0, 0], "n": [0, 0, 1], "t": [0, 0]},
+            {"p": [1, 0, 0], "n": [0, 0, 1], "t": [1, 0]},
+            {"p": [0, 1, 0], "n": [0, 0, 1], "t": [0, 1]},
+            {"p": [1, 1, 0], "n": [0, 0, 1], "t": [1, 1]}
+        ]"#;
+        let result = import(&[mesh_object("A", "Quad", "Red", 0.0, Some(four_vertices))]);
+        let message = result.map(|_| ()).unwrap_err().to_string();
+        assert!(message.contains("Mesh A has 4 vertices"), "{}", message);
+    }
+
+    #[test]
+    fn tex_coord_ignores_its_sockets() {
+        let node = serde_json::from_str::<BlenderNode>(
+            r#"{
+                "type": "TEX_COORD",
+                "out_uv": {"type": "VALUE", "value": [0, 0, 0]},
+                "out_window": {"type": "VALUE", "value": [0, 0, 0]}
+            }"#,
+        );
+        assert!(matches!(node, Ok(BlenderNode::TexCoord)), "{:?}", node);
+    }
+
+    #[test]
+    fn masked_paint_example_mixes_by_its_texture() {
+        let pwd = concat!(env!("CARGO_MANIFEST_DIR"), "/examples");
+        let json = include_str!("../../examples/masked_paint.blend.json");
+        let settings =
+            BlenderSettings { strict_textures: true, max_texture_pixels: u64::MAX, ..settings() };
+        let scene = Blender::new(pwd, json, 4, 3, None, settings).import().unwrap();
+        let material = &scene.materials[scene.triangles[0].material()];
+        assert!(material.double_sided);
+
+        // The luminance of the texture selects between the metal and the paint colored by it
+        let mut texture = Graph::new();
+        let texture_node = texture.add_node(Box::new(tex_image::Node { image: 0, vector: None }));
+        let zero = Vec3([0.0; 3]);
+        let mut facs = vec![];
+        for &(u, v) in &[(0.01, 0.99), (0.3, 0.6), (0.55, 0.2), (0.9, 0.8)] {
+            let uv = Vec2([u, v]);
+            let mut ctx = texture.new_context(&scene, uv, zero, zero, zero);
+            let color: Vec4 =
+                ctx.evaluate_link(Link::Node(texture_node, tex_image::outputs::COLOR));
+            let fac: f64 = ctx.evaluate_link(Link::Node(texture_node, tex_image::outputs::COLOR));
+            let fac = fac.clamp(0.0, 1.0);
+            let bsdf: Bsdf = material
+                .graph
+                .new_context(&scene, uv, zero, zero, zero)
+                .evaluate_link(Link::Node(material.output, output_material::outputs::SURFACE));
+            let expected = Vec3([0.9; 3]) * (1.0 - fac) + color.xyz() * fac;
+            for i in 0..3 {
+                assert!(
+                    bsdf.color[i].almost_eq_within(expected[i], 1e-9),
+                    "{:?} at {:?}",
+                    bsdf,
+                    uv
+                );
+            }
+            assert!(bsdf.metallic.almost_eq_within(1.0 - fac, 1e-9), "{:?} at {:?}", bsdf, uv);
+            facs.push(fac);
+        }
+        // The mask is not uniform, so both shaders show
+        assert!(
+            facs.iter().any(|&fac| fac < 0.5) && facs.iter().any(|&fac| fac > 0.5),
+            "{:?}",
+            facs
+        );
+    }
+
+    // The size of the image plane of CAMERA with the given sensor fit rendered at w x h pixels
+    fn image_plane(sensor_fit: &str, w: usize, h: usize) -> (f64, f64) {
+        let json = format!(
+            r#"{{"objects": {{"Cam": {{
+                "name": "Cam", "type": "CAMERA", "xfov": 0.8, "yfov": 0.6, "znear": 0.1,
+                "zfar": 100, "sensor_fit": "{}",
+                "matrix": [[1, 0, 0, 0], [0, 1, 0, 0], [0, 0, 1, 5], [0, 0, 0, 1]]
+            }}}}}}"#,
+            sensor_fit
+        );
+        let scene = Blender::new("", &json, w, h, None, settings()).import().unwrap();
+        (scene.camera.plane_width, scene.camera.plane_height)
+    }
+
+    #[test]
+    fn skewed_camera_is_only_an_error_if_picked() {
+        let json = format!(
+            r#"{{"objects": {{{}, "Askew": {{
+                "name": "Askew", "type": "CAMERA", "xfov": 0.8, "yfov": 0.6, "znear": 0.1,
+                "zfar": 100, "matrix": [[1, 0.5, 0, 0], [0, 1, 0, 0], [0, 0, 1, 5], [0, 0, 0, 1]]
+            }}}}}}"#,
+            CAMERA
+        );
+        assert!(Blender::new("", &json, 4, 3, None, settings()).import().is_ok());
+        let result = Blender::new("", &json, 4, 3, Some("Askew"), settings()).import();
+        let message = result.map(|_| ()).unwrap_err().to_string();
+        assert!(message.contains("Camera Askew is transformed without keeping"), "{}", message);
+    }
+
+    #[test]
+    fn camera_fits_sensor_like_blender() {
+        // At znear = 0.1, the sensor spans these widths for xfov = 0.8 and yfov = 0.6
+        let x_sensor = 0.2 * 0.4f64.tan();
+        let y_sensor = 0.2 * 0.3f64.tan();
+        let cases = [
+            // The width stays fixed when the image aspect differs from the camera's
+            ("HORIZONTAL", 4, 3, x_sensor, x_sensor * 3.0 / 4.0),
+            ("HORIZONTAL", 3, 4, x_sensor, x_sensor * 4.0 / 3.0),
+            ("VERTICAL", 4, 3, y_sensor * 4.0 / 3.0, y_sensor),
+            ("VERTICAL", 3, 4, y_sensor * 3.0 / 4.0, y_sensor),
+            // The sensor spans the longer side
+            ("AUTO", 4, 3, x_sensor, x_sensor * 3.0 / 4.0),
+            ("AUTO", 3, 4, x_sensor * 3.0 / 4.0, x_sensor),
+        ];
+        for &(sensor_fit, w, h, width, height) in &cases {
+            let (plane_width, plane_height) = image_plane(sensor_fit, w, h);
+            assert!(
+                plane_width.almost_eq_within(width, EPS)
+                    && plane_height.almost_eq_within(height, EPS),
+                "{} at {}x{}: {}x{}",
+                sensor_fit,
+                w,
+                h,
+                plane_width,
+                plane_height
+            );
+        }
+    }
+}
@@ -1,6 +1,8 @@
 mod blender;
+mod photon;
 
-pub use blender::Blender;
+pub use blender::{Blender, BlenderSettings};
+pub use photon::Photon;
 
 use crate::scene::Scene;
 use std::error::Error;
@@ -0,0 +1,360 @@
+// A minimal scene format which does not depend on Blender. Example:
+//
+// {
+//     "camera": {
+//         "position": [0.0, -5.0, 1.0],
+//         "look_at": [0.0, 0.0, 0.0],
+//         "up": [0.0, 0.0, 1.0],
+//         "xfov": 60.0,              // horizontal field of view in degrees
+//         "znear": 0.1,              // optional, defaults to 0.1
+//         "zfar": 1000.0,            // optional, defaults to infinity
+//         "pixel_aspect": 1.0        // optional, width of a pixel divided by its height
+//     },
+//     "materials": {
+//         "red": {
+//             "color": [1.0, 0.0, 0.0],
+//             "specular": 0.5,
+//             "specular_tint": 0.0,         // optional, 1 tints the highlights with the color
+//             "metallic": 0.0,
+//             "emission": [0.0, 0.0, 0.0],  // optional, light given off by the surface
+//             "emission_strength": 1.0,     // optional, multiplies the emission
+//             "double_sided": false         // optional, defaults to false
+//         }
+//     },
+//     "meshes": [
+//         {
+//             "material": "red",
+//             "vertices": [[-1.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]],
+//             "normals": [[0.0, -1.0, 0.0], [0.0, -1.0, 0.0], [0.0, -1.0, 0.0]],  // optional
+//             "tex_coords": [[0.0, 0.0], [1.0, 0.0], [0.5, 1.0]],                 // optional
+//             "tangents": [[1.0, 0.0, 0.0, 1.0], [1.0, 0.0, 0.0, 1.0], [1.0, 0.0, 0.0, 1.0]],
+//             "triangles": [[0, 1, 2]]   // indices into the vertex lists, counter-clockwise
+//         }
+//     ],
+//     "lights": [
+//         {
+//             "name": "key",  // optional, defaults to light0, light1, ... for --light-scale
+//             "position": [2.0, -2.0, 3.0],
+//             "color": [100.0, 100.0, 100.0],
+//             "radius": 0.0
+//         }
+//     ]
+// }
+//
+// Tangents are optional as well, given as in glTF with the sign of the bitangent in w. Without
+// them the tangents follow the texture coordinates. Without normals each triangle is shaded flat.
+// Colors are linear RGB. Specular and metallic default to 0, materials are single-sided by default.
+
+use super::{Import, ImportError};
+use crate::math::{Vec2, Vec3, Vec4, EPS};
+use crate::scene::{
+    bsdf_principled, Camera, FovAxis, GraphBuilder, Link, PointLight, Projection, Scene, Triangle,
+    Vertex,
+};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+pub struct Photon<'a> {
+    string: &'a str,
+    w: usize,
+    h: usize,
+}
+
+impl<'a> Photon<'a> {
+    pub fn new(string: &'a str, w: usize, h: usize) -> Photon<'a> {
+        Photon { string, w, h }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct PhotonJson {
+    camera: PhotonCamera,
+    #[serde(default)]
+    materials: BTreeMap<String, PhotonMaterial>,
+    #[serde(default)]
+    meshes: Vec<PhotonMesh>,
+    #[serde(default)]
+    lights: Vec<PhotonLight>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PhotonCamera {
+    position: [f64; 3],
+    look_at: [f64; 3],
+    up: [f64; 3],
+    xfov: f64,
+    #[serde(default = "default_znear")]
+    znear: f64,
+    #[serde(default = "default_zfar")]
+    zfar: f64,
+    #[serde(default = "default_pixel_aspect")]
+    pixel_aspect: f64,
+}
+
+fn default_znear() -> f64 {
+    0.1
+}
+
+fn default_zfar() -> f64 {
+    f64::INFINITY
+}
+
+fn default_pixel_aspect() -> f64 {
+    1.0
+}
+
+#[derive(Deserialize, Debug)]
+struct PhotonMaterial {
+    color: [f64; 3],
+    #[serde(default)]
+    specular: f64,
+    #[serde(default)]
+    specular_tint: f64,
+    #[serde(default)]
+    metallic: f64,
+    #[serde(default)]
+    emission: [f64; 3],
+    #[serde(default = "default_emission_strength")]
+    emission_strength: f64,
+    #[serde(default)]
+    double_sided: bool,
+}
+
+fn default_emission_strength() -> f64 {
+    1.0
+}
+
+#[derive(Deserialize, Debug)]
+struct PhotonMesh {
+    material: String,
+    vertices: Vec<[f64; 3]>,
+    #[serde(default)]
+    normals: Option<Vec<[f64; 3]>>,
+    #[serde(default)]
+    tex_coords: Option<Vec<[f64; 2]>>,
+    #[serde(default)]
+    tangents: Option<Vec<[f64; 4]>>,
+    triangles: Vec<[usize; 3]>,
+}
+
+#[derive(Deserialize, Debug)]
+struct PhotonLight {
+    // Defaults to light0, light1, ... by position in the list
+    #[serde(default)]
+    name: Option<String>,
+    position: [f64; 3],
+    color: [f64; 3],
+    #[serde(default)]
+    radius: f64,
+}
+
+impl<'a> Import for Photon<'a> {
+    fn import(&self) -> Result<Scene, ImportError> {
+        let json: PhotonJson = serde_json::from_str(self.string).map_err(|e| format!("{}", e))?;
+
+        // up only needs to point roughly upwards, so make the camera's axes orthogonal here
+        let position = Vec3(json.camera.position);
+        let (look, distance) = (Vec3(json.camera.look_at) - position).normalize_len();
+        if distance <= EPS {
+            return Err("The camera looks at its own position".into());
+        }
+        // The length of the cross product is the sine of the angle between up and look, a zero up
+        // gives NaN
+        let (left, sine) = Vec3(json.camera.up).normalize().cross(look).normalize_len();
+        if sine.is_nan() || sine <= EPS {
+            return Err("The camera's up is parallel to the direction it looks in".into());
+        }
+        let up = look.cross(left);
+        let camera = Camera::new(
+            position,
+            look,
+            up,
+            left,
+            Projection {
+                fov: json.camera.xfov.to_radians(),
+                fov_axis: FovAxis::Horizontal,
+                znear: json.camera.znear,
+                zfar: json.camera.zfar,
+                pixel_aspect: json.camera.pixel_aspect,
+            },
+            self.w,
+            self.h,
+        );
+
+        let mut material_indices = BTreeMap::new();
+        let mut materials = vec![];
+        for (name, material) in &json.materials {
+            let mut graph = GraphBuilder::new();
+            let bsdf = graph.add(bsdf_principled::Node {
+                base_color: Link::Constant(Vec4([
+                    material.color[0],
+                    material.color[1],
+                    material.color[2],
+                    1.0,
+                ])),
+                // The principled node stores specular in 1/0.08ths
+                specular: Link::Constant(material.specular / 0.08),
+                specular_tint: Link::Constant(material.specular_tint),
+                metallic: Link::Constant(material.metallic),
+                emission: Link::Constant(Vec3(material.emission).xyz1()),
+                emission_strength: Link::Constant(material.emission_strength),
+            });
+            material_indices.insert(name.as_str(), materials.len());
+            materials.push(graph.material(bsdf.bsdf(), material.double_sided));
+        }
+
+        let mut triangles = vec![];
+        for mesh in &json.meshes {
+            let material = *material_indices
+                .get(mesh.material.as_str())
+                .ok_or_else(|| format!("Unknown material {}", mesh.material))?;
+            for indices in &mesh.triangles {
+                let mut vertices = [Vertex {
+                    position: Vec3([0.0; 3]),
+                    normal: Vec3([0.0; 3]),
+                    tex_coord: Vec2([0.0; 2]),
+                    object_position: Vec3([0.0; 3]),
+                    tangent: None,
+                }; 3];
+                for (vertex, index) in vertices.iter_mut().zip(indices.iter()) {
+                    vertex.position = Vec3(
+                        *mesh
+                            .vertices
+                            .get(*index)
+                            .ok_or_else(|| format!("Vertex index {} out of range", index))?,
+                    );
+                    // Meshes are given in world space
+                    vertex.object_position = vertex.position;
+                    if let Some(normals) = &mesh.normals {
+                        vertex.normal = Vec3(
+                            *normals
+                                .get(*index)
+                                .ok_or_else(|| format!("Normal index {} out of range", index))?,
+                        );
+                    }
+                    if let Some(tex_coords) = &mesh.tex_coords {
+                        vertex.tex_coord = Vec2(*tex_coords.get(*index).ok_or_else(|| {
+                            format!("Texture coordinate index {} out of range", index)
+                        })?);
+                    }
+                    if let Some(tangents) = &mesh.tangents {
+                        vertex.tangent = Some(Vec4(
+                            *tangents
+                                .get(*index)
+                                .ok_or_else(|| format!("Tangent index {} out of range", index))?,
+                        ));
+                    }
+                }
+                if mesh.normals.is_none() {
+                    let normal = (vertices[1].position - vertices[0].position)
+                        .cross(vertices[2].position - vertices[0].position)
+                        .normalize();
+                    for vertex in vertices.iter_mut() {
+                        vertex.normal = normal;
+                    }
+                }
+                // Without normals the mesh is shaded flat
+                triangles.push(Triangle::new(
+                    vertices[0],
+                    vertices[1],
+                    vertices[2],
+                    material,
+                    mesh.normals.is_some(),
+                ));
+            }
+        }
+
+        let point_lights = json
+            .lights
+            .iter()
+            .map(|light| PointLight {
+                position: Vec3(light.position),
+                color: Vec3(light.color),
+                radius: light.radius,
+                a: 0.0,
+                b: 0.0,
+                c: 1.0,
+            })
+            .collect();
+        let light_names = json
+            .lights
+            .iter()
+            .enumerate()
+            .map(|(i, light)| light.name.clone().unwrap_or_else(|| format!("light{}", i)))
+            .collect();
+
+        Ok(Scene {
+            camera,
+            triangles,
+            meshes: vec![],
+            instances: vec![],
+            point_lights,
+            light_names,
+            materials,
+            images: vec![],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::tests::assert_vec3_eq;
+    use crate::math::AlmostEq;
+    use crate::scene::{output_material, Bsdf};
+
+    const EXAMPLE: &str = include_str!("../../examples/bent_quad.photon.json");
+
+    fn import_error(json: &str) -> String {
+        Photon::new(json, 4, 3).import().map(|_| ()).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn example_imports_materials_normals_and_lights() {
+        let scene = Photon::new(EXAMPLE, 4, 3).import().unwrap();
+        assert_eq!(scene.triangles.len(), 4);
+        assert_eq!(scene.light_names, vec!["key", "light1"]);
+        assert_eq!(scene.point_lights[0].radius, 0.2);
+        assert_eq!(scene.point_lights[1].radius, 0.0);
+
+        let floor = &scene.triangles[0];
+        let copper = &scene.triangles[2];
+        assert!(scene.materials[floor.material()].double_sided);
+        assert!(!scene.materials[copper.material()].double_sided);
+        let zero = Vec3([0.0; 3]);
+        let material = &scene.materials[copper.material()];
+        let bsdf: Bsdf = material
+            .graph
+            .new_context(&scene, Vec2([0.0; 2]), zero, zero, zero)
+            .evaluate_link(Link::Node(material.output, output_material::outputs::SURFACE));
+        assert_vec3_eq(bsdf.color, Vec3([0.95, 0.64, 0.54]), EPS);
+        assert!((bsdf.specular - 0.5).almost_zero());
+        assert!((bsdf.specular_tint - 0.25).almost_zero());
+        assert!((bsdf.metallic - 1.0).almost_zero());
+        assert_vec3_eq(bsdf.emission, Vec3([0.25, 0.125, 0.0]), EPS);
+
+        // The floor has no normals and is shaded flat, the bent quad interpolates its normals
+        let center = Vec3([1.0 / 3.0; 3]);
+        assert_vec3_eq(floor.normal(center).normalize(), Vec3([0.0, 0.0, 1.0]), EPS);
+        assert_vec3_eq(copper.normal(center), Vec3([0.0, -2.6, 0.8]) / 3.0, EPS);
+    }
+
+    #[test]
+    fn out_of_range_index_is_rejected() {
+        let json = EXAMPLE.replacen("[[0, 1, 2], [0, 2, 3]]", "[[0, 1, 2], [0, 2, 4]]", 1);
+        let message = import_error(&json);
+        assert!(message.contains("Vertex index 4 out of range"), "{}", message);
+    }
+
+    #[test]
+    fn camera_needs_up_apart_from_its_view_direction() {
+        let json = EXAMPLE.replace(r#""up": [0.0, 0.0, 1.0]"#, r#""up": [0.0, 4.0, -1.0]"#);
+        let message = import_error(&json);
+        assert!(message.contains("up is parallel"), "{}", message);
+
+        let json =
+            EXAMPLE.replace(r#""look_at": [0.0, 0.0, 0.5]"#, r#""look_at": [0.0, -4.0, 1.5]"#);
+        let message = import_error(&json);
+        assert!(message.contains("looks at its own position"), "{}", message);
+    }
+}
@@ -0,0 +1,47 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// Messages are printed to stderr if their level is at most the configured verbosity.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    // Problems which do not abort the program
+    Warning = 1,
+    // Progress and timings of the main phases
+    Info = 2,
+    // Details within the phases
+    Verbose = 3,
+}
+
+// 0 is --quiet, 2 is the default and 3 is -v
+static VERBOSITY: AtomicUsize = AtomicUsize::new(2);
+
+pub fn set_verbosity(verbosity: usize) {
+    VERBOSITY.store(verbosity, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as usize <= VERBOSITY.load(Ordering::Relaxed)
+}
+
+macro_rules! log_warning {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Warning) {
+            eprintln!("Warning: {}", format_args!($($arg)*));
+        }
+    };
+}
+
+macro_rules! log_info {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Info) {
+            eprintln!($($arg)*);
+        }
+    };
+}
+
+macro_rules! log_verbose {
+    ($($arg:tt)*) => {
+        if $crate::log::enabled($crate::log::Level::Verbose) {
+            eprintln!($($arg)*);
+        }
+    };
+}
@@ -3,18 +3,27 @@
 #[macro_use]
 extern crate clap;
 
-use import::{Blender, Import};
+#[macro_use]
+mod log;
+
+use flate2::read::GzDecoder;
+use import::{Blender, BlenderSettings, Import, Photon};
+use math::Vec3;
+use rand::Rng;
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::fmt::{Debug, Formatter};
 use std::io::Read;
 use std::path::Path;
 use std::process::{Command, Stdio};
 use std::str::FromStr;
-use std::sync::{atomic, Arc};
+use std::sync::Arc;
 use std::{fs, thread, time};
 
 mod gui;
 mod import;
 mod math;
+mod output;
 mod scene;
 mod simd;
 mod tracing;
@@ -45,112 +54,794 @@ fn main() -> Result<(), ErrorMessage> {
         (version: crate_version!())
         (author: crate_authors!("; "))
         (about: crate_description!())
-        (@arg INPUT: +required "file to render")
+        (@arg INPUT: required_unless[view] "file to render, - reads a .blend.json from stdin")
         (@arg OUTPUT: "file to write")
         (@arg headless: -H --headless "Do not show the GUI")
-        (@arg threads: -t --threads +takes_value default_value(&cpu_count_str) "Number of worker threads")
-        (@arg exposure: -e --exposure +takes_value default_value("0.0") "Exposure multiplier of the camera given as a power of two")
+        (@arg dry_run: --("dry-run") "Import the scene and build the BVH, then exit without rendering")
+        (@arg view: --view +takes_value conflicts_with[INPUT] "Show a saved image in the GUI instead of rendering, e.g. a .hdr or a PNG written with --no-tone-mapping")
+        (@arg threads: -t --threads +takes_value default_value(&cpu_count_str) "Number of worker threads, 0 uses all cores")
+        (@arg exposure: -e --exposure +takes_value default_value("0.0") "Exposure of the camera, colors are multiplied by e to the power of it")
+        (@arg white_balance: --("white-balance") +takes_value default_value("1,1,1") "Gains for the red, green and blue channels given as R,G,B, applied with the exposure")
         (@arg width: -x --width +takes_value default_value("1600") "Image width in pixels")
         (@arg height: -y --height +takes_value default_value("900") "Image height in pixels")
+        (@arg window_width: --("window-width") +takes_value "Width of the GUI window, the image is scaled to fit (defaults to the image width)")
+        (@arg window_height: --("window-height") +takes_value "Height of the GUI window, the image is scaled to fit (defaults to the image height)")
         (@arg antialiasing: -a --antialiasing +takes_value default_value("1") "Number of samples (as a power of four) to use per pixel")
-        (@arg seed: -s --seed +takes_value default_value("4103685768640310862782726084387274121") "Seed to use for random stuff")
+        (@arg supersample: --supersample +takes_value default_value("1") "Render at this multiple of the image size and downscale, in addition to the antialiasing")
+        (@arg shadow_samples: --("shadow-samples") +takes_value default_value("20") "Number of shadow rays per light for soft shadows")
+        (@arg hard_shadows: --("hard-shadows") "Shoot one shadow ray to the center of each light, ignoring its radius, for fast previews")
+        (@arg light_samples: --("light-samples") +takes_value default_value("0") "Number of lights to importance sample per shading point (0 uses all lights)")
+        (@arg light_cutoff: --("light-cutoff") +takes_value default_value("0.0") "Intensity below which a light is ignored (0 considers every light everywhere)")
+        (@arg report_bad_samples: --("report-bad-samples") "Log which materials produced samples that are black or not finite")
+        (@arg debug: --debug +takes_value "Visualize hits instead of shading them: normals, bary, tangents, depth, heat or albedo")
+        (@arg aov: --aov +takes_value +multiple number_of_values(1) "Also write a visualization like --debug to a PNG file, given as MODE:PATH")
+        (@arg no_preview: --("no-preview") "Do not show a quick preview in the GUI before the full render")
+        (@arg aa_pattern: --("aa-pattern") +takes_value default_value("rgss") "Where subpixels are sampled: rgss for fixed rotated grid offsets or jitter for random positions")
+        (@arg packet_tracing: --("packet-tracing") "Trace the primary rays of each antialiasing group as one packet")
+        (@arg scale: --scale +takes_value default_value("1.0") "Multiply all distances of a Blender scene by this factor on import")
+        (@arg camera: -c --camera +takes_value "Name of the camera to render in Blender scenes, defaults to the active camera")
+        (@arg time_limit: --("time-limit") +takes_value "Stop rendering after this many seconds and keep what was rendered so far")
+        (@arg blender_path: --("blender-path") +takes_value default_value("blender") "Blender executable used to export .blend files")
+        (@arg exporter_script: --("exporter-script") +takes_value default_value("blender_ray_exporter.py") "Export script which Blender runs on .blend files")
+        (@arg blender_timeout: --("blender-timeout") +takes_value default_value("600") "Seconds after which the Blender export is aborted, 0 waits forever")
+        (@arg light_scale: --("light-scale") +takes_value +multiple number_of_values(1) "Multiply the intensity of a light, given as NAME=FACTOR")
+        (@arg light_off: --("light-off") +takes_value +multiple number_of_values(1) "Remove the light with this name")
+        (@arg ground: --ground +takes_value "Add a large diffuse ground of this color, given as R,G,B, at z = 0")
+        (@arg remove_redundant: --("remove-redundant") "Remove duplicate triangles and triangles without area after the import")
+        (@arg strict_textures: --("strict-textures") "Abort the import if a texture cannot be loaded instead of using a placeholder")
+        (@arg max_texture_megapixels: --("max-texture-megapixels") +takes_value default_value("100") "Treat larger textures as unreadable instead of decoding them")
+        (@arg strict_materials: --("strict-materials") "Abort the import if a mesh has no material output instead of rendering it gray")
+        (@arg output_depth: --("output-depth") +takes_value default_value("8") "Bits per channel of the output image, 8 or 16")
+        (@arg output_linear: --("output-linear") "Write linear instead of sRGB encoded colors to the output image")
+        (@arg no_tone_mapping: --("no-tone-mapping") "Do not apply the Reinhard curve to the output image")
+        (@arg transparent: --transparent "Give the output image an alpha channel which is transparent where nothing was hit")
+        (@arg write_metadata: --("write-metadata") requires[OUTPUT] "Write the settings, scene counts and timings of the render next to the output image as .json")
+        (@arg stats_json: --("stats-json") +takes_value "Write ray counts and timings as JSON to this file, - for stdout")
+        (@arg quiet: -q --quiet conflicts_with[verbose] "Do not print anything except errors")
+        (@arg verbose: -v --verbose "Print details about each phase")
+        (@arg verbose_timing: --("verbose-timing") "Print a table of where the time went at the end")
+        (@arg seed: -s --seed +takes_value default_value("random") "Seed to use for random stuff, random draws a new one for every run")
     );
     let matches = clap_app.get_matches();
-    let thread_count: usize = FromStr::from_str(matches.value_of("threads").unwrap()).unwrap();
-    let window_w: usize = FromStr::from_str(matches.value_of("width").unwrap()).unwrap();
-    let window_h: usize = FromStr::from_str(matches.value_of("height").unwrap()).unwrap();
-    let exposure: f64 = FromStr::from_str(matches.value_of("exposure").unwrap()).unwrap();
-    let antialiasing: u32 = FromStr::from_str(matches.value_of("antialiasing").unwrap()).unwrap();
-    let seed: u128 = FromStr::from_str(matches.value_of("seed").unwrap()).unwrap();
+    log::set_verbosity(if matches.is_present("quiet") {
+        0
+    } else if matches.is_present("verbose") {
+        3
+    } else {
+        2
+    });
+    // The BVH traversal uses AVX2 intrinsics without a fallback, which would crash mid-render
+    if !is_x86_feature_detected!("avx2") {
+        return Err(ErrorMessage::from("This build requires a CPU with AVX2."));
+    }
+    let thread_count: usize = FromStr::from_str(matches.value_of("threads").unwrap())
+        .map_err(|e| format!("Invalid number of threads: {}", e))?;
+    let thread_count = if thread_count == 0 { num_cpus::get() } else { thread_count };
+    let window_w: usize = FromStr::from_str(matches.value_of("width").unwrap())
+        .map_err(|e| format!("Invalid width: {}", e))?;
+    let window_h: usize = FromStr::from_str(matches.value_of("height").unwrap())
+        .map_err(|e| format!("Invalid height: {}", e))?;
+    if window_w == 0 || window_h == 0 {
+        return Err(ErrorMessage::from("The image has to be at least one pixel wide and high."));
+    }
+    // The GUI alone keeps several buffers of this size
+    if window_w.saturating_mul(window_h) > 64 * 1024 * 1024 {
+        log_warning!("{}x{} pixels may not fit into memory.", window_w, window_h);
+    }
+    let window_width: Option<usize> = matches
+        .value_of("window_width")
+        .map(FromStr::from_str)
+        .transpose()
+        .map_err(|e| format!("Invalid window width: {}", e))?;
+    let window_height: Option<usize> = matches
+        .value_of("window_height")
+        .map(FromStr::from_str)
+        .transpose()
+        .map_err(|e| format!("Invalid window height: {}", e))?;
+    if window_width == Some(0) || window_height == Some(0) {
+        return Err(ErrorMessage::from("The window has to be at least one pixel wide and high."));
+    }
+    let exposure: f64 = FromStr::from_str(matches.value_of("exposure").unwrap())
+        .map_err(|e| format!("Invalid exposure: {}", e))?;
+    if !exposure.is_finite() {
+        return Err(ErrorMessage::from("The exposure has to be a finite number."));
+    }
+    let white_balance = rgb("white balance", matches.value_of("white_balance").unwrap())?;
+    if let Some(view_path) = matches.value_of("view") {
+        let image = output::ImageBuffer::read(view_path)?;
+        let window_size = window_size(image.w(), image.h(), window_width, window_height);
+        return view(image, window_size, exposure, white_balance);
+    }
+    let antialiasing: u32 = FromStr::from_str(matches.value_of("antialiasing").unwrap())
+        .map_err(|e| format!("Invalid antialiasing: {}", e))?;
+    let supersample: usize = FromStr::from_str(matches.value_of("supersample").unwrap())
+        .map_err(|e| format!("Invalid supersampling factor: {}", e))?;
+    if supersample == 0 {
+        return Err(ErrorMessage::from("The supersampling factor has to be at least 1."));
+    }
+    // The subpixels are numbered by a usize
+    let sample_count = 4usize
+        .checked_pow(antialiasing)
+        .and_then(|n| n.checked_mul(supersample))
+        .and_then(|n| n.checked_mul(supersample))
+        .and_then(|n| n.checked_mul(window_w))
+        .and_then(|n| n.checked_mul(window_h))
+        .ok_or_else(|| {
+            format!(
+                "Antialiasing {} with supersampling {} needs too many samples for {}x{} pixels.",
+                antialiasing, supersample, window_w, window_h
+            )
+        })?;
+    let seed: u128 = match matches.value_of("seed").unwrap() {
+        "random" => {
+            let seed = rand::rngs::OsRng.gen();
+            // Printed so the run can be reproduced
+            log_info!("Seed: {}", seed);
+            seed
+        }
+        seed => FromStr::from_str(seed).map_err(|e| format!("Invalid seed: {}", e))?,
+    };
+    let shadow_samples: u32 = FromStr::from_str(matches.value_of("shadow_samples").unwrap())
+        .map_err(|e| format!("Invalid number of shadow samples: {}", e))?;
+    // Soft shadows average their samples, so without one every light would be black
+    if shadow_samples == 0 {
+        return Err(ErrorMessage::from("The number of shadow samples has to be at least 1."));
+    }
+    let hard_shadows = matches.is_present("hard_shadows");
+    let light_samples: u32 = FromStr::from_str(matches.value_of("light_samples").unwrap())
+        .map_err(|e| format!("Invalid number of light samples: {}", e))?;
+    let light_cutoff: f64 = FromStr::from_str(matches.value_of("light_cutoff").unwrap())
+        .map_err(|e| format!("Invalid light cutoff: {}", e))?;
+    if !(light_cutoff >= 0.0 && light_cutoff.is_finite()) {
+        return Err(ErrorMessage::from("The light cutoff has to be a positive number."));
+    }
+    let time_limit = match matches.value_of("time_limit") {
+        Some(time_limit) => {
+            let seconds: f64 =
+                FromStr::from_str(time_limit).map_err(|e| format!("Invalid time limit: {}", e))?;
+            if !(0.0..1e9).contains(&seconds) {
+                return Err(ErrorMessage::from("The time limit has to be a positive number."));
+            }
+            Some(time::Duration::from_secs_f64(seconds))
+        }
+        None => None,
+    };
+    let strict_textures = matches.is_present("strict_textures");
+    let max_texture_megapixels: f64 =
+        FromStr::from_str(matches.value_of("max_texture_megapixels").unwrap())
+            .map_err(|e| format!("Invalid maximum texture size: {}", e))?;
+    if !(max_texture_megapixels >= 0.0 && max_texture_megapixels.is_finite()) {
+        return Err(ErrorMessage::from("The maximum texture size has to be a positive number."));
+    }
+    let max_texture_pixels = (max_texture_megapixels * 1e6) as u64;
+    let strict_materials = matches.is_present("strict_materials");
+    let headless = matches.is_present("headless");
+    let dry_run = matches.is_present("dry_run");
+    let output_path = matches.value_of("OUTPUT");
+    if let Some(output_path) = output_path {
+        if !output_path.ends_with(".png") {
+            return Err(ErrorMessage::from("Unknown output format."));
+        }
+    } else if headless && !matches.is_present("aov") {
+        log_warning!("Rendering without GUI and without output file.");
+    }
+    let output_depth: u8 = FromStr::from_str(matches.value_of("output_depth").unwrap())
+        .map_err(|e| format!("Invalid output depth: {}", e))?;
+    if output_depth != 8 && output_depth != 16 {
+        return Err(ErrorMessage::from("The output depth has to be 8 or 16."));
+    }
+    let png_settings = output::PngSettings {
+        exposure,
+        white_balance,
+        bit_depth: output_depth,
+        linear: matches.is_present("output_linear"),
+        tone_mapping: !matches.is_present("no_tone_mapping"),
+        transparent: matches.is_present("transparent"),
+    };
+    let packet_tracing = matches.is_present("packet_tracing");
+    let aa_pattern: tracing::AaPattern =
+        FromStr::from_str(matches.value_of("aa_pattern").unwrap())?;
+    // Nobody would see the preview without GUI
+    let preview = !headless && !matches.is_present("no_preview");
+    let debug: Option<tracing::DebugMode> =
+        matches.value_of("debug").map(FromStr::from_str).transpose()?;
+    let mut aovs = vec![];
+    let mut aov_paths = vec![];
+    for aov in matches.values_of("aov").into_iter().flatten() {
+        let mut parts = aov.splitn(2, ':');
+        let mode: tracing::DebugMode = FromStr::from_str(parts.next().unwrap())?;
+        let path =
+            parts.next().ok_or_else(|| format!("Invalid AOV {}, expected MODE:PATH.", aov))?;
+        if !path.ends_with(".png") {
+            return Err(ErrorMessage::from(format!("Unknown output format of AOV {}.", aov)));
+        }
+        aovs.push(mode);
+        aov_paths.push(path);
+    }
+    let aov_count = aovs.len();
 
+    // Printed as a table at the end with --verbose-timing
+    let mut phase_times = vec![];
     let scene = Arc::new({
         let start_time = time::Instant::now();
 
         let path = matches.value_of("INPUT").unwrap();
+        let camera_name = matches.value_of("camera");
+        let scale: f64 = FromStr::from_str(matches.value_of("scale").unwrap())
+            .map_err(|e| format!("Invalid scale: {}", e))?;
+        if !(scale > 0.0 && scale.is_finite()) {
+            return Err(ErrorMessage::from("The scale has to be positive."));
+        }
 
-        let scene = if path.ends_with(".blend") {
-            eprintln!("Starting Blender ...");
-            let result = Command::new("blender")
-                .args(&[path, "-b", "--log-level", "0", "-P", "blender_ray_exporter.py", "--"])
-                .stderr(Stdio::null())
-                .stdout(Stdio::piped())
-                .stdin(Stdio::null())
-                .output()
-                .map_err(|e| format!("Could not execute blender: {}", e))?;
-            eprintln!("Blender done.");
-            if !result.status.success() {
-                Err("Blender export did not exit successfully!".to_owned())
+        // - reads a .blend.json from stdin
+        let from_stdin = path == "-";
+        let mut scene = if from_stdin
+            || path.ends_with(".blend")
+            || path.ends_with(".blend.json")
+            || path.ends_with(".blend.json.gz")
+        {
+            let json_text = if path.ends_with(".blend") {
+                log_info!("Starting Blender ...");
+                let blender_path = matches.value_of("blender_path").unwrap();
+                let exporter_script = matches.value_of("exporter_script").unwrap();
+                if !Path::new(exporter_script).is_file() {
+                    return Err(ErrorMessage::from(format!(
+                        "Export script {} not found, see --exporter-script.",
+                        exporter_script
+                    )));
+                }
+                let timeout: u64 = FromStr::from_str(matches.value_of("blender_timeout").unwrap())
+                    .map_err(|e| format!("Invalid Blender timeout: {}", e))?;
+                let timeout =
+                    if timeout == 0 { None } else { Some(time::Duration::from_secs(timeout)) };
+                let blender_start_time = time::Instant::now();
+                let json_text = run_blender(blender_path, path, exporter_script, timeout)?;
+                phase_times.push(("Blender export", blender_start_time.elapsed()));
+                log_info!("Blender done.");
+                json_text[json_text.find('{').ok_or("Missing first { in JSON.")?
+                    ..=json_text.rfind('}').ok_or("Missing last } in JSON.")?]
+                    .to_owned()
+            } else if from_stdin {
+                let mut text = String::new();
+                std::io::stdin()
+                    .read_to_string(&mut text)
+                    .map_err(|e| format!("Standard input cannot be read: {}", e))?;
+                text
             } else {
-                let json_text = String::from_utf8(result.stdout)
-                    .map_err(|e| format!("Encoding error: {}", e))?;
-                let json_text = &json_text[json_text.find('{').ok_or("Missing first { in JSON.")?
-                    ..=json_text.rfind('}').ok_or("Missing last } in JSON.")?];
-                Blender::new(
-                    Path::new(path)
-                        .parent()
-                        .ok_or("Cannot get parent directory")?
-                        .to_str()
-                        .ok_or("Path contains invalid characters")?,
-                    &json_text,
-                    window_w,
-                    window_h,
-                )
-                .import()
-                .map_err(|e| format!("Error during Blender import: {}", e))
-            }
-        } else if path.ends_with(".blend.json") {
+                let mut file_text = String::new();
+                let infile = fs::File::open(path)
+                    .map_err(|e| format!("File {} cannot be opened: {}", path, e))?;
+                // Exports of large meshes compress well, so they may be gzipped
+                let mut reader: Box<dyn Read> = if path.ends_with(".gz") {
+                    Box::new(GzDecoder::new(infile))
+                } else {
+                    Box::new(infile)
+                };
+                reader
+                    .read_to_string(&mut file_text)
+                    .map_err(|e| format!("File {} cannot be read: {}", path, e))?;
+                file_text
+            };
+            let import_start_time = time::Instant::now();
+            // Relative texture paths start at the current directory for scenes from stdin and
+            // for bare file names, whose parent is empty
+            let pwd = if from_stdin {
+                "."
+            } else {
+                match Path::new(path).parent().ok_or("Cannot get parent directory")? {
+                    parent if parent.as_os_str().is_empty() => ".",
+                    parent => parent.to_str().ok_or("Path contains invalid characters")?,
+                }
+            };
+            let importer = Blender::new(
+                pwd,
+                &json_text,
+                window_w,
+                window_h,
+                camera_name,
+                BlenderSettings {
+                    strict_textures,
+                    max_texture_pixels,
+                    strict_materials,
+                    thread_count,
+                    scale,
+                },
+            );
+            let scene =
+                importer.import().map_err(|e| format!("Error during Blender import: {}", e));
+            phase_times.push(("Scene import", import_start_time.elapsed()));
+            phase_times.push(("  Texture decoding", importer.image_time()));
+            scene
+        } else if path.ends_with(".photon.json") {
             let mut file_text = String::new();
             let mut infile = fs::File::open(path)
                 .map_err(|e| format!("File {} cannot be opened: {}", path, e))?;
             infile
                 .read_to_string(&mut file_text)
                 .map_err(|e| format!("File {} cannot be read: {}", path, e))?;
-            Blender::new(
-                Path::new(path)
-                    .parent()
-                    .ok_or("Cannot get parent directory")?
-                    .to_str()
-                    .ok_or("Path contains invalid characters")?,
-                &file_text,
-                window_w,
-                window_h,
-            )
-            .import()
-            .map_err(|e| format!("Error during Blender JSON import: {}", e))
+            if camera_name.is_some() {
+                return Err(ErrorMessage::from("Photon scenes only have a single camera."));
+            }
+            if scale != 1.0 {
+                return Err(ErrorMessage::from("Only Blender scenes can be scaled."));
+            }
+            let import_start_time = time::Instant::now();
+            let scene = Photon::new(&file_text, window_w, window_h)
+                .import()
+                .map_err(|e| format!("Error during photon JSON import: {}", e));
+            phase_times.push(("Scene import", import_start_time.elapsed()));
+            scene
         } else {
             Err("Unknown input format.".to_owned())
         }?;
 
         let end_time = time::Instant::now();
-        eprintln!("Parsing input file: {} ms", (end_time - start_time).as_millis());
+        log_info!("Parsing input file: {} ms", (end_time - start_time).as_millis());
+        let summary = scene.summary();
+        let summary_text = format!(
+            "Scene: {} triangles, {} meshes with {} instances, {} lights, {} materials, {} images",
+            summary.triangles,
+            scene.meshes.len(),
+            scene.instances.len(),
+            summary.lights,
+            summary.materials,
+            summary.images
+        );
+        // Checking the scene is all a dry run does, so it always reports what it found
+        if dry_run {
+            log_info!("{}", summary_text);
+        } else {
+            log_verbose!("{}", summary_text);
+        }
+        match summary.aabb {
+            Some((min, max)) => log_verbose!("Scene bounds: {:?} to {:?}", min.0, max.0),
+            None => log_warning!("The scene does not contain any triangles."),
+        }
+
+        for light_scale in matches.values_of("light_scale").into_iter().flatten() {
+            let mut parts = light_scale.rsplitn(2, '=');
+            let factor = parts.next().unwrap();
+            let name = parts.next().ok_or_else(|| {
+                format!("Invalid light scale {}, expected NAME=FACTOR.", light_scale)
+            })?;
+            let factor: f64 = FromStr::from_str(factor)
+                .map_err(|e| format!("Invalid light scale {}: {}", light_scale, e))?;
+            if !scene.scale_light(name, factor) {
+                return Err(ErrorMessage::from(format!("Light {} not found.", name)));
+            }
+        }
+        for name in matches.values_of("light_off").into_iter().flatten() {
+            if !scene.remove_light(name) {
+                return Err(ErrorMessage::from(format!("Light {} not found.", name)));
+            }
+        }
+
+        if matches.is_present("remove_redundant") {
+            let before = scene.triangles.len() + scene.meshes.iter().map(Vec::len).sum::<usize>();
+            let removed = scene.remove_redundant_triangles();
+            log_info!("Removing redundant triangles: {} -> {}", before, before - removed);
+        }
+
+        if let Some(ground) = matches.value_of("ground") {
+            scene.add_ground(rgb("ground color", ground)?);
+        }
 
         scene
     });
 
-    let (pixel_sender, pixel_receiver) = crossbeam_channel::unbounded();
-    let want_quit = Arc::new(atomic::AtomicBool::new(false));
+    let render_settings = tracing::RenderSettings {
+        shadow_samples,
+        hard_shadows,
+        light_samples,
+        light_cutoff,
+        packet_tracing,
+        aa_pattern,
+        debug,
+        report_bad_samples: matches.is_present("report_bad_samples"),
+        antialiasing,
+        supersample,
+        preview,
+        aovs,
+        seed,
+        thread_count,
+    };
+    if dry_run {
+        let bvh_time = tracing::build_only(&scene, &render_settings);
+        if matches.is_present("verbose_timing") {
+            phase_times.push(("BVH", bvh_time));
+            print_timing(&phase_times, None);
+        }
+        return Ok(());
+    }
 
-    let window_thread = {
-        let want_quit = Arc::clone(&want_quit);
+    let (render_sender, render_receiver) = crossbeam_channel::unbounded();
+    let (camera_sender, camera_receiver) = crossbeam_channel::unbounded();
+    let cancel = tracing::Cancel::new();
+    if let Some(time_limit) = time_limit {
+        let cancel = cancel.clone();
         thread::Builder::new()
+            .name("Time limit".to_owned())
+            .spawn(move || {
+                thread::sleep(time_limit);
+                cancel.cancel();
+            })
+            .unwrap();
+    }
+
+    // Without the GUI, SDL is never initialized, so no display is needed
+    let (pixel_sender, window_thread) = if headless {
+        // Nobody moves the camera, so the renderer stops after the first frame
+        drop(camera_sender);
+        (None, None)
+    } else {
+        let (pixel_sender, pixel_receiver) = crossbeam_channel::unbounded();
+        let cancel = cancel.clone();
+        let camera = scene.camera;
+        let window_size = window_size(window_w, window_h, window_width, window_height);
+        let window_thread = thread::Builder::new()
             .name("GUI".to_owned())
             .spawn(move || {
-                gui::main_loop(window_w, window_h, exposure, pixel_receiver, &want_quit);
+                gui::main_loop(
+                    (window_w, window_h),
+                    window_size,
+                    exposure,
+                    white_balance,
+                    Some((camera, &camera_sender)),
+                    pixel_receiver,
+                    &cancel,
+                );
             })
-            .unwrap()
+            .unwrap();
+        (Some(pixel_sender), Some(window_thread))
     };
 
-    tracing::main(
+    // Forwards the pixels to the GUI and collects them for the output image and the AOVs. Once a
+    // frame is complete, the output keeps it and only the GUI follows the camera. Returns whether
+    // the output shows a camera moved by the GUI, because it was moved before that.
+    let output_thread = thread::Builder::new()
+        .name("Output".to_owned())
+        .spawn(move || {
+            let new_aov_images =
+                || (0..aov_count).map(|_| output::ImageBuffer::new(window_w, window_h)).collect();
+            let mut image = output::ImageBuffer::new(window_w, window_h);
+            let mut aov_images: Vec<_> = new_aov_images();
+            let mut samples = 0;
+            let mut camera_moved = false;
+            for message in render_receiver.iter() {
+                let complete = samples == sample_count;
+                match message {
+                    tracing::RenderMessage::Pixel(x, y, color, hit) if !complete => {
+                        image.add(x, y, color, hit);
+                        samples += 1;
+                    }
+                    tracing::RenderMessage::Aov(aov, x, y, color, hit) => {
+                        if !complete {
+                            aov_images[aov].add(x, y, color, hit);
+                        }
+                        continue;
+                    }
+                    tracing::RenderMessage::Restart if !complete => {
+                        image = output::ImageBuffer::new(window_w, window_h);
+                        aov_images = new_aov_images();
+                        samples = 0;
+                        camera_moved = true;
+                    }
+                    // The output image only gets the full render
+                    _ => {}
+                }
+                if let Some(pixel_sender) = &pixel_sender {
+                    // The GUI may have been closed already
+                    pixel_sender.send(message).ok();
+                }
+            }
+            (image, aov_images, camera_moved)
+        })
+        .unwrap();
+
+    let triangle_count = scene.triangles.len() + scene.meshes.iter().map(Vec::len).sum::<usize>();
+    let light_count = scene.point_lights.len();
+    let stats = tracing::main(
         scene,
-        antialiasing,
+        render_settings,
         window_w,
         window_h,
-        thread_count,
-        seed,
-        want_quit,
-        pixel_sender,
+        cancel,
+        camera_receiver,
+        render_sender,
     );
 
-    window_thread.join().unwrap();
+    if let Some(stats_path) = matches.value_of("stats_json") {
+        let json = serde_json::to_string_pretty(&stats)
+            .map_err(|e| format!("Cannot serialize statistics: {}", e))?;
+        if stats_path == "-" {
+            println!("{}", json);
+        } else {
+            fs::write(stats_path, json + "\n")
+                .map_err(|e| format!("File {} cannot be written: {}", stats_path, e))?;
+        }
+    }
+
+    let (image, aov_images, camera_moved) = output_thread.join().unwrap();
+    if camera_moved && (output_path.is_some() || !aov_paths.is_empty()) {
+        log_warning!(
+            "The camera was moved before the first frame was done, the output shows the new view."
+        );
+    }
+    let png_start_time = time::Instant::now();
+    if let Some(output_path) = output_path {
+        output::write_png(&image, output_path, &png_settings, thread_count)?;
+        log_verbose!("Wrote {}", output_path);
+    }
+    // AOVs are data rather than pictures, so their values are written unchanged
+    let aov_png_settings = output::PngSettings {
+        exposure: 0.0,
+        white_balance: Vec3([1.0; 3]),
+        linear: true,
+        tone_mapping: false,
+        ..png_settings
+    };
+    for (aov_image, aov_path) in aov_images.iter().zip(&aov_paths) {
+        output::write_png(aov_image, aov_path, &aov_png_settings, thread_count)?;
+        log_verbose!("Wrote {}", aov_path);
+    }
+    if output_path.is_some() || !aov_paths.is_empty() {
+        phase_times.push(("PNG output", png_start_time.elapsed()));
+    }
+
+    if let (true, Some(output_path)) = (matches.is_present("write_metadata"), output_path) {
+        let input = matches.value_of("INPUT").unwrap();
+        // Standard input is gone by now
+        let input_fnv1a = if input == "-" {
+            None
+        } else {
+            let input_data =
+                fs::read(input).map_err(|e| format!("File {} cannot be read: {}", input, e))?;
+            Some(format!("{:016x}", fnv1a(&input_data)))
+        };
+        let metadata = Metadata {
+            input,
+            input_fnv1a,
+            camera: matches.value_of("camera"),
+            camera_moved,
+            width: window_w,
+            height: window_h,
+            antialiasing,
+            supersample,
+            exposure,
+            white_balance: white_balance.0,
+            seed: seed.to_string(),
+            threads: thread_count,
+            shadow_samples,
+            hard_shadows,
+            light_samples,
+            light_cutoff,
+            packet_tracing,
+            aa_pattern,
+            triangles: triangle_count,
+            lights: light_count,
+            phase_ms: phase_times
+                .iter()
+                .map(|(name, time)| (name.trim(), time.as_millis() as u64))
+                .collect(),
+            stats: &stats,
+        };
+        let metadata_path = Path::new(output_path).with_extension("json");
+        let json = serde_json::to_string_pretty(&metadata)
+            .map_err(|e| format!("Cannot serialize metadata: {}", e))?;
+        fs::write(&metadata_path, json + "\n")
+            .map_err(|e| format!("File {} cannot be written: {}", metadata_path.display(), e))?;
+        log_verbose!("Wrote {}", metadata_path.display());
+    }
+
+    if matches.is_present("verbose_timing") {
+        print_timing(&phase_times, Some(&stats));
+    }
+
+    if let Some(window_thread) = window_thread {
+        window_thread.join().unwrap();
+    }
     Ok(())
 }
+
+// Everything needed to reproduce or audit a render, written by --write-metadata
+#[derive(Serialize)]
+struct Metadata<'a> {
+    input: &'a str,
+    // FNV-1a hash of the input file, to tell whether it changed since. None for stdin.
+    input_fnv1a: Option<String>,
+    camera: Option<&'a str>,
+    // The GUI moved the camera before the first frame was done, so the image shows another view
+    camera_moved: bool,
+    width: usize,
+    height: usize,
+    antialiasing: u32,
+    supersample: usize,
+    exposure: f64,
+    white_balance: [f64; 3],
+    // As a string, since JSON numbers cannot hold 128 bits
+    seed: String,
+    threads: usize,
+    shadow_samples: u32,
+    hard_shadows: bool,
+    light_samples: u32,
+    light_cutoff: f64,
+    packet_tracing: bool,
+    aa_pattern: tracing::AaPattern,
+    triangles: usize,
+    lights: usize,
+    // Phases timed in main, the BVH and raytracing times are part of the stats
+    phase_ms: BTreeMap<&'a str, u64>,
+    stats: &'a tracing::RenderStats,
+}
+
+fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+// The size of the GUI window for an image of the given size. If only one side is given, the other
+// one follows the aspect ratio of the image.
+fn window_size(
+    image_w: usize,
+    image_h: usize,
+    width: Option<usize>,
+    height: Option<usize>,
+) -> (usize, usize) {
+    match (width, height) {
+        (Some(width), Some(height)) => (width, height),
+        (Some(width), None) => (width, (width * image_h / image_w).max(1)),
+        (None, Some(height)) => ((height * image_w / image_h).max(1), height),
+        (None, None) => (image_w, image_h),
+    }
+}
+
+// Parses a color or channel gains given as R,G,B, e.g. for --white-balance. what names the
+// value in errors.
+fn rgb(what: &str, value: &str) -> Result<Vec3, ErrorMessage> {
+    let channels: Vec<f64> = value
+        .split(',')
+        .map(|channel| FromStr::from_str(channel.trim()))
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("Invalid {} {}: {}", what, value, e))?;
+    match channels[..] {
+        [r, g, b] if r >= 0.0 && g >= 0.0 && b >= 0.0 => Ok(Vec3([r, g, b])),
+        _ => Err(ErrorMessage::from(format!(
+            "Invalid {} {}, expected three values R,G,B that are not negative.",
+            what, value
+        ))),
+    }
+}
+
+// Shows the image in the GUI, where only the exposure can be changed
+fn view(
+    image: output::ImageBuffer,
+    window_size: (usize, usize),
+    exposure: f64,
+    white_balance: Vec3,
+) -> Result<(), ErrorMessage> {
+    let (pixel_sender, pixel_receiver) = crossbeam_channel::unbounded();
+    for (i, color) in image.colors(false).into_iter().enumerate() {
+        pixel_sender
+            .send(tracing::RenderMessage::Pixel(i % image.w(), i / image.w(), color, true))
+            .unwrap();
+    }
+    // Nothing is rendered, so there is no camera to move
+    gui::main_loop(
+        (image.w(), image.h()),
+        window_size,
+        exposure,
+        white_balance,
+        None,
+        pixel_receiver,
+        &tracing::Cancel::new(),
+    );
+    Ok(())
+}
+
+// Prints how long each phase took, how the rays were split up and how evenly the workers were
+// loaded. Without stats, as for a dry run, only the phases timed in main are printed.
+fn print_timing(phase_times: &[(&str, time::Duration)], stats: Option<&tracing::RenderStats>) {
+    let stats_times =
+        stats.map(|stats| vec![("BVH", stats.bvh_ms), ("Raytracing", stats.render_ms)]);
+    let phase_times = phase_times
+        .iter()
+        .map(|(name, time)| (*name, time.as_millis() as u64))
+        .chain(stats_times.into_iter().flatten());
+    eprintln!("{:<20} {:>10}", "Phase", "Time");
+    for (name, ms) in phase_times {
+        eprintln!("{:<20} {:>7} ms", name, ms);
+    }
+    let stats = match stats {
+        Some(stats) => stats,
+        None => return,
+    };
+
+    let rays = (stats.primary_rays + stats.reflection_rays + stats.shadow_rays).max(1);
+    eprintln!();
+    eprintln!("{:<20} {:>10} {:>7}", "Rays", "Count", "Share");
+    for (name, count) in &[
+        ("Primary", stats.primary_rays),
+        ("Reflection", stats.reflection_rays),
+        ("Shadow", stats.shadow_rays),
+    ] {
+        eprintln!("{:<20} {:>10} {:>6.1}%", name, count, *count as f64 * 100.0 / rays as f64);
+    }
+
+    let busy_ms = &stats.worker_busy_ms;
+    if !busy_ms.is_empty() {
+        eprintln!();
+        eprintln!(
+            "Worker busy time: min {} ms, mean {} ms, max {} ms",
+            busy_ms.iter().min().unwrap(),
+            busy_ms.iter().sum::<u64>() / busy_ms.len() as u64,
+            busy_ms.iter().max().unwrap()
+        );
+        eprintln!("Slowest work item: {} us", stats.slowest_item_us);
+    }
+}
+
+// Runs the export script on the .blend file and returns what it printed. Blender is killed if it
+// takes longer than the timeout. On failure, the error contains Blender's stderr.
+fn run_blender(
+    blender_path: &str,
+    path: &str,
+    exporter_script: &str,
+    timeout: Option<time::Duration>,
+) -> Result<String, String> {
+    let mut child = Command::new(blender_path)
+        .args([path, "-b", "--log-level", "0", "--python-exit-code", "1"])
+        .args(["-P", exporter_script, "--"])
+        .stderr(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stdin(Stdio::null())
+        .spawn()
+        .map_err(|e| format!("Could not execute {}: {}", blender_path, e))?;
+
+    // Both pipes are drained while waiting, as Blender blocks as soon as one of them is full
+    let mut stdout = child.stdout.take().unwrap();
+    let mut stderr = child.stderr.take().unwrap();
+    let stdout_thread = thread::spawn(move || {
+        let mut text = vec![];
+        stdout.read_to_end(&mut text).map(|_| text)
+    });
+    let stderr_thread = thread::spawn(move || {
+        let mut text = vec![];
+        stderr.read_to_end(&mut text).ok();
+        String::from_utf8_lossy(&text).into_owned()
+    });
+
+    let start_time = time::Instant::now();
+    let status = loop {
+        if let Some(status) =
+            child.try_wait().map_err(|e| format!("Could not wait for Blender: {}", e))?
+        {
+            break status;
+        }
+        if let Some(timeout) = timeout {
+            if start_time.elapsed() > timeout {
+                child.kill().ok();
+                child.wait().ok();
+                return Err(format!(
+                    "Blender did not finish within {} s and was killed, see --blender-timeout.",
+                    timeout.as_secs()
+                ));
+            }
+        }
+        thread::sleep(time::Duration::from_millis(50));
+    };
+
+    let stdout = stdout_thread
+        .join()
+        .unwrap()
+        .map_err(|e| format!("Cannot read Blender's output: {}", e))?;
+    let stderr = stderr_thread.join().unwrap();
+    if !status.success() {
+        return Err(format!(
+            "Blender export did not exit successfully ({}):\n{}",
+            status,
+            stderr.trim()
+        ));
+    }
+    String::from_utf8(stdout).map_err(|e| format!("Encoding error: {}", e))
+}
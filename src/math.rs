@@ -1,5 +1,44 @@
 use std::fmt::{Debug, Formatter};
-use std::ops::{Add, AddAssign, Div, DivAssign, Mul, Neg, Sub};
+use std::ops::{Add, AddAssign, Div, DivAssign, Index, IndexMut, Mul, Neg, Sub};
+
+// Component access by index and conversions from and to plain arrays
+macro_rules! impl_array_access {
+    ($vec:ident, $n:expr) => {
+        impl Index<usize> for $vec {
+            type Output = f64;
+
+            #[inline(always)]
+            fn index(&self, index: usize) -> &f64 {
+                &self.0[index]
+            }
+        }
+
+        impl IndexMut<usize> for $vec {
+            #[inline(always)]
+            fn index_mut(&mut self, index: usize) -> &mut f64 {
+                &mut self.0[index]
+            }
+        }
+
+        impl From<[f64; $n]> for $vec {
+            #[inline(always)]
+            fn from(array: [f64; $n]) -> $vec {
+                $vec(array)
+            }
+        }
+
+        impl From<$vec> for [f64; $n] {
+            #[inline(always)]
+            fn from(vec: $vec) -> [f64; $n] {
+                vec.0
+            }
+        }
+    };
+}
+
+impl_array_access!(Vec2, 2);
+impl_array_access!(Vec3, 3);
+impl_array_access!(Vec4, 4);
 
 #[derive(Copy, Clone, PartialEq)]
 pub struct Vec2(pub vecmath::Vector2<f64>);
@@ -40,6 +79,15 @@ impl Add<Vec2> for Vec2 {
     }
 }
 
+impl Sub<Vec2> for Vec2 {
+    type Output = Vec2;
+
+    #[inline(always)]
+    fn sub(self, rhs: Vec2) -> Vec2 {
+        Vec2(vecmath::vec2_sub(self.0, rhs.0))
+    }
+}
+
 #[derive(Copy, Clone, PartialEq)]
 pub struct Vec3(pub vecmath::Vector3<f64>);
 
@@ -70,6 +118,15 @@ impl Vec3 {
         Vec3(vecmath::vec3_cross(self.0, rhs.0))
     }
 
+    // Two unit vectors which are perpendicular to this unit vector and to each other, with
+    // (t, b, self) right-handed
+    pub fn orthonormal_basis(self) -> (Vec3, Vec3) {
+        let helper =
+            if self.x().abs() < 0.9 { Vec3([1.0, 0.0, 0.0]) } else { Vec3([0.0, 1.0, 0.0]) };
+        let tangent = helper.cross(self).normalize();
+        (tangent, self.cross(tangent))
+    }
+
     #[inline(always)]
     pub fn dot(self, rhs: Vec3) -> f64 {
         vecmath::vec3_dot(self.0, rhs.0)
@@ -120,6 +177,13 @@ impl Vec3 {
     pub fn manhattan_len(self) -> f64 {
         self.0[0].abs() + self.0[1].abs() + self.0[2].abs()
     }
+
+    // The exact sRGB transfer function, as applied by GL_FRAMEBUFFER_SRGB
+    pub fn linear_to_srgb(self) -> Vec3 {
+        let encode =
+            |c: f64| if c <= 0.003_130_8 { c * 12.92 } else { 1.055 * c.powf(1.0 / 2.4) - 0.055 };
+        Vec3([encode(self.0[0]), encode(self.0[1]), encode(self.0[2])])
+    }
 }
 
 impl Mul<Vec3> for f64 {
@@ -269,6 +333,59 @@ impl Div<f64> for Vec4 {
     }
 }
 
+impl Mul<Vec4> for f64 {
+    type Output = Vec4;
+
+    #[inline(always)]
+    fn mul(self, rhs: Vec4) -> Vec4 {
+        Vec4(vecmath::vec4_mul([self, self, self, self], rhs.0))
+    }
+}
+
+impl Mul<Vec4> for Vec4 {
+    type Output = Vec4;
+
+    #[inline(always)]
+    fn mul(self, rhs: Vec4) -> Vec4 {
+        Vec4(vecmath::vec4_mul(self.0, rhs.0))
+    }
+}
+
+impl Neg for Vec4 {
+    type Output = Vec4;
+
+    #[inline(always)]
+    fn neg(self) -> Vec4 {
+        Vec4(vecmath::vec4_neg(self.0))
+    }
+}
+
+impl Sub<Vec4> for Vec4 {
+    type Output = Vec4;
+
+    #[inline(always)]
+    fn sub(self, rhs: Vec4) -> Vec4 {
+        Vec4(vecmath::vec4_sub(self.0, rhs.0))
+    }
+}
+
+impl AddAssign<Vec4> for Vec4 {
+    #[inline(always)]
+    fn add_assign(&mut self, rhs: Vec4) {
+        self.0 = vecmath::vec4_add(self.0, rhs.0);
+    }
+}
+
+impl DivAssign<f64> for Vec4 {
+    #[inline(always)]
+    fn div_assign(&mut self, rhs: f64) {
+        self.0[0] /= rhs;
+        self.0[1] /= rhs;
+        self.0[2] /= rhs;
+        self.0[3] /= rhs;
+    }
+}
+
 #[derive(Copy, Clone, PartialEq)]
 pub struct Mat4(pub vecmath::Matrix4<f64>); // column major
 
@@ -304,30 +421,16 @@ impl Mul<Vec4> for Mat4 {
 
 impl Mat4 {
     #[inline(always)]
-    pub fn rotation_around_vector(axis: Vec3, angle: f64 /* in rad */) -> Mat4 {
-        let (x, y, z) = (axis.0[0], axis.0[1], axis.0[2]);
-        let a = 1.0 - angle.cos();
-        Mat4([
-            [
-                x * x * a + angle.cos(),
-                x * y * a - z * angle.sin(),
-                x * z * a + y * angle.sin(),
-                0.0,
-            ],
-            [
-                y * x * a + z * angle.sin(),
-                y * y * a + angle.cos(),
-                y * z * a - x * angle.sin(),
-                0.0,
-            ],
-            [
-                z * x * a - y * angle.sin(),
-                z * y * a + x * angle.sin(),
-                z * z * a + angle.cos(),
-                0.0,
-            ],
-            [0.0, 0.0, 0.0, 1.0],
-        ])
+    pub fn identity() -> Mat4 {
+        Mat4(vecmath::mat4_id())
+    }
+
+    pub fn scaling(factor: f64) -> Mat4 {
+        let mut matrix = Mat4::identity();
+        for i in 0..3 {
+            matrix.0[i][i] = factor;
+        }
+        matrix
     }
 
     #[inline(always)]
@@ -339,24 +442,154 @@ impl Mat4 {
     pub fn transpose(self) -> Mat4 {
         Mat4(vecmath::mat4_transposed(self.0))
     }
+
+    // Negative for transformations which mirror
+    #[inline(always)]
+    pub fn det(self) -> f64 {
+        vecmath::mat4_det(self.0)
+    }
+}
+
+// A rotation as unit quaternion w + v.x * i + v.y * j + v.z * k
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Quat {
+    pub v: Vec3,
+    pub w: f64,
+}
+
+// Only some of these are used by the GUI's camera controls so far
+#[allow(dead_code)]
+impl Quat {
+    pub fn identity() -> Quat {
+        Quat { v: Vec3([0.0; 3]), w: 1.0 }
+    }
+
+    // Counter-clockwise rotation around the normalized axis, angle in radians
+    pub fn from_axis_angle(axis: Vec3, angle: f64) -> Quat {
+        Quat { v: axis * (angle / 2.0).sin(), w: (angle / 2.0).cos() }
+    }
+
+    pub fn dot(self, rhs: Quat) -> f64 {
+        self.v.dot(rhs.v) + self.w * rhs.w
+    }
+
+    pub fn normalize(self) -> Quat {
+        let len = self.dot(self).sqrt();
+        Quat { v: self.v / len, w: self.w / len }
+    }
+
+    // Interpolates between the rotations at constant angular velocity, t = 0 returns self.
+    pub fn slerp(self, rhs: Quat, t: f64) -> Quat {
+        // q and -q are the same rotation, take the one on the shorter arc
+        let (rhs, cos) = if self.dot(rhs) < 0.0 {
+            (Quat { v: -rhs.v, w: -rhs.w }, -self.dot(rhs))
+        } else {
+            (rhs, self.dot(rhs))
+        };
+
+        let (a, b) = if cos > 1.0 - EPS {
+            // sin(angle) is too close to zero, but the arc is almost straight anyway
+            (1.0 - t, t)
+        } else {
+            let angle = cos.acos();
+            (((1.0 - t) * angle).sin() / angle.sin(), (t * angle).sin() / angle.sin())
+        };
+        Quat { v: self.v * a + rhs.v * b, w: self.w * a + rhs.w * b }.normalize()
+    }
+}
+
+impl Mul<Quat> for Quat {
+    type Output = Quat;
+
+    // Applies rhs first, then self
+    fn mul(self, rhs: Quat) -> Quat {
+        Quat {
+            v: rhs.v * self.w + self.v * rhs.w + self.v.cross(rhs.v),
+            w: self.w * rhs.w - self.v.dot(rhs.v),
+        }
+    }
+}
+
+impl From<Quat> for Mat4 {
+    fn from(q: Quat) -> Mat4 {
+        let (x, y, z, w) = (q.v.x(), q.v.y(), q.v.z(), q.w);
+        Mat4([
+            [1.0 - 2.0 * (y * y + z * z), 2.0 * (x * y + z * w), 2.0 * (x * z - y * w), 0.0],
+            [2.0 * (x * y - z * w), 1.0 - 2.0 * (x * x + z * z), 2.0 * (y * z + x * w), 0.0],
+            [2.0 * (x * z + y * w), 2.0 * (y * z - x * w), 1.0 - 2.0 * (x * x + y * y), 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ])
+    }
 }
 
+impl From<Mat4> for Quat {
+    // Only looks at the rotational part, which must not be scaled
+    fn from(m: Mat4) -> Quat {
+        // m.0 is column major, so r(row, column) is a lot easier to read
+        let r = |row: usize, column: usize| m.0[column][row];
+        let trace = r(0, 0) + r(1, 1) + r(2, 2);
+        // Divide by the largest component to keep the precision
+        if trace > 0.0 {
+            let s = (trace + 1.0).sqrt() * 2.0;
+            Quat {
+                v: Vec3([
+                    (r(2, 1) - r(1, 2)) / s,
+                    (r(0, 2) - r(2, 0)) / s,
+                    (r(1, 0) - r(0, 1)) / s,
+                ]),
+                w: s / 4.0,
+            }
+        } else if r(0, 0) > r(1, 1) && r(0, 0) > r(2, 2) {
+            let s = (1.0 + r(0, 0) - r(1, 1) - r(2, 2)).sqrt() * 2.0;
+            Quat {
+                v: Vec3([s / 4.0, (r(0, 1) + r(1, 0)) / s, (r(0, 2) + r(2, 0)) / s]),
+                w: (r(2, 1) - r(1, 2)) / s,
+            }
+        } else if r(1, 1) > r(2, 2) {
+            let s = (1.0 + r(1, 1) - r(0, 0) - r(2, 2)).sqrt() * 2.0;
+            Quat {
+                v: Vec3([(r(0, 1) + r(1, 0)) / s, s / 4.0, (r(1, 2) + r(2, 1)) / s]),
+                w: (r(0, 2) - r(2, 0)) / s,
+            }
+        } else {
+            let s = (1.0 + r(2, 2) - r(0, 0) - r(1, 1)).sqrt() * 2.0;
+            Quat {
+                v: Vec3([(r(0, 2) + r(2, 0)) / s, (r(1, 2) + r(2, 1)) / s, s / 4.0]),
+                w: (r(1, 0) - r(0, 1)) / s,
+            }
+        }
+    }
+}
+
+// General tolerance for comparing values, e.g. the weights of a BSDF's components
 pub const EPS: f64 = 2e-7;
+// Minimum distance along a ray before a hit counts, so surfaces do not shadow or reflect themselves
+pub const RAY_BIAS: f64 = 2e-7;
+// Maximum deviation of the barycentric coordinates' sum from 1 for a point inside a triangle
+pub const HIT_TOLERANCE: f64 = 2e-7;
+// Maximum dot product of two camera axes which are considered orthogonal
+pub const ORTHO_TOLERANCE: f64 = 2e-7;
 
 pub trait AlmostEq {
-    fn almost_eq(self, rhs: Self) -> bool;
     fn almost_zero(self) -> bool;
+    fn almost_eq_within(self, rhs: Self, tolerance: f64) -> bool;
+    fn almost_zero_within(self, tolerance: f64) -> bool;
 }
 
 impl AlmostEq for f64 {
     #[inline(always)]
-    fn almost_eq(self, rhs: f64) -> bool {
-        (self - rhs).abs() < EPS
+    fn almost_zero(self) -> bool {
+        self.almost_zero_within(EPS)
     }
 
     #[inline(always)]
-    fn almost_zero(self) -> bool {
-        self.abs() < EPS
+    fn almost_eq_within(self, rhs: f64, tolerance: f64) -> bool {
+        (self - rhs).abs() < tolerance
+    }
+
+    #[inline(always)]
+    fn almost_zero_within(self, tolerance: f64) -> bool {
+        self.abs() < tolerance
     }
 }
 
@@ -373,3 +606,90 @@ pub struct Plane {
     pub c: f64,
     pub d: f64,
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::f64::consts::PI;
+
+    const X: Vec3 = Vec3([1.0, 0.0, 0.0]);
+    const Y: Vec3 = Vec3([0.0, 1.0, 0.0]);
+    const Z: Vec3 = Vec3([0.0, 0.0, 1.0]);
+
+    // Shared with the tests of the other modules
+    pub fn assert_vec3_eq(a: Vec3, b: Vec3, tolerance: f64) {
+        assert!((a - b).len().almost_zero_within(tolerance), "{:?} != {:?}", a, b);
+    }
+
+    // q and -q are the same rotation
+    fn assert_rotation_eq(a: Quat, b: Quat) {
+        assert!(a.dot(b).abs().almost_eq_within(1.0, EPS), "{:?} != {:?}", a, b);
+    }
+
+    fn rotate(q: Quat, v: Vec3) -> Vec3 {
+        (Mat4::from(q) * v.xyz0()).xyz()
+    }
+
+    #[test]
+    fn axis_angle_rotates_counter_clockwise() {
+        assert_vec3_eq(rotate(Quat::from_axis_angle(Z, PI / 2.0), X), Y, EPS);
+        assert_vec3_eq(rotate(Quat::from_axis_angle(X, PI / 2.0), Y), Z, EPS);
+        assert_vec3_eq(rotate(Quat::from_axis_angle(Y, PI / 2.0), Z), X, EPS);
+        assert_vec3_eq(rotate(Quat::from_axis_angle(Z, PI), X), -X, EPS);
+        assert_vec3_eq(rotate(Quat::identity(), X), X, EPS);
+    }
+
+    #[test]
+    fn mul_applies_rhs_first() {
+        let x = Quat::from_axis_angle(X, PI / 2.0);
+        let z = Quat::from_axis_angle(Z, PI / 2.0);
+        // Z turns to -Y around X, then to X around Z
+        assert_vec3_eq(rotate(z * x, Z), X, EPS);
+        // Z stays put around Z, then turns to -Y around X
+        assert_vec3_eq(rotate(x * z, Z), -Y, EPS);
+        let m = Mat4::from(z) * Mat4::from(x);
+        assert_vec3_eq(
+            rotate(z * x, Vec3([0.3, -0.5, 0.8])),
+            (m * Vec4([0.3, -0.5, 0.8, 0.0])).xyz(),
+            EPS,
+        );
+        assert_rotation_eq(z * Quat::from_axis_angle(Z, -PI / 2.0), Quat::identity());
+    }
+
+    #[test]
+    fn slerp_keeps_angular_velocity() {
+        let start = Quat::from_axis_angle(Z, 0.0);
+        let end = Quat::from_axis_angle(Z, PI / 2.0);
+        assert_rotation_eq(start.slerp(end, 0.0), start);
+        assert_rotation_eq(start.slerp(end, 1.0), end);
+        assert_rotation_eq(start.slerp(end, 0.5), Quat::from_axis_angle(Z, PI / 4.0));
+        assert_rotation_eq(start.slerp(end, 1.0 / 3.0), Quat::from_axis_angle(Z, PI / 6.0));
+        // -end is the same rotation, the shorter arc still goes through 45°
+        let negated = Quat { v: -end.v, w: -end.w };
+        assert_rotation_eq(start.slerp(negated, 0.5), Quat::from_axis_angle(Z, PI / 4.0));
+        // Between rotations around different axes
+        let x = Quat::from_axis_angle(X, PI / 2.0);
+        let halfway = x.slerp(end, 0.5);
+        assert!(halfway.dot(x).almost_eq_within(halfway.dot(end), EPS));
+        // Almost equal rotations take the linear path
+        let close = Quat::from_axis_angle(Z, 1e-9);
+        assert_rotation_eq(start.slerp(close, 0.5), Quat::from_axis_angle(Z, 5e-10));
+    }
+
+    #[test]
+    fn mat4_conversion_round_trips() {
+        let axis = Vec3([1.0, 2.0, -3.0]).normalize();
+        // The last three take the branches for a negative trace
+        let rotations = [
+            Quat::identity(),
+            Quat::from_axis_angle(axis, 0.7),
+            Quat::from_axis_angle(axis, -2.5),
+            Quat::from_axis_angle(X, PI),
+            Quat::from_axis_angle(Y, PI),
+            Quat::from_axis_angle(Z, PI),
+        ];
+        for &q in &rotations {
+            assert_rotation_eq(Quat::from(Mat4::from(q)), q);
+        }
+    }
+}
@@ -0,0 +1,401 @@
+use crate::math::{Vec3, Vec4};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
+use std::thread;
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+// Keyword of the tEXt chunk which marks tone mapped PNGs
+const TONE_MAPPING_KEYWORD: &[u8] = b"Tone mapping";
+
+// Sums up the samples of each pixel, just like the display buffer of the GUI.
+pub struct ImageBuffer {
+    w: usize,
+    h: usize,
+    pixels: Vec<Vec4>,
+    // The summed weights of the samples whose primary ray hit the scene
+    coverage: Vec<f64>,
+}
+
+impl ImageBuffer {
+    pub fn new(w: usize, h: usize) -> ImageBuffer {
+        ImageBuffer { w, h, pixels: vec![Vec4([0.0; 4]); w * h], coverage: vec![0.0; w * h] }
+    }
+
+    // Reads an image file back. Radiance HDR files hold linear colors. PNGs keep their bit depth
+    // and are decoded as their gAMA or sRGB chunk says, all other formats are taken to be sRGB
+    // encoded.
+    pub fn read(path: &str) -> Result<ImageBuffer, String> {
+        if path.ends_with(".exr") {
+            return Err(format!("Cannot read {}: EXR files are not supported, use .hdr", path));
+        }
+        if path.ends_with(".hdr") {
+            let file = File::open(path).map_err(|e| format!("Cannot open {}: {}", path, e))?;
+            let decoder = image::hdr::HDRDecoder::new(BufReader::new(file))
+                .map_err(|e| format!("Cannot read {}: {}", path, e))?;
+            let metadata = decoder.metadata();
+            let pixels = decoder
+                .read_image_hdr()
+                .map_err(|e| format!("Cannot read {}: {}", path, e))?
+                .iter()
+                .map(|p| Vec4([f64::from(p[0]), f64::from(p[1]), f64::from(p[2]), 1.0]))
+                .collect();
+            return Ok(ImageBuffer::from_pixels(
+                metadata.width as usize,
+                metadata.height as usize,
+                pixels,
+            ));
+        }
+
+        let bytes = fs::read(path).map_err(|e| format!("Cannot open {}: {}", path, e))?;
+        if bytes.starts_with(&PNG_SIGNATURE) {
+            return read_png(path, &bytes);
+        }
+        read_8_bit(path, &bytes, Vec4::srgb_to_linear)
+    }
+
+    // Saved images cover the whole frame
+    fn from_pixels(w: usize, h: usize, pixels: Vec<Vec4>) -> ImageBuffer {
+        ImageBuffer { w, h, pixels, coverage: vec![1.0; w * h] }
+    }
+
+    pub fn w(&self) -> usize {
+        self.w
+    }
+
+    pub fn h(&self) -> usize {
+        self.h
+    }
+
+    // The sample's color is weighted by its w component. hit tells whether its primary ray hit
+    // the scene.
+    pub fn add(&mut self, x: usize, y: usize, sample: Vec4, hit: bool) {
+        self.pixels[y * self.w + x] += sample;
+        if hit {
+            self.coverage[y * self.w + x] += sample.w();
+        }
+    }
+
+    // The summed samples and coverage of the pixel. Pixels without samples yet take the ones of
+    // the pixel rendered in their place during the progressive refinement, like in the GUI.
+    fn get(&self, x: usize, y: usize) -> (Vec4, f64) {
+        let mut power_of_two = 0;
+        loop {
+            let (sample_x, sample_y) =
+                ((x >> power_of_two) << power_of_two, (y >> power_of_two) << power_of_two);
+            let index = sample_y * self.w + sample_x;
+            if self.pixels[index].w() != 0.0 {
+                return (self.pixels[index], self.coverage[index]);
+            }
+            if sample_x == 0 && sample_y == 0 {
+                return (Vec4([0.0; 4]), 0.0);
+            }
+            power_of_two += 1;
+        }
+    }
+
+    // The colors of all pixels row by row. Without transparency, missed samples count as black
+    // and w is 1. With it, w is the fraction of samples which hit the scene and the color is the
+    // average of those samples alone, i.e. straight alpha.
+    pub fn colors(&self, transparent: bool) -> Vec<Vec4> {
+        let mut colors = Vec::with_capacity(self.w * self.h);
+        for y in 0..self.h {
+            for x in 0..self.w {
+                let (pixel, coverage) = self.get(x, y);
+                colors.push(if pixel.w() == 0.0 || (transparent && coverage == 0.0) {
+                    Vec4([0.0, 0.0, 0.0, if transparent { 0.0 } else { 1.0 }])
+                } else if transparent {
+                    let color = pixel.xyz() / coverage;
+                    Vec4([color.x(), color.y(), color.z(), coverage / pixel.w()])
+                } else {
+                    (pixel.xyz() / pixel.w()).xyz1()
+                });
+            }
+        }
+        colors
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct PngSettings {
+    // Exposure as used by the GUI, i.e. colors are multiplied by e^exposure
+    pub exposure: f64,
+    // Multiplies each channel before the exposure, to correct color casts
+    pub white_balance: Vec3,
+    // 8 or 16 bits per channel
+    pub bit_depth: u8,
+    // Write linear values instead of sRGB encoded ones
+    pub linear: bool,
+    // Apply the Reinhard curve of the GUI
+    pub tone_mapping: bool,
+    // Add an alpha channel which is 0 where the primary rays missed the scene
+    pub transparent: bool,
+}
+
+impl PngSettings {
+    fn bytes_per_pixel(&self) -> usize {
+        (if self.transparent { 4 } else { 3 }) * (self.bit_depth as usize / 8)
+    }
+}
+
+pub fn write_png(
+    image: &ImageBuffer,
+    path: &str,
+    settings: &PngSettings,
+    thread_count: usize,
+) -> Result<(), String> {
+    let data = encode(&image.colors(settings.transparent), settings, thread_count);
+
+    let file = File::create(path).map_err(|e| format!("Cannot create {}: {}", path, e))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), image.w as u32, image.h as u32);
+    encoder.set_color(if settings.transparent {
+        png::ColorType::RGBA
+    } else {
+        png::ColorType::RGB
+    });
+    encoder.set_depth(if settings.bit_depth == 16 {
+        png::BitDepth::Sixteen
+    } else {
+        png::BitDepth::Eight
+    });
+    let mut writer = encoder.write_header().map_err(|e| format!("Cannot write {}: {}", path, e))?;
+    if settings.linear {
+        // gAMA with a gamma of 1.0, scaled by 100000
+        writer.write_chunk(*b"gAMA", &100_000u32.to_be_bytes())
+    } else {
+        // sRGB with the perceptual rendering intent
+        writer.write_chunk(*b"sRGB", &[0])
+    }
+    .map_err(|e| format!("Cannot write {}: {}", path, e))?;
+    if settings.tone_mapping {
+        // The curve cannot be undone, so ImageBuffer::read refuses these images
+        let text = [TONE_MAPPING_KEYWORD, b"\0Reinhard"].concat();
+        writer.write_chunk(*b"tEXt", &text).map_err(|e| format!("Cannot write {}: {}", path, e))?;
+    }
+    writer.write_image_data(&data).map_err(|e| format!("Cannot write {}: {}", path, e))
+}
+
+// Reads a PNG of any bit depth into linear colors, ignoring its alpha channel
+fn read_png(path: &str, bytes: &[u8]) -> Result<ImageBuffer, String> {
+    // The png crate skips the chunks which describe the colors, so they are looked up here
+    let chunks = png_chunks(bytes);
+    let chunk = |kind: &[u8; 4]| chunks.iter().find(|chunk| chunk.0 == *kind).map(|chunk| chunk.1);
+    let tone_mapped = chunks.iter().any(|(kind, data)| {
+        kind == b"tEXt" && data.split(|b| *b == 0).next() == Some(TONE_MAPPING_KEYWORD)
+    });
+    if tone_mapped {
+        return Err(format!(
+            "Cannot read {}: the image is tone mapped, render it with --no-tone-mapping",
+            path
+        ));
+    }
+    // The sRGB chunk overrides gAMA, and images with neither are most likely sRGB as well
+    let gamma = match (chunk(b"sRGB"), chunk(b"gAMA")) {
+        (None, Some(&[a, b, c, d])) => {
+            Some(f64::from(u32::from_be_bytes([a, b, c, d])) / 100_000.0)
+        }
+        _ => None,
+    };
+    // sRGB is decoded with the exact curve write_png encodes with, not the approximation of
+    // Vec4::srgb_to_linear
+    let decode_channel = |c: f64| match gamma {
+        Some(gamma) if gamma > 0.0 => c.powf(1.0 / gamma),
+        _ if c <= 0.040_45 => c / 12.92,
+        _ => ((c + 0.055) / 1.055).powf(2.4),
+    };
+    let decode = |color: Vec4| {
+        Vec4([decode_channel(color.x()), decode_channel(color.y()), decode_channel(color.z()), 1.0])
+    };
+
+    let mut decoder = png::Decoder::new(bytes);
+    // By default, 16 bits are cut to 8
+    decoder.set_transformations(png::Transformations::IDENTITY);
+    let (info, mut reader) =
+        decoder.read_info().map_err(|e| format!("Cannot read {}: {}", path, e))?;
+    let channels = match info.color_type {
+        png::ColorType::Grayscale => 1,
+        png::ColorType::GrayscaleAlpha => 2,
+        png::ColorType::RGB => 3,
+        png::ColorType::RGBA => 4,
+        png::ColorType::Indexed => 0,
+    };
+    let bit_depth = info.bit_depth as usize;
+    if channels == 0 || bit_depth < 8 {
+        // Photon never writes these, so 8 bits per channel will do
+        return read_8_bit(path, bytes, decode);
+    }
+    let mut data = vec![0; info.buffer_size()];
+    reader.next_frame(&mut data).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+    // Channels are big-endian
+    let bytes_per_channel = bit_depth / 8;
+    let max = ((1u32 << bit_depth) - 1) as f64;
+    let pixels = data
+        .chunks(channels * bytes_per_channel)
+        .map(|pixel| {
+            let channel = |i: usize| {
+                let bytes = &pixel[i * bytes_per_channel..(i + 1) * bytes_per_channel];
+                f64::from(bytes.iter().fold(0u32, |value, byte| value << 8 | u32::from(*byte)))
+                    / max
+            };
+            // Gray is spread to all three colors
+            let (r, g, b) = if channels < 3 { (0, 0, 0) } else { (0, 1, 2) };
+            decode(Vec4([channel(r), channel(g), channel(b), 1.0]))
+        })
+        .collect();
+    Ok(ImageBuffer::from_pixels(info.width as usize, info.height as usize, pixels))
+}
+
+// Reads an image of any format with 8 bits per channel, turning the channels into linear colors
+// with decode and ignoring the alpha channel
+fn read_8_bit(
+    path: &str,
+    bytes: &[u8],
+    decode: impl Fn(Vec4) -> Vec4,
+) -> Result<ImageBuffer, String> {
+    let image = image::load_from_memory(bytes)
+        .map_err(|e| format!("Cannot read {}: {}", path, e))?
+        .to_rgba();
+    let (w, h) = image.dimensions();
+    let pixels = image
+        .pixels()
+        .map(|p| {
+            decode(Vec4([
+                f64::from(p[0]) / 255.0,
+                f64::from(p[1]) / 255.0,
+                f64::from(p[2]) / 255.0,
+                1.0,
+            ]))
+        })
+        .collect();
+    Ok(ImageBuffer::from_pixels(w as usize, h as usize, pixels))
+}
+
+// The type and data of the chunks of a PNG before its image data
+fn png_chunks(bytes: &[u8]) -> Vec<([u8; 4], &[u8])> {
+    let mut chunks = vec![];
+    let mut rest = &bytes[PNG_SIGNATURE.len()..];
+    // Length, type and checksum take 12 bytes besides the data
+    while rest.len() >= 12 {
+        let length = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let kind = [rest[4], rest[5], rest[6], rest[7]];
+        if kind == *b"IDAT" || rest.len() - 12 < length {
+            break;
+        }
+        chunks.push((kind, &rest[8..8 + length]));
+        rest = &rest[12 + length..];
+    }
+    chunks
+}
+
+// Applies exposure, tone mapping and the sRGB curve to the colors and quantizes them to the
+// big-endian channels of the PNG. The alpha channel, if any, is taken from w as is. Every pixel
+// is independent, so the colors are split into one chunk per thread.
+pub fn encode(colors: &[Vec4], settings: &PngSettings, thread_count: usize) -> Vec<u8> {
+    let chunk_size = ((colors.len() + thread_count - 1) / thread_count.max(1)).max(1);
+    let encode_threads: Vec<_> = colors
+        .chunks(chunk_size)
+        .map(|chunk| {
+            let chunk = chunk.to_vec();
+            let settings = *settings;
+            thread::spawn(move || encode_chunk(&chunk, &settings))
+        })
+        .collect();
+
+    let mut data = Vec::with_capacity(colors.len() * settings.bytes_per_pixel());
+    for encode_thread in encode_threads {
+        data.extend(encode_thread.join().unwrap());
+    }
+    data
+}
+
+fn encode_chunk(colors: &[Vec4], settings: &PngSettings) -> Vec<u8> {
+    let mut data = Vec::with_capacity(colors.len() * settings.bytes_per_pixel());
+    let exposure = settings.exposure.exp();
+    for color_alpha in colors {
+        let mut color = color_alpha.xyz() * settings.white_balance * exposure;
+        if settings.tone_mapping {
+            color = color / (1.0 + color.x().max(color.y()).max(color.z()));
+        }
+        if !settings.linear {
+            color = color.linear_to_srgb();
+        }
+        let alpha = if settings.transparent { Some(color_alpha.w()) } else { None };
+        for c in color.0.iter().chain(alpha.iter()) {
+            let c = c.clamp(0.0, 1.0);
+            if settings.bit_depth == 16 {
+                data.extend_from_slice(&((c * 65535.0).round() as u16).to_be_bytes());
+            } else {
+                data.push((c * 255.0).round() as u8);
+            }
+        }
+    }
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::AlmostEq;
+
+    fn settings() -> PngSettings {
+        PngSettings {
+            exposure: 0.0,
+            white_balance: Vec3([1.0; 3]),
+            bit_depth: 8,
+            linear: false,
+            tone_mapping: false,
+            transparent: false,
+        }
+    }
+
+    // Writes an image of the given colors in a row with write_png and reads it back
+    fn write_and_read(
+        name: &str,
+        colors: &[Vec3],
+        settings: &PngSettings,
+    ) -> Result<ImageBuffer, String> {
+        let mut image = ImageBuffer::new(colors.len(), 1);
+        for (x, color) in colors.iter().enumerate() {
+            image.add(x, 0, color.xyz1(), true);
+        }
+        let path = std::env::temp_dir().join(format!("photon-{}-{}.png", std::process::id(), name));
+        let path = path.to_str().unwrap();
+        write_png(&image, path, settings, 1).unwrap();
+        let result = ImageBuffer::read(path);
+        fs::remove_file(path).unwrap();
+        result
+    }
+
+    #[test]
+    fn written_pngs_are_read_back() {
+        let colors = [Vec3([0.2; 3]), Vec3([0.9, 0.5, 0.01]), Vec3([0.0, 1.0, 0.003])];
+        for &bit_depth in [8, 16].iter() {
+            for &linear in [false, true].iter() {
+                for &transparent in [false, true].iter() {
+                    let settings = PngSettings { bit_depth, linear, transparent, ..settings() };
+                    let name = format!("read-back-{}-{}-{}", bit_depth, linear, transparent);
+                    let image = write_and_read(&name, &colors, &settings).unwrap();
+                    // The sRGB curve stretches a step of the quantization by at most about 2
+                    let tolerance = 2.0 / f64::from((1u32 << bit_depth) - 1);
+                    for (color, expected) in image.colors(false).iter().zip(colors.iter()) {
+                        for i in 0..3 {
+                            assert!(
+                                color[i].almost_eq_within(expected[i], tolerance),
+                                "{:?} instead of {:?} with {:?}",
+                                color,
+                                expected,
+                                settings
+                            );
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tone_mapped_pngs_are_refused() {
+        let settings = PngSettings { tone_mapping: true, ..settings() };
+        let message = write_and_read("tone-mapped", &[Vec3([0.2; 3])], &settings).err().unwrap();
+        assert!(message.contains("--no-tone-mapping"), "{}", message);
+    }
+}
@@ -1,5 +1,4 @@
 use crate::math::Vec4;
-use image::GenericImageView;
 use std::fmt::{Debug, Formatter};
 
 pub struct Image {
@@ -9,10 +8,25 @@ pub struct Image {
 }
 
 impl Image {
-    pub fn from_path(path: &str) -> Result<Image, String> {
+    // Color textures are stored in sRGB, while data like metallic or roughness maps are linear.
+    // Images with more than max_pixels pixels are rejected before decoding them, so a broken
+    // header cannot make the decoder allocate gigabytes.
+    pub fn from_path(path: &str, srgb: bool, max_pixels: u64) -> Result<Image, String> {
+        let (w, h) = image::image_dimensions(path)
+            .map_err(|e| format!("Error while reading image {}: {}", path, e))?;
+        if u64::from(w) * u64::from(h) > max_pixels {
+            return Err(format!(
+                "Image {} is too large with {}x{} pixels, at most {} are allowed",
+                path, w, h, max_pixels
+            ));
+        }
+
+        // Grayscale, CMYK (converted while decoding JPEGs) and 16 bit images all end up as 8 bit
+        // RGBA here, so the channels below can be indexed unconditionally
         let image = image::open(path)
             .map_err(|e| format!("Error while reading image {}: {}", path, e))?
-            .flipv();
+            .flipv()
+            .to_rgba();
 
         let (w, h) = image.dimensions();
         let w = w as usize;
@@ -21,19 +35,26 @@ impl Image {
         for x in 0..w {
             for y in 0..h {
                 let p = image.get_pixel(x as u32, y as u32);
-                content[w * y + x] = Vec4([
+                let color = Vec4([
                     f64::from(p.0[0]) / 255.0,
                     f64::from(p.0[1]) / 255.0,
                     f64::from(p.0[2]) / 255.0,
                     f64::from(p.0[3]) / 255.0,
-                ])
-                .srgb_to_linear();
+                ]);
+                content[w * y + x] = if srgb { color.srgb_to_linear() } else { color };
             }
         }
 
         Ok(Image { w, h, content })
     }
 
+    // A magenta and black checkerboard standing in for textures which could not be loaded
+    pub fn missing() -> Image {
+        let magenta = Vec4([1.0, 0.0, 1.0, 1.0]);
+        let black = Vec4([0.0, 0.0, 0.0, 1.0]);
+        Image { w: 2, h: 2, content: vec![magenta, black, black, magenta] }
+    }
+
     pub fn w(&self) -> usize {
         self.w
     }
@@ -52,3 +73,122 @@ impl Debug for Image {
         write!(f, "Image {{ w: {}, h: {}, .. }}", self.w, self.h)
     }
 }
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::math::AlmostEq;
+
+    // A path for the file in the temporary directory, unique to this test process
+    fn temp_path(file_name: &str) -> String {
+        let path =
+            std::env::temp_dir().join(format!("photon-{}-{}", std::process::id(), file_name));
+        path.to_str().unwrap().to_owned()
+    }
+
+    // Writes the image as a PNG into the temporary directory and returns its path
+    pub fn write_png<P, C>(name: &str, image: &image::ImageBuffer<P, C>) -> String
+    where
+        P: image::Pixel<Subpixel = u8> + 'static,
+        C: std::ops::Deref<Target = [u8]>,
+    {
+        let path = temp_path(&format!("{}.png", name));
+        image.save(&path).unwrap();
+        path
+    }
+
+    fn assert_color(color: Vec4, expected: Vec4) {
+        assert!((color - expected).0.iter().all(|c| c.almost_zero()), "{:?}", color);
+    }
+
+    #[test]
+    fn grayscale_becomes_rgba() {
+        // Two rows, the top one white and the bottom one black
+        let gray = image::GrayImage::from_raw(1, 2, vec![255, 0]).unwrap();
+        let path = write_png("gray", &gray);
+        let image = Image::from_path(&path, false, u64::MAX).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!((image.w(), image.h()), (1, 2));
+        // Rows are flipped, so y grows upwards like texture coordinates
+        assert_color(image.get(0, 0), Vec4([0.0, 0.0, 0.0, 1.0]));
+        assert_color(image.get(0, 1), Vec4([1.0, 1.0, 1.0, 1.0]));
+    }
+
+    #[test]
+    fn grayscale_jpeg_becomes_rgba() {
+        // Two blocks of 8x8 pixels, each of a single gray, so the compression barely changes them
+        let mut gray = vec![204; 64];
+        gray.extend(vec![51; 64]);
+        let mut jpeg = vec![];
+        image::jpeg::JPEGEncoder::new_with_quality(&mut jpeg, 100)
+            .encode(&gray, 8, 16, image::ColorType::Gray(8))
+            .unwrap();
+        let path = temp_path("gray.jpg");
+        std::fs::write(&path, jpeg).unwrap();
+        let image = Image::from_path(&path, false, u64::MAX);
+        std::fs::remove_file(&path).unwrap();
+        let image = image.unwrap();
+        assert_eq!((image.w(), image.h()), (8, 16));
+        for &(y, expected) in &[(0, 0.2), (15, 0.8)] {
+            let color = image.get(3, y);
+            let expected = Vec4([expected, expected, expected, 1.0]);
+            assert!((color - expected).0.iter().all(|c| c.abs() <= 2.0 / 255.0), "{:?}", color);
+        }
+    }
+
+    #[test]
+    fn grayscale_alpha_keeps_alpha() {
+        let gray_alpha = image::GrayAlphaImage::from_raw(1, 1, vec![51, 102]).unwrap();
+        let path = write_png("gray-alpha", &gray_alpha);
+        let image = Image::from_path(&path, false, u64::MAX).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_color(image.get(0, 0), Vec4([0.2, 0.2, 0.2, 0.4]));
+    }
+
+    #[test]
+    fn max_pixels_limits_size() {
+        let rgb = image::RgbImage::from_raw(3, 2, vec![0; 18]).unwrap();
+        let path = write_png("max-pixels", &rgb);
+        let at_limit = Image::from_path(&path, false, 6);
+        let above_limit = Image::from_path(&path, false, 5);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(at_limit.map(|image| (image.w(), image.h())), Ok((3, 2)));
+        let message = above_limit.unwrap_err();
+        assert!(message.contains("3x2") && message.contains("at most 5"), "{}", message);
+    }
+
+    #[test]
+    fn max_pixels_is_checked_before_decoding() {
+        // The header claims 100000x100000 pixels, but the image data is empty
+        let mut header = vec![];
+        header.extend(&100_000u32.to_be_bytes());
+        header.extend(&100_000u32.to_be_bytes());
+        header.extend(&[8, 6, 0, 0, 0]);
+        let mut png = vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+        png.extend(chunk(b"IHDR", &header));
+        png.extend(chunk(b"IDAT", &[]));
+        png.extend(chunk(b"IEND", &[]));
+        let path = temp_path("huge.png");
+        std::fs::write(&path, png).unwrap();
+        let result = Image::from_path(&path, false, 1 << 24);
+        std::fs::remove_file(&path).unwrap();
+        let message = result.unwrap_err();
+        assert!(message.contains("too large with 100000x100000 pixels"), "{}", message);
+    }
+
+    // A PNG chunk with its length and checksum
+    fn chunk(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_be_bytes().to_vec();
+        chunk.extend(kind);
+        chunk.extend(data);
+        let mut crc = !0u32;
+        for byte in &chunk[4..] {
+            crc ^= u32::from(*byte);
+            for _ in 0..8 {
+                crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+            }
+        }
+        chunk.extend(&(!crc).to_be_bytes());
+        chunk
+    }
+}
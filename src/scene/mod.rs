@@ -1,7 +1,13 @@
 mod image;
 mod nodes;
+#[allow(clippy::module_inception)]
 mod scene;
 
 pub use self::image::Image;
-pub use nodes::{bsdf_principled, output_material, tex_image, Bsdf, Graph, Link, LinkType, Node};
-pub use scene::{Camera, Geometry, PointLight, Scene, Triangle, Vertex};
+pub use nodes::{
+    bsdf_principled, mix_shader, output_material, separate_rgb, tex_coord, tex_image, Bsdf, Graph,
+    GraphBuilder, Link, LinkType,
+};
+pub use scene::{
+    Camera, FovAxis, Geometry, Instance, Material, PointLight, Projection, Scene, Triangle, Vertex,
+};
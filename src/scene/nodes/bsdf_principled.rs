@@ -11,7 +11,11 @@ pub struct Node {
     pub base_color: Link<Vec4>,
     // Stored in 1/0.08ths
     pub specular: Link<f64>,
+    pub specular_tint: Link<f64>,
     pub metallic: Link<f64>,
+    pub emission: Link<Vec4>,
+    // Multiplies the emission color, so emission can exceed 1
+    pub emission_strength: Link<f64>,
 }
 
 impl graph::Node for Node {
@@ -19,8 +23,11 @@ impl graph::Node for Node {
         let bsdf = Bsdf {
             color: ctx.evaluate_link(self.base_color).xyz(),
             specular: ctx.evaluate_link(self.specular) * 0.08,
+            specular_tint: ctx.evaluate_link(self.specular_tint),
             metallic: ctx.evaluate_link(self.metallic),
+            emission: ctx.evaluate_link(self.emission).xyz()
+                * ctx.evaluate_link(self.emission_strength),
         };
-        return vec![bsdf.to_output()];
+        vec![bsdf.to_output()]
     }
 }
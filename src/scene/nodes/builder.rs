@@ -0,0 +1,166 @@
+use super::graph::{Bsdf, Graph, Link, LinkType, Node};
+use super::{bsdf_principled, mix_shader, output_material, separate_rgb, tex_coord, tex_image};
+use crate::math::Vec4;
+use crate::scene::Material;
+use std::marker::PhantomData;
+
+// Assembles a graph from nodes without handling their indices. Adding a node returns a handle
+// whose output sockets carry the type of their value, so an output can only be linked to an
+// input of the same type.
+pub struct GraphBuilder {
+    graph: Graph,
+}
+
+// A node added to a GraphBuilder
+#[derive(Debug)]
+pub struct NodeHandle<N> {
+    index: usize,
+    node: PhantomData<N>,
+}
+
+// Deriving these would require N itself to be Copy
+impl<N> Clone for NodeHandle<N> {
+    fn clone(&self) -> NodeHandle<N> {
+        *self
+    }
+}
+
+impl<N> Copy for NodeHandle<N> {}
+
+// An output socket of a node added to a GraphBuilder, producing a T
+#[derive(Debug)]
+pub struct Socket<T: LinkType> {
+    node: usize,
+    socket: usize,
+    value: PhantomData<T>,
+}
+
+impl<T: LinkType> Clone for Socket<T> {
+    fn clone(&self) -> Socket<T> {
+        *self
+    }
+}
+
+impl<T: LinkType> Copy for Socket<T> {}
+
+impl<T: LinkType> Socket<T> {
+    pub fn link(self) -> Link<T> {
+        Link::Node(self.node, self.socket)
+    }
+}
+
+impl GraphBuilder {
+    pub fn new() -> GraphBuilder {
+        GraphBuilder { graph: Graph::new() }
+    }
+
+    pub fn add<N: Node + 'static>(&mut self, node: N) -> NodeHandle<N> {
+        NodeHandle { index: self.graph.add_node(Box::new(node)), node: PhantomData }
+    }
+
+    // Adds an output node shading the surface with the given BSDF and turns the graph into a
+    // material
+    pub fn material(mut self, surface: Socket<Bsdf>, double_sided: bool) -> Material {
+        let output = self.add(output_material::Node { surface: surface.link() }).index;
+        Material { output, graph: self.graph, double_sided }
+    }
+}
+
+impl<N> NodeHandle<N> {
+    // Untyped by the node, for nodes without accessors such as the wrappers of tests
+    pub(super) fn socket<T: LinkType>(self, socket: usize) -> Socket<T> {
+        Socket { node: self.index, socket, value: PhantomData }
+    }
+}
+
+impl NodeHandle<bsdf_principled::Node> {
+    pub fn bsdf(self) -> Socket<Bsdf> {
+        self.socket(bsdf_principled::outputs::BSDF)
+    }
+}
+
+#[allow(dead_code)]
+impl NodeHandle<mix_shader::Node> {
+    pub fn shader(self) -> Socket<Bsdf> {
+        self.socket(mix_shader::outputs::SHADER)
+    }
+}
+
+// Only some of the sockets are linked by the importers so far
+#[allow(dead_code)]
+impl NodeHandle<tex_image::Node> {
+    pub fn color(self) -> Socket<Vec4> {
+        self.socket(tex_image::outputs::COLOR)
+    }
+
+    pub fn alpha(self) -> Socket<f64> {
+        self.socket(tex_image::outputs::ALPHA)
+    }
+}
+
+#[allow(dead_code)]
+impl NodeHandle<separate_rgb::Node> {
+    pub fn r(self) -> Socket<f64> {
+        self.socket(separate_rgb::outputs::R)
+    }
+
+    pub fn g(self) -> Socket<f64> {
+        self.socket(separate_rgb::outputs::G)
+    }
+
+    pub fn b(self) -> Socket<f64> {
+        self.socket(separate_rgb::outputs::B)
+    }
+}
+
+#[allow(dead_code)]
+impl NodeHandle<tex_coord::Node> {
+    pub fn generated(self) -> Socket<Vec4> {
+        self.socket(tex_coord::outputs::GENERATED)
+    }
+
+    pub fn uv(self) -> Socket<Vec4> {
+        self.socket(tex_coord::outputs::UV)
+    }
+
+    pub fn object(self) -> Socket<Vec4> {
+        self.socket(tex_coord::outputs::OBJECT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tex_image::tests::scene;
+    use super::*;
+    use crate::math::{Vec2, Vec3};
+    use crate::scene::{Image, Link};
+
+    #[test]
+    fn sockets_link_the_outputs_they_are_named_after() {
+        // Every input of the BSDF reads a different output, so a socket with the wrong index
+        // shows up as a swapped value
+        let mut graph = GraphBuilder::new();
+        let coords = graph.add(tex_coord::Node { aabb: (Vec3([0.0; 3]), Vec3([4.0; 3])) });
+        let split = graph.add(separate_rgb::Node { image: coords.uv().link() });
+        let bsdf = graph.add(bsdf_principled::Node {
+            base_color: coords.generated().link(),
+            specular: split.r().link(),
+            specular_tint: split.g().link(),
+            metallic: split.b().link(),
+            emission: coords.object().link(),
+            emission_strength: Link::Constant(1.0),
+        });
+        let material = graph.material(bsdf.bsdf(), true);
+        assert!(material.double_sided);
+
+        let scene = scene(Image::missing());
+        let zero = Vec3([0.0; 3]);
+        let object = Vec3([1.0, 2.0, 3.0]);
+        let mut ctx = material.graph.new_context(&scene, Vec2([0.25, 0.5]), object, zero, zero);
+        let bsdf: Bsdf =
+            ctx.evaluate_link(Link::Node(material.output, output_material::outputs::SURFACE));
+        assert_eq!(bsdf.color, Vec3([0.25, 0.5, 0.75]));
+        assert_eq!((bsdf.specular, bsdf.specular_tint, bsdf.metallic), (0.25 * 0.08, 0.5, 0.0));
+        assert_eq!(bsdf.emission, object);
+    }
+}
@@ -6,7 +6,11 @@ use std::fmt::Debug;
 pub struct Bsdf {
     pub color: Vec3,
     pub specular: f64,
+    // How much the specular reflection takes on the hue of the base color
+    pub specular_tint: f64,
     pub metallic: f64,
+    // Light given off by the surface itself
+    pub emission: Vec3,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -25,6 +29,8 @@ impl LinkType for f64 {
     fn from_output(o: Output) -> f64 {
         match o {
             Output::F64(v) => v,
+            // Blender implicitly converts colors plugged into value sockets to their luminance
+            Output::Vec4(v) => 0.2126 * v.x() + 0.7152 * v.y() + 0.0722 * v.z(),
             _ => panic!("Type error in graph"),
         }
     }
@@ -68,6 +74,9 @@ pub enum Link<T: LinkType> {
 
 pub struct EvaluationContext<'a> {
     tex_coord: Vec2,
+    object_position: Vec3,
+    tangent: Vec3,
+    bitangent: Vec3,
     graph: &'a Graph,
     scene: &'a Scene,
     node_results: Vec<Option<Vec<Output>>>,
@@ -90,6 +99,23 @@ impl<'a> EvaluationContext<'a> {
         self.tex_coord
     }
 
+    // The shaded point in the coordinate system of the object it belongs to
+    pub fn object_position(&self) -> Vec3 {
+        self.object_position
+    }
+
+    // Unit directions of growing u and v texture coordinates, perpendicular to the shading normal.
+    // For nodes that bend the normal, none of which exist yet.
+    #[allow(dead_code)]
+    pub fn tangent(&self) -> Vec3 {
+        self.tangent
+    }
+
+    #[allow(dead_code)]
+    pub fn bitangent(&self) -> Vec3 {
+        self.bitangent
+    }
+
     pub fn scene(&self) -> &Scene {
         self.scene
     }
@@ -97,6 +123,11 @@ impl<'a> EvaluationContext<'a> {
 
 pub trait Node: Debug + Sync + Send {
     fn evaluate(&self, ctx: &mut EvaluationContext) -> Vec<Output>;
+
+    // The index of the scene image the node reads from, if any
+    fn image_mut(&mut self) -> Option<&mut usize> {
+        None
+    }
 }
 
 #[derive(Debug)]
@@ -109,16 +140,31 @@ impl Graph {
         Graph { nodes: vec![] }
     }
 
+    // The indices of all images used by the nodes of the graph
+    pub fn images_mut(&mut self) -> impl Iterator<Item = &mut usize> {
+        self.nodes.iter_mut().filter_map(|node| node.image_mut())
+    }
+
     pub fn add_node(&mut self, node: Box<dyn Node>) -> usize {
         self.nodes.push(node);
         self.nodes.len() - 1
     }
 
-    pub fn new_context<'a>(&'a self, scene: &'a Scene, tex_coord: Vec2) -> EvaluationContext<'a> {
+    pub fn new_context<'a>(
+        &'a self,
+        scene: &'a Scene,
+        tex_coord: Vec2,
+        object_position: Vec3,
+        tangent: Vec3,
+        bitangent: Vec3,
+    ) -> EvaluationContext<'a> {
         EvaluationContext {
             tex_coord,
+            object_position,
+            tangent,
+            bitangent,
             scene,
-            graph: &self,
+            graph: self,
             node_results: vec![None; self.nodes.len()],
         }
     }
@@ -0,0 +1,107 @@
+use super::graph;
+use super::graph::{Bsdf, EvaluationContext, Link, LinkType, Output};
+
+pub mod outputs {
+    pub const SHADER: usize = 0;
+}
+
+// Blends two BSDFs, fac 0 giving the first and 1 the second. Blender mixes the light reflected by
+// each shader, while this mixes their parameters. Both agree for the emission and for shaders
+// which only differ in color.
+#[derive(Debug)]
+pub struct Node {
+    pub fac: Link<f64>,
+    pub shader1: Link<Bsdf>,
+    pub shader2: Link<Bsdf>,
+}
+
+impl graph::Node for Node {
+    fn evaluate(&self, ctx: &mut EvaluationContext) -> Vec<Output> {
+        let fac = ctx.evaluate_link(self.fac).clamp(0.0, 1.0);
+        let a = ctx.evaluate_link(self.shader1);
+        let b = ctx.evaluate_link(self.shader2);
+        let mix = |a: f64, b: f64| a * (1.0 - fac) + b * fac;
+        let bsdf = Bsdf {
+            color: a.color * (1.0 - fac) + b.color * fac,
+            specular: mix(a.specular, b.specular),
+            specular_tint: mix(a.specular_tint, b.specular_tint),
+            metallic: mix(a.metallic, b.metallic),
+            emission: a.emission * (1.0 - fac) + b.emission * fac,
+        };
+        vec![bsdf.to_output()]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::builder::GraphBuilder;
+    use super::super::tex_image::tests::scene;
+    use super::super::{bsdf_principled, output_material, separate_rgb, tex_image};
+    use super::*;
+    use crate::math::{Vec2, Vec3, Vec4};
+    use crate::scene::Image;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // Counts how often the wrapped node is evaluated
+    #[derive(Debug)]
+    struct Counted<N> {
+        node: N,
+        count: Arc<AtomicUsize>,
+    }
+
+    impl<N: graph::Node> graph::Node for Counted<N> {
+        fn evaluate(&self, ctx: &mut EvaluationContext) -> Vec<Output> {
+            self.count.fetch_add(1, Ordering::SeqCst);
+            self.node.evaluate(ctx)
+        }
+    }
+
+    fn principled(base_color: Link<Vec4>, metallic: f64) -> bsdf_principled::Node {
+        bsdf_principled::Node {
+            base_color,
+            specular: Link::Constant(0.5),
+            specular_tint: Link::Constant(0.0),
+            metallic: Link::Constant(metallic),
+            emission: Link::Constant(Vec4([0.0, 0.0, 0.0, 1.0])),
+            emission_strength: Link::Constant(1.0),
+        }
+    }
+
+    #[test]
+    fn texture_feeding_mask_and_color_is_evaluated_once() {
+        // The checkerboard of magenta and black texels is the mask through its red channel and
+        // the color of the first shader
+        let scene = scene(Image::missing());
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut graph = GraphBuilder::new();
+        let texture = graph.add(Counted {
+            node: tex_image::Node { image: 0, vector: None },
+            count: Arc::clone(&count),
+        });
+        let color = texture.socket(tex_image::outputs::COLOR).link();
+        let mask = graph.add(separate_rgb::Node { image: color });
+        let paint = graph.add(principled(color, 0.0));
+        let metal = graph.add(principled(Link::Constant(Vec4([1.0; 4])), 1.0));
+        let mix = graph.add(Node {
+            fac: mask.r().link(),
+            shader1: paint.bsdf().link(),
+            shader2: metal.bsdf().link(),
+        });
+        let material = graph.material(mix.shader(), false);
+
+        let zero = Vec3([0.0; 3]);
+        let evaluate = |u: f64, v: f64| -> Bsdf {
+            let mut ctx = material.graph.new_context(&scene, Vec2([u, v]), zero, zero, zero);
+            ctx.evaluate_link(Link::Node(material.output, output_material::outputs::SURFACE))
+        };
+        // At the center of a magenta texel, the mask selects the metal
+        let bsdf = evaluate(0.25, 0.25);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert_eq!((bsdf.color, bsdf.metallic), (Vec3([1.0; 3]), 1.0));
+        // At the center of a black texel, it selects the paint in the color of the texel
+        let bsdf = evaluate(0.75, 0.25);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        assert_eq!((bsdf.color, bsdf.metallic), (Vec3([0.0; 3]), 0.0));
+    }
+}
@@ -1,7 +1,12 @@
+mod builder;
 mod graph;
 
 pub mod bsdf_principled;
+pub mod mix_shader;
 pub mod output_material;
+pub mod separate_rgb;
+pub mod tex_coord;
 pub mod tex_image;
 
-pub use graph::{Bsdf, Graph, Link, LinkType, Node};
+pub use builder::GraphBuilder;
+pub use graph::{Bsdf, Graph, Link, LinkType};
@@ -12,6 +12,6 @@ pub struct Node {
 
 impl graph::Node for Node {
     fn evaluate(&self, ctx: &mut EvaluationContext) -> Vec<Output> {
-        return vec![ctx.evaluate_link(self.surface).to_output()];
+        vec![ctx.evaluate_link(self.surface).to_output()]
     }
 }
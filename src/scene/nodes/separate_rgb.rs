@@ -0,0 +1,21 @@
+use super::graph;
+use super::graph::{EvaluationContext, Link, LinkType, Output};
+use crate::math::Vec4;
+
+pub mod outputs {
+    pub const R: usize = 0;
+    pub const G: usize = 1;
+    pub const B: usize = 2;
+}
+
+#[derive(Debug)]
+pub struct Node {
+    pub image: Link<Vec4>,
+}
+
+impl graph::Node for Node {
+    fn evaluate(&self, ctx: &mut EvaluationContext) -> Vec<Output> {
+        let image = ctx.evaluate_link(self.image);
+        vec![image.x().to_output(), image.y().to_output(), image.z().to_output()]
+    }
+}
@@ -0,0 +1,36 @@
+use super::graph;
+use super::graph::{EvaluationContext, LinkType, Output};
+use crate::math::Vec3;
+
+pub mod outputs {
+    pub const GENERATED: usize = 0;
+    pub const UV: usize = 1;
+    pub const OBJECT: usize = 2;
+}
+
+#[derive(Debug)]
+pub struct Node {
+    // The AABB of the mesh in object space, which is mapped to [0, 1] for the generated coordinates
+    pub aabb: (Vec3, Vec3),
+}
+
+impl graph::Node for Node {
+    fn evaluate(&self, ctx: &mut EvaluationContext) -> Vec<Output> {
+        let object = ctx.object_position();
+        let (aabb_min, aabb_max) = self.aabb;
+        let mut generated = Vec3([0.0; 3]);
+        for i in 0..3 {
+            let size = aabb_max[i] - aabb_min[i];
+            // Flat meshes have no extent along one axis
+            if size > 0.0 {
+                generated[i] = (object[i] - aabb_min[i]) / size;
+            }
+        }
+        let uv = ctx.tex_coord();
+        vec![
+            generated.xyz1().to_output(),
+            Vec3([uv.x(), uv.y(), 0.0]).xyz1().to_output(),
+            object.xyz1().to_output(),
+        ]
+    }
+}
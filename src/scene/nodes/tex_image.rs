@@ -1,5 +1,6 @@
 use super::graph;
-use super::graph::{EvaluationContext, LinkType, Output};
+use super::graph::{EvaluationContext, Link, LinkType, Output};
+use crate::math::{Vec2, Vec4};
 
 pub mod outputs {
     pub const COLOR: usize = 0;
@@ -9,14 +10,24 @@ pub mod outputs {
 #[derive(Debug)]
 pub struct Node {
     pub image: usize,
+    // The texture coordinates to look up, the UV coordinates of the surface if None
+    pub vector: Option<Link<Vec4>>,
 }
 
 impl graph::Node for Node {
     fn evaluate(&self, ctx: &mut EvaluationContext) -> Vec<Output> {
+        let tex_coord = match self.vector {
+            Some(vector) => {
+                let vector = ctx.evaluate_link(vector);
+                Vec2([vector.x(), vector.y()])
+            }
+            None => ctx.tex_coord(),
+        };
+
         let image = &ctx.scene().images[self.image];
-        let tex_coord = ctx.tex_coord();
 
-        // Bilinear interpolation between pixel centers
+        // Bilinear interpolation between pixel centers. sRGB images are converted to linear when
+        // they are loaded, so this blends in linear space.
         let ideal_x = tex_coord.x() * image.w() as f64;
         let ideal_y = tex_coord.y() * image.h() as f64;
 
@@ -42,18 +53,127 @@ impl graph::Node for Node {
 
         let p1234 = p34 * (ideal_y - floor05(ideal_y)) + p12 * (floor05(ideal_y) + 1.0 - ideal_y);
 
-        return vec![p1234.to_output(), p1234.w().to_output()];
+        vec![p1234.to_output(), p1234.w().to_output()]
+    }
+
+    fn image_mut(&mut self) -> Option<&mut usize> {
+        Some(&mut self.image)
     }
 }
 
+// Wraps num into [0, mod_by), also for negative multiples of mod_by
 fn real_mod(num: isize, mod_by: isize) -> usize {
-    if num >= 0 {
-        (num % mod_by) as usize
-    } else {
-        (-(-num % mod_by) + mod_by) as usize
-    }
+    num.rem_euclid(mod_by) as usize
 }
 
+// The largest pixel center at or below num. Rounding towards zero instead would give a center
+// above num for num < 0.5, whose interpolation weights then leave [0, 1].
 fn floor05(num: f64) -> f64 {
-    (num - 0.5).trunc() + 0.5
+    (num - 0.5).floor() + 0.5
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+    use crate::math::{AlmostEq, Vec3};
+    use crate::scene::image::tests::write_png;
+    use crate::scene::{Camera, FovAxis, Graph, Image, Projection, Scene};
+
+    // A scene with nothing but the image, which is all the node looks at
+    pub fn scene(image: Image) -> Scene {
+        Scene {
+            camera: Camera::new(
+                Vec3([0.0, 0.0, 0.0]),
+                Vec3([1.0, 0.0, 0.0]),
+                Vec3([0.0, 0.0, 1.0]),
+                Vec3([0.0, 1.0, 0.0]),
+                Projection {
+                    fov: 1.0,
+                    fov_axis: FovAxis::Horizontal,
+                    znear: 0.1,
+                    zfar: 100.0,
+                    pixel_aspect: 1.0,
+                },
+                1,
+                1,
+            ),
+            triangles: vec![],
+            meshes: vec![],
+            instances: vec![],
+            point_lights: vec![],
+            light_names: vec![],
+            materials: vec![],
+            images: vec![image],
+        }
+    }
+
+    fn sample(scene: &Scene, u: f64, v: f64) -> Vec4 {
+        let mut graph = Graph::new();
+        let node = graph.add_node(Box::new(Node { image: 0, vector: None }));
+        let zero = Vec3([0.0; 3]);
+        let mut ctx = graph.new_context(scene, Vec2([u, v]), zero, zero, zero);
+        ctx.evaluate_link(Link::Node(node, outputs::COLOR))
+    }
+
+    #[test]
+    fn srgb_texels_blend_in_linear_space() {
+        let edge = image::RgbImage::from_raw(2, 1, vec![128, 128, 128, 255, 255, 255]).unwrap();
+        let path = write_png("edge", &edge);
+        let scene = scene(Image::from_path(&path, true, u64::MAX).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        let gray = (128.0f64 / 255.0).powf(2.2);
+        // Halfway and a quarter of the way between the pixel centers at u = 0.25 and u = 0.75
+        for &(u, t) in &[(0.5, 0.5), (0.375, 0.25)] {
+            let color = sample(&scene, u, 0.5);
+            let linear_mix = gray * (1.0 - t) + t;
+            let srgb_mix = ((128.0 / 255.0) * (1.0 - t) + t).powf(2.2);
+            assert!(color.x().almost_eq_within(linear_mix, 1e-9), "{:?} at u = {}", color, u);
+            assert!(!color.x().almost_eq_within(srgb_mix, 0.01), "{:?} at u = {}", color, u);
+            assert!(color.w().almost_eq_within(1.0, 1e-9));
+        }
+    }
+
+    #[test]
+    fn real_mod_wraps_negative_numbers() {
+        assert_eq!(real_mod(5, 4), 1);
+        assert_eq!(real_mod(-4, 4), 0);
+        assert_eq!(real_mod(-5, 4), 3);
+        assert_eq!(real_mod(-1, 1), 0);
+        assert!(real_mod(isize::MIN + 1, 7) < 7);
+        assert!(real_mod(isize::MIN, 7) < 7);
+    }
+
+    #[test]
+    fn floor05_finds_pixel_center_below() {
+        assert_eq!(floor05(0.5), 0.5);
+        assert_eq!(floor05(1.2), 0.5);
+        assert_eq!(floor05(1.5), 1.5);
+        assert_eq!(floor05(0.3), -0.5);
+        assert_eq!(floor05(-0.2), -0.5);
+        assert_eq!(floor05(-0.5), -0.5);
+        assert_eq!(floor05(-0.6), -1.5);
+    }
+
+    #[test]
+    fn texture_wraps_around() {
+        // Columns of 0, 0.5 and 1 in a linear image
+        let ramp = image::GrayImage::from_raw(3, 1, vec![0, 128, 255]).unwrap();
+        let path = write_png("ramp", &ramp);
+        let scene = scene(Image::from_path(&path, false, u64::MAX).unwrap());
+        std::fs::remove_file(&path).unwrap();
+        let first = sample(&scene, 1.0 / 6.0, 0.5);
+        // A whole number of repetitions away, also below 0
+        for &offset in &[-3.0, -1.0, 1.0, 2.0] {
+            let color = sample(&scene, 1.0 / 6.0 + offset, 0.5);
+            assert!(
+                color.x().almost_eq_within(first.x(), 1e-9),
+                "{:?} at offset {}",
+                color,
+                offset
+            );
+        }
+        // Left of the first pixel center it blends towards the last column
+        let color = sample(&scene, 0.0, 0.5);
+        assert!(color.x().almost_eq_within(0.5, 1e-9), "{:?}", color);
+    }
 }
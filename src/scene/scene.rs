@@ -1,25 +1,208 @@
 use super::image::Image;
-use super::nodes::{output_material, Bsdf, Graph, Link};
-use crate::math::{HasAABB, Plane, Vec2, Vec3};
+use super::nodes::{bsdf_principled, output_material, Bsdf, Graph, GraphBuilder, Link};
+use crate::math::{AlmostEq, HasAABB, Mat4, Plane, Vec2, Vec3, Vec4, EPS, HIT_TOLERANCE};
+use std::collections::HashSet;
 
 #[derive(Debug)]
 pub struct Scene {
     pub camera: Camera,
     pub triangles: Vec<Triangle>,
+    // Triangles of meshes used more than once, in their local coordinate system
+    pub meshes: Vec<Vec<Triangle>>,
+    pub instances: Vec<Instance>,
     pub point_lights: Vec<PointLight>,
-    pub materials: Vec<(usize, Graph)>,
+    // The name of the light with the same index, so lights can be adjusted from the command line
+    pub light_names: Vec<String>,
+    pub materials: Vec<Material>,
     pub images: Vec<Image>,
 }
 
+// The size of a scene at a glance, e.g. to sanity-check an import
+#[derive(Debug, Copy, Clone)]
+pub struct SceneSummary {
+    // Triangles as rendered, i.e. those of a mesh count once per instance
+    pub triangles: usize,
+    pub lights: usize,
+    pub materials: usize,
+    pub images: usize,
+    // Bounds of all triangles in world space, None without triangles
+    pub aabb: Option<(Vec3, Vec3)>,
+}
+
 impl Scene {
-    pub fn evaluate_material(&self, triangle: &Triangle, tex_coord: Vec2) -> Bsdf {
-        let (output_index, material) = &self.materials[triangle.material];
-        let mut ctx = material.new_context(self, tex_coord);
-        ctx.evaluate_link(Link::Node(*output_index, output_material::outputs::SURFACE))
+    pub fn summary(&self) -> SceneSummary {
+        let mut aabb = triangles_aabb(&self.triangles);
+        for instance in &self.instances {
+            if let Some((local_min, local_max)) = triangles_aabb(&self.meshes[instance.mesh]) {
+                for corner in 0..8 {
+                    let local_corner = Vec3([
+                        if corner & 1 == 0 { local_min.x() } else { local_max.x() },
+                        if corner & 2 == 0 { local_min.y() } else { local_max.y() },
+                        if corner & 4 == 0 { local_min.z() } else { local_max.z() },
+                    ]);
+                    let world_corner = (instance.transform * local_corner.xyz1()).xyz();
+                    aabb = Some(match aabb {
+                        Some((min, max)) => (min.min(world_corner), max.max(world_corner)),
+                        None => (world_corner, world_corner),
+                    });
+                }
+            }
+        }
+        SceneSummary {
+            triangles: self.triangles.len()
+                + self
+                    .instances
+                    .iter()
+                    .map(|instance| self.meshes[instance.mesh].len())
+                    .sum::<usize>(),
+            lights: self.point_lights.len(),
+            materials: self.materials.len(),
+            images: self.images.len(),
+            aabb,
+        }
+    }
+
+    // barycentric holds the weights of the triangle's vertices at the shaded point
+    pub fn evaluate_material(
+        &self,
+        triangle: &Triangle,
+        tex_coord: Vec2,
+        barycentric: Vec3,
+        tangent: Vec3,
+        bitangent: Vec3,
+    ) -> Bsdf {
+        let material = &self.materials[triangle.material];
+        let object_position = triangle.a.object_position * barycentric.x()
+            + triangle.b.object_position * barycentric.y()
+            + triangle.c.object_position * barycentric.z();
+        let mut ctx =
+            material.graph.new_context(self, tex_coord, object_position, tangent, bitangent);
+        ctx.evaluate_link(Link::Node(material.output, output_material::outputs::SURFACE))
+    }
+
+    // Multiplies the color of the named light by factor. Returns false if there is no such light.
+    pub fn scale_light(&mut self, name: &str, factor: f64) -> bool {
+        match self.light_names.iter().position(|light_name| light_name == name) {
+            Some(i) => {
+                self.point_lights[i].color = self.point_lights[i].color * factor;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Removes the named light. Returns false if there is no such light.
+    pub fn remove_light(&mut self, name: &str) -> bool {
+        match self.light_names.iter().position(|light_name| light_name == name) {
+            Some(i) => {
+                self.point_lights.remove(i);
+                self.light_names.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Removes triangles without area, which no ray can hit, and all but the first of triangles
+    // with the same corners, winding and material. Returns the number of removed triangles.
+    pub fn remove_redundant_triangles(&mut self) -> usize {
+        let mut removed = remove_redundant_triangles(&mut self.triangles);
+        for mesh in &mut self.meshes {
+            removed += remove_redundant_triangles(mesh);
+        }
+        removed
     }
+
+    // Adds a diffuse ground of the given color at z = 0. It is two triangles centered below the
+    // scene and a hundred times as wide, which is as good as infinite from inside the scene
+    // without making the triangles so large that intersecting them gets imprecise.
+    pub fn add_ground(&mut self, color: Vec3) {
+        let (center, half_size) = match self.summary().aabb {
+            Some((min, max)) => {
+                let size = (max.x() - min.x()).max(max.y() - min.y()).max(1.0);
+                ((min + max) / 2.0, 50.0 * size)
+            }
+            None => (Vec3([0.0; 3]), 50.0),
+        };
+        let mut graph = GraphBuilder::new();
+        let bsdf = graph.add(bsdf_principled::Node {
+            base_color: Link::Constant(color.xyz1()),
+            specular: Link::Constant(0.0),
+            specular_tint: Link::Constant(0.0),
+            metallic: Link::Constant(0.0),
+            emission: Link::Constant(Vec4([0.0, 0.0, 0.0, 1.0])),
+            emission_strength: Link::Constant(1.0),
+        });
+        let material = self.materials.len();
+        self.materials.push(graph.material(bsdf.bsdf(), true));
+
+        let corner = |u: f64, v: f64| {
+            let position = Vec3([
+                center.x() + (2.0 * u - 1.0) * half_size,
+                center.y() + (2.0 * v - 1.0) * half_size,
+                0.0,
+            ]);
+            Vertex {
+                position,
+                normal: Vec3([0.0, 0.0, 1.0]),
+                tex_coord: Vec2([u, v]),
+                object_position: position,
+                tangent: None,
+            }
+        };
+        let corners = [corner(0.0, 0.0), corner(1.0, 0.0), corner(1.0, 1.0), corner(0.0, 1.0)];
+        self.triangles.push(Triangle::new(corners[0], corners[1], corners[2], material, false));
+        self.triangles.push(Triangle::new(corners[0], corners[2], corners[3], material, false));
+    }
+}
+
+fn triangles_aabb(triangles: &[Triangle]) -> Option<(Vec3, Vec3)> {
+    triangles.iter().map(Triangle::calculate_aabb).fold(None, |aabb, (min, max)| match aabb {
+        Some((aabb_min, aabb_max)) => Some((aabb_min.min(min), aabb_max.max(max))),
+        None => Some((min, max)),
+    })
+}
+
+fn remove_redundant_triangles(triangles: &mut Vec<Triangle>) -> usize {
+    let count = triangles.len();
+    let mut seen = HashSet::new();
+    triangles.retain(|triangle| {
+        let corners = [triangle.a.position, triangle.b.position, triangle.c.position];
+        let longest_edge_sqlen =
+            (0..3).map(|i| (corners[(i + 1) % 3] - corners[i]).sqlen()).fold(0.0, f64::max);
+        let Plane { a, b, c, .. } = triangle.plane;
+        // Relative to the edges, so that finely tessellated small meshes are kept
+        if Vec3([a, b, c]).len() <= EPS * longest_edge_sqlen {
+            return false;
+        }
+
+        // Start with the smallest corner, so rotated duplicates have the same key
+        let key = |i: usize| -> [u64; 3] {
+            let Vec3([x, y, z]) = corners[i % 3];
+            [x.to_bits(), y.to_bits(), z.to_bits()]
+        };
+        let first = (0..3).min_by_key(|i| key(*i)).unwrap();
+        seen.insert((key(first), key(first + 1), key(first + 2), triangle.material))
+    });
+    count - triangles.len()
 }
 
 #[derive(Debug)]
+pub struct Material {
+    // Index of the output_material node in the graph
+    pub output: usize,
+    pub graph: Graph,
+    // Shade both sides of the triangles instead of culling their backfaces
+    pub double_sided: bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Instance {
+    pub mesh: usize,
+    pub transform: Mat4,
+}
+
+#[derive(Debug, Copy, Clone)]
 pub struct Camera {
     pub position: Vec3,
     pub top_left_corner: Vec3,
@@ -27,11 +210,104 @@ pub struct Camera {
     pub plane_height: f64,
     pub right_vector: Vec3,
     pub down_vector: Vec3,
+    pub znear: f64,
+    pub zfar: f64,
+}
+
+// The side of the image a camera's field of view spans, the other one follows from the aspect
+// ratio of the image
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FovAxis {
+    Horizontal,
+    Vertical,
+}
+
+// How a camera maps the scene to its image. fov is the field of view in radians along fov_axis
+// and znear the distance of the image plane. Nothing closer than znear or farther than zfar
+// (measured along the viewing direction) is visible. pixel_aspect is the width of a pixel divided
+// by its height.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Projection {
+    pub fov: f64,
+    pub fov_axis: FovAxis,
+    pub znear: f64,
+    pub zfar: f64,
+    pub pixel_aspect: f64,
+}
+
+impl Camera {
+    // Creates a camera at position looking along look that renders an image of w x h pixels.
+    // look, up and left have to be normalized and orthogonal to each other.
+    pub fn new(
+        position: Vec3,
+        look: Vec3,
+        up: Vec3,
+        left: Vec3,
+        projection: Projection,
+        w: usize,
+        h: usize,
+    ) -> Camera {
+        let Projection { fov, fov_axis, znear, zfar, pixel_aspect } = projection;
+        // Non-square pixels change the aspect ratio of the displayed image. As the rays are spread
+        // evenly across the image plane, this is all it takes to stretch them accordingly.
+        let image_aspect = w as f64 * pixel_aspect / h as f64;
+        let (image_plane_half_width, image_plane_half_height) = match fov_axis {
+            FovAxis::Horizontal => {
+                let half_width = znear * (fov / 2.0).tan();
+                (half_width, half_width / image_aspect)
+            }
+            FovAxis::Vertical => {
+                let half_height = znear * (fov / 2.0).tan();
+                (half_height * image_aspect, half_height)
+            }
+        };
+        let image_plane_top_left =
+            position + znear * look + image_plane_half_width * left + image_plane_half_height * up;
+        Camera {
+            position,
+            top_left_corner: image_plane_top_left,
+            plane_width: image_plane_half_width * 2.0,
+            plane_height: image_plane_half_height * 2.0,
+            right_vector: -left,
+            down_vector: -up,
+            znear,
+            zfar,
+        }
+    }
+
+    // The range of lambda in which primary rays are not clipped. Primary rays go from the camera
+    // to the image plane, so each ray reaches a depth of znear at lambda = 1 and a depth of zfar at
+    // lambda = zfar / znear.
+    pub fn clip_range(&self) -> (f64, f64) {
+        (1.0, self.zfar / self.znear)
+    }
+
+    // The normalized direction towards the center of the image plane
+    pub fn look(&self) -> Vec3 {
+        let center = self.top_left_corner
+            + self.right_vector * (self.plane_width / 2.0)
+            + self.down_vector * (self.plane_height / 2.0);
+        (center - self.position).normalize()
+    }
+
+    // Moves the camera by translation and turns it around its position by rotation.
+    pub fn transformed(&self, translation: Vec3, rotation: Mat4) -> Camera {
+        let rotate = |v: Vec3| (rotation * v.xyz0()).xyz();
+        let position = self.position + translation;
+        Camera {
+            position,
+            top_left_corner: position + rotate(self.top_left_corner - self.position),
+            right_vector: rotate(self.right_vector),
+            down_vector: rotate(self.down_vector),
+            ..*self
+        }
+    }
 }
 
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct PointLight {
     pub position: Vec3,
+    // Radiant intensity in W/sr
     pub color: Vec3,
     pub radius: f64,
     // Light attenuation ax² + bx + c
@@ -55,20 +331,54 @@ pub struct Triangle {
     c: Vertex,
     material: usize,
     plane: Plane,
+    // Unit directions in which the texture coordinates u and v grow along the triangle, used
+    // unless all vertices have a tangent
+    tangent: Vec3,
+    bitangent: Vec3,
+    // Flat triangles are shaded with the normal of their plane instead of the vertex normals
+    smooth: bool,
 }
 
 impl Triangle {
-    pub fn new(ta: Vertex, tb: Vertex, tc: Vertex, material: usize) -> Triangle {
+    pub fn new(ta: Vertex, tb: Vertex, tc: Vertex, material: usize, smooth: bool) -> Triangle {
         // (a, b, c) is the normal vector of the triangle's plane:  n = (t[1]-t[0]) x (t[2]-t[0])
         // Triangle plane:  ax + by + cz = d
         //     (a, b, c) = n.xyz
         //     d = dot(t[0], n.xyz)
         let (pa, pb, pc, pd) = {
             let n = (tb.position - ta.position).cross(tc.position - ta.position);
+            // Face the same side as the vertex normals, which the winding does not guarantee,
+            // e.g. for mirrored objects
+            let vertex_normal = ta.normal + tb.normal + tc.normal;
+            let n = if n.dot(vertex_normal) < 0.0 { -n } else { n };
             let d = ta.position.dot(n);
             (n.x(), n.y(), n.z(), d)
         };
-        Triangle { a: ta, b: tb, c: tc, material, plane: Plane { a: pa, b: pb, c: pc, d: pd } }
+        // Solve edge = du * tangent + dv * bitangent for both edges from a
+        let (tangent, bitangent) = {
+            let (e1, e2) = (tb.position - ta.position, tc.position - ta.position);
+            let (Vec2([du1, dv1]), Vec2([du2, dv2])) =
+                (tb.tex_coord - ta.tex_coord, tc.tex_coord - ta.tex_coord);
+            let det = du1 * dv2 - du2 * dv1;
+            let tangent = (e1 * dv2 - e2 * dv1) / det;
+            let bitangent = (e2 * du1 - e1 * du2) / det;
+            if det.abs() > 1e-12 && tangent.sqlen() > 0.0 && bitangent.sqlen() > 0.0 {
+                (tangent.normalize(), bitangent.normalize())
+            } else {
+                // Without UV area any frame will do
+                Vec3([pa, pb, pc]).normalize().orthonormal_basis()
+            }
+        };
+        Triangle {
+            a: ta,
+            b: tb,
+            c: tc,
+            material,
+            plane: Plane { a: pa, b: pb, c: pc, d: pd },
+            tangent,
+            bitangent,
+            smooth,
+        }
     }
 
     pub fn a(&self) -> &Vertex {
@@ -83,8 +393,86 @@ impl Triangle {
         &self.c
     }
 
-    pub fn plane(&self) -> &Plane {
-        &self.plane
+    pub fn material(&self) -> usize {
+        self.material
+    }
+
+    pub fn set_material(&mut self, material: usize) {
+        self.material = material;
+    }
+
+    // The shading normal at the given barycentric coordinates, not normalized
+    pub fn normal(&self, Vec3([alpha, beta, gamma]): Vec3) -> Vec3 {
+        if self.smooth {
+            self.a.normal * alpha + self.b.normal * beta + self.c.normal * gamma
+        } else {
+            let Plane { a, b, c, .. } = self.plane;
+            Vec3([a, b, c])
+        }
+    }
+
+    // Tangent and bitangent at the given barycentric coordinates with the given unit shading
+    // normal, made orthonormal to it. Vertex tangents are interpolated. Otherwise the bitangent
+    // keeps the side of the triangle's, so mirrored texture coordinates stay mirrored.
+    pub fn tangent_frame(&self, Vec3([alpha, beta, gamma]): Vec3, normal: Vec3) -> (Vec3, Vec3) {
+        if let (Some(a), Some(b), Some(c)) = (self.a.tangent, self.b.tangent, self.c.tangent) {
+            let tangent = (a * alpha + b * beta + c * gamma).xyz();
+            let tangent = tangent - normal * normal.dot(tangent);
+            // Otherwise the vertex tangents cancel out, e.g. on a seam
+            if tangent.sqlen() >= 1e-12 {
+                let tangent = tangent.normalize();
+                return (tangent, normal.cross(tangent) * a.w().signum());
+            }
+        }
+        let tangent = self.tangent - normal * normal.dot(self.tangent);
+        if tangent.sqlen() < 1e-12 {
+            return normal.orthonormal_basis();
+        }
+        let tangent = tangent.normalize();
+        let bitangent = normal.cross(tangent);
+        if bitangent.dot(self.bitangent) < 0.0 {
+            (tangent, -bitangent)
+        } else {
+            (tangent, bitangent)
+        }
+    }
+
+    // Intersects the line ray_origin + lambda * ray with the triangle with the corner a, the edges
+    // b - a and c - a, and normal = edge_ab x edge_ac, which the BVH keeps around for each
+    // triangle. Returns lambda and the barycentric coordinates (weights of a, b and c) of the hit,
+    // both sides of the triangle count. The barycentric coordinates are the areas of the
+    // sub-triangles formed with the hit, relative to the area of the triangle.
+    #[inline(always)]
+    pub fn intersect_edges(
+        a: Vec3,
+        edge_ab: Vec3,
+        edge_ac: Vec3,
+        normal: Vec3,
+        ray_origin: Vec3,
+        ray: Vec3,
+    ) -> Option<(f64, Vec3)> {
+        let lambda = normal.dot(a - ray_origin) / normal.dot(ray);
+        if !lambda.is_finite() {
+            // The ray is parallel to the triangle
+            return None;
+        }
+
+        // With p = intersection - a, the sub-triangles have the areas
+        //     (a, b, p): |p x edge_ab|
+        //     (a, c, p): |p x edge_ac|
+        //     (b, c, p): |(edge_ab - p) x (edge_ac - p)| = |normal + p x edge_ab - p x edge_ac|
+        let p = ray_origin + lambda * ray - a;
+        let cross_ab = p.cross(edge_ab);
+        let cross_ac = p.cross(edge_ac);
+        let area_triangle = normal.len();
+        let gamma = cross_ab.len() / area_triangle;
+        let beta = cross_ac.len() / area_triangle;
+        let alpha = (normal + cross_ab - cross_ac).len() / area_triangle;
+        if !(alpha + beta + gamma).almost_eq_within(1.0, HIT_TOLERANCE) {
+            return None;
+        }
+
+        Some((lambda, Vec3([alpha, beta, gamma])))
     }
 }
 
@@ -96,6 +484,8 @@ impl HasAABB for Triangle {
     }
 }
 
+// Every hit carries one, so boxing the triangle would cost an allocation per ray
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum Geometry {
     Triangle(Triangle),
@@ -116,4 +506,198 @@ pub struct Vertex {
     pub position: Vec3,
     pub normal: Vec3,
     pub tex_coord: Vec2,
+    // The position before the object was transformed into the scene
+    pub object_position: Vec3,
+    // A tangent given by the importer and in w the sign of the bitangent, which is
+    // w * (normal x tangent) as in glTF. Without one, tangents follow the texture coordinates.
+    pub tangent: Option<Vec4>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::tests::assert_vec3_eq;
+
+    // The triangle (0, 0, 0), (1, 0, 0), (0, 1, 0) in the plane z = 0
+    fn triangle() -> Triangle {
+        let vertex = |x: f64, y: f64| Vertex {
+            position: Vec3([x, y, 0.0]),
+            normal: Vec3([0.0, 0.0, 1.0]),
+            tex_coord: Vec2([x, y]),
+            object_position: Vec3([x, y, 0.0]),
+            tangent: None,
+        };
+        Triangle::new(vertex(0.0, 0.0), vertex(1.0, 0.0), vertex(0.0, 1.0), 0, false)
+    }
+
+    // The triangle above with its texture coordinates at the corners given by tex_coord
+    fn textured_triangle(tex_coord: impl Fn(f64, f64) -> Vec2) -> Triangle {
+        let vertex = |x: f64, y: f64| Vertex {
+            position: Vec3([x, y, 0.0]),
+            normal: Vec3([0.0, 0.0, 1.0]),
+            tex_coord: tex_coord(x, y),
+            object_position: Vec3([x, y, 0.0]),
+            tangent: None,
+        };
+        Triangle::new(vertex(0.0, 0.0), vertex(1.0, 0.0), vertex(0.0, 1.0), 0, true)
+    }
+
+    const CENTER: Vec3 = Vec3([1.0 / 3.0, 1.0 / 3.0, 1.0 / 3.0]);
+    const UP: Vec3 = Vec3([0.0, 0.0, 1.0]);
+
+    #[test]
+    fn tangents_follow_texture_coordinates() {
+        // u grows along y and v along -x, at different rates
+        let (tangent, bitangent) =
+            textured_triangle(|x, y| Vec2([2.0 * y, -x])).tangent_frame(CENTER, UP);
+        assert_vec3_eq(tangent, Vec3([0.0, 1.0, 0.0]), 1e-9);
+        assert_vec3_eq(bitangent, Vec3([-1.0, 0.0, 0.0]), 1e-9);
+        // A tilted shading normal tilts the frame with it
+        let normal = Vec3([0.0, -1.0, 1.0]).normalize();
+        let (tangent, bitangent) =
+            textured_triangle(|x, y| Vec2([2.0 * y, -x])).tangent_frame(CENTER, normal);
+        assert_vec3_eq(tangent, Vec3([0.0, 1.0, 1.0]).normalize(), 1e-9);
+        assert_vec3_eq(bitangent, Vec3([-1.0, 0.0, 0.0]), 1e-9);
+    }
+
+    #[test]
+    fn tangents_without_uv_area_fall_back_to_any_frame() {
+        // All corners on one texel, and all of them on a line
+        for tex_coord in [|_, _| Vec2([0.5, 0.5]), |x: f64, y: f64| Vec2([x + y, x + y])].iter() {
+            let (tangent, bitangent) = textured_triangle(tex_coord).tangent_frame(CENTER, UP);
+            let (expected_tangent, expected_bitangent) = UP.orthonormal_basis();
+            assert_vec3_eq(tangent, expected_tangent, 1e-9);
+            assert_vec3_eq(bitangent, expected_bitangent, 1e-9);
+        }
+    }
+
+    #[test]
+    fn mirrored_texture_coordinates_keep_the_bitangent_side() {
+        // u grows along -x, so normal x tangent points to -y, while v grows along y
+        let (tangent, bitangent) =
+            textured_triangle(|x, y| Vec2([-x, y])).tangent_frame(CENTER, UP);
+        assert_vec3_eq(tangent, Vec3([-1.0, 0.0, 0.0]), 1e-9);
+        assert_vec3_eq(bitangent, Vec3([0.0, 1.0, 0.0]), 1e-9);
+        // The frame is left-handed then
+        assert_vec3_eq(tangent.cross(bitangent), -UP, 1e-9);
+    }
+
+    // The triangle above with texture coordinates equal to x and y, and the given vertex tangents
+    fn triangle_with_tangents(tangents: [Vec4; 3]) -> Triangle {
+        let vertex = |x: f64, y: f64, tangent: Vec4| Vertex {
+            position: Vec3([x, y, 0.0]),
+            normal: Vec3([0.0, 0.0, 1.0]),
+            tex_coord: Vec2([x, y]),
+            object_position: Vec3([x, y, 0.0]),
+            tangent: Some(tangent),
+        };
+        let [a, b, c] = tangents;
+        Triangle::new(vertex(0.0, 0.0, a), vertex(1.0, 0.0, b), vertex(0.0, 1.0, c), 0, true)
+    }
+
+    #[test]
+    fn vertex_tangents_are_interpolated() {
+        let x = Vec4([1.0, 0.0, 0.0, 1.0]);
+        let y = Vec4([0.0, 1.0, 0.0, 1.0]);
+        let triangle = triangle_with_tangents([x, y, x]);
+        let (tangent, bitangent) = triangle.tangent_frame(Vec3([0.0, 1.0, 0.0]), UP);
+        assert_vec3_eq(tangent, Vec3([0.0, 1.0, 0.0]), 1e-9);
+        assert_vec3_eq(bitangent, Vec3([-1.0, 0.0, 0.0]), 1e-9);
+        let (tangent, bitangent) = triangle.tangent_frame(CENTER, UP);
+        assert_vec3_eq(tangent, Vec3([2.0, 1.0, 0.0]).normalize(), 1e-9);
+        assert_vec3_eq(bitangent, Vec3([-1.0, 2.0, 0.0]).normalize(), 1e-9);
+    }
+
+    #[test]
+    fn vertex_tangents_carry_the_bitangent_sign() {
+        let x = Vec4([1.0, 0.0, 0.0, -1.0]);
+        let (tangent, bitangent) = triangle_with_tangents([x, x, x]).tangent_frame(CENTER, UP);
+        assert_vec3_eq(tangent, Vec3([1.0, 0.0, 0.0]), 1e-9);
+        assert_vec3_eq(bitangent, Vec3([0.0, -1.0, 0.0]), 1e-9);
+    }
+
+    #[test]
+    fn cancelling_vertex_tangents_fall_back_to_texture_coordinates() {
+        // Opposite tangents at a and b, and one along the normal at c, as on a seam
+        let triangle = triangle_with_tangents([
+            Vec4([1.0, 0.0, 0.0, 1.0]),
+            Vec4([-1.0, 0.0, 0.0, 1.0]),
+            Vec4([0.0, 0.0, 1.0, 1.0]),
+        ]);
+        let (tangent, bitangent) = triangle.tangent_frame(Vec3([0.4, 0.4, 0.2]), UP);
+        assert_vec3_eq(tangent, Vec3([1.0, 0.0, 0.0]), 1e-9);
+        assert_vec3_eq(bitangent, Vec3([0.0, 1.0, 0.0]), 1e-9);
+    }
+
+    // Intersects triangle() from its corner and edges, like the BVH does
+    fn intersect(ray_origin: Vec3, ray: Vec3) -> Option<(f64, Vec3)> {
+        let triangle = triangle();
+        let a = triangle.a().position;
+        let (edge_ab, edge_ac) = (triangle.b().position - a, triangle.c().position - a);
+        Triangle::intersect_edges(a, edge_ab, edge_ac, edge_ab.cross(edge_ac), ray_origin, ray)
+    }
+
+    fn assert_hit(hit: Option<(f64, Vec3)>, lambda: f64, barycentric: Vec3) {
+        let (hit_lambda, hit_barycentric) = hit.expect("expected a hit");
+        assert!(hit_lambda.almost_eq_within(lambda, 1e-9), "lambda {}", hit_lambda);
+        assert!(
+            (hit_barycentric - barycentric).len().almost_zero_within(1e-9),
+            "barycentric {:?}",
+            hit_barycentric
+        );
+    }
+
+    #[test]
+    fn intersect_hit() {
+        let hit = intersect(Vec3([0.25, 0.25, 2.0]), Vec3([0.0, 0.0, -1.0]));
+        assert_hit(hit, 2.0, Vec3([0.5, 0.25, 0.25]));
+        // The length of the ray scales lambda
+        let hit = intersect(Vec3([0.25, 0.25, 2.0]), Vec3([0.0, 0.0, -4.0]));
+        assert_hit(hit, 0.5, Vec3([0.5, 0.25, 0.25]));
+        // Both sides count
+        let hit = intersect(Vec3([0.25, 0.25, -2.0]), Vec3([0.0, 0.0, 1.0]));
+        assert_hit(hit, 2.0, Vec3([0.5, 0.25, 0.25]));
+    }
+
+    #[test]
+    fn intersect_miss() {
+        assert_eq!(intersect(Vec3([1.0, 1.0, 2.0]), Vec3([0.0, 0.0, -1.0])), None);
+        assert_eq!(intersect(Vec3([-0.1, 0.5, 2.0]), Vec3([0.0, 0.0, -1.0])), None);
+        assert_eq!(intersect(Vec3([0.5, -0.1, 2.0]), Vec3([0.0, 0.0, -1.0])), None);
+    }
+
+    #[test]
+    fn intersect_parallel() {
+        assert_eq!(intersect(Vec3([-1.0, 0.25, 1.0]), Vec3([1.0, 0.0, 0.0])), None);
+        // Even within the plane of the triangle
+        assert_eq!(intersect(Vec3([-1.0, 0.25, 0.0]), Vec3([1.0, 0.0, 0.0])), None);
+    }
+
+    #[test]
+    fn intersect_grazing() {
+        // Rays at a shallow angle still hit where the line meets the plane
+        let hit = intersect(Vec3([-99.75, 0.25, 1.0]), Vec3([1.0, 0.0, -0.01]));
+        assert_hit(hit, 100.0, Vec3([0.5, 0.25, 0.25]));
+        // and miss once that is past the far edge
+        assert_eq!(intersect(Vec3([-99.75, 0.25, 1.0]), Vec3([1.0, 0.0, -0.0099])), None);
+    }
+
+    #[test]
+    fn intersect_behind_origin() {
+        // The line is intersected, so hits behind the origin have a negative lambda for the
+        // caller to reject
+        let hit = intersect(Vec3([0.25, 0.25, 2.0]), Vec3([0.0, 0.0, 1.0]));
+        assert_hit(hit, -2.0, Vec3([0.5, 0.25, 0.25]));
+    }
+
+    #[test]
+    fn intersect_edges_and_vertices() {
+        let down = Vec3([0.0, 0.0, -1.0]);
+        assert_hit(intersect(Vec3([0.5, 0.0, 1.0]), down), 1.0, Vec3([0.5, 0.5, 0.0]));
+        assert_hit(intersect(Vec3([0.0, 0.5, 1.0]), down), 1.0, Vec3([0.5, 0.0, 0.5]));
+        assert_hit(intersect(Vec3([0.5, 0.5, 1.0]), down), 1.0, Vec3([0.0, 0.5, 0.5]));
+        assert_hit(intersect(Vec3([0.0, 0.0, 1.0]), down), 1.0, Vec3([1.0, 0.0, 0.0]));
+        assert_hit(intersect(Vec3([1.0, 0.0, 1.0]), down), 1.0, Vec3([0.0, 1.0, 0.0]));
+        assert_hit(intersect(Vec3([0.0, 1.0, 1.0]), down), 1.0, Vec3([0.0, 0.0, 1.0]));
+    }
 }
@@ -1,58 +1,98 @@
 use crate::math::{HasAABB, Vec3};
 use crate::simd::Simd4;
-use std::f64::{INFINITY, NEG_INFINITY};
+use std::arch::x86_64::*;
+use std::cmp::Ordering;
 use std::fmt::{Debug, Formatter};
 
-#[derive(Clone)]
-enum Value<T: HasAABB + Clone> {
-    Node,
+#[derive(Copy, Clone)]
+enum Value {
+    // Index of the child node
+    Node(usize),
     Empty,
-    Leaf(T),
+    // Index of the value in Bvh::values
+    Leaf(usize),
 }
 
-impl<T: HasAABB + Clone> Debug for Value<T> {
+impl Debug for Value {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             Value::Empty => write!(f, "ε"),
-            Value::Node => write!(f, "N"),
+            Value::Node(..) => write!(f, "N"),
             Value::Leaf(..) => write!(f, "L(..)"),
         }
     }
 }
 
-impl<T: HasAABB + Debug + Clone> Value<T> {
+impl Value {
     fn is_empty(&self) -> bool {
-        match self {
-            Value::Empty => true,
-            _ => false,
-        }
+        matches!(self, Value::Empty)
     }
 }
 
 #[derive(Debug, Clone)]
-struct Node<T: HasAABB + Debug + Clone> {
+struct Node {
     aabb_min_x: Simd4,
     aabb_min_y: Simd4,
     aabb_min_z: Simd4,
     aabb_max_x: Simd4,
     aabb_max_y: Simd4,
     aabb_max_z: Simd4,
-    value: [Value<T>; 4],
+    value: [Value; 4],
 }
 
-impl<T: HasAABB + Debug + Clone> Node<T> {
+impl Node {
     fn get_aabb(&self, i: usize) -> (Vec3, Vec3) {
         let slot_aabb_min = Vec3([self.aabb_min_x[i], self.aabb_min_y[i], self.aabb_min_z[i]]);
         let slot_aabb_max = Vec3([self.aabb_max_x[i], self.aabb_max_y[i], self.aabb_max_z[i]]);
         (slot_aabb_min, slot_aabb_max)
     }
+
+    // A node holding the given values, at most four
+    fn new(values: Vec<((Vec3, Vec3), Value)>) -> Node {
+        let mut node = Node {
+            aabb_min_x: Simd4([f64::INFINITY; 4]),
+            aabb_min_y: Simd4([f64::INFINITY; 4]),
+            aabb_min_z: Simd4([f64::INFINITY; 4]),
+            aabb_max_x: Simd4([f64::NEG_INFINITY; 4]),
+            aabb_max_y: Simd4([f64::NEG_INFINITY; 4]),
+            aabb_max_z: Simd4([f64::NEG_INFINITY; 4]),
+            value: [Value::Empty, Value::Empty, Value::Empty, Value::Empty],
+        };
+        for (i, ((aabb_min, aabb_max), value)) in values.into_iter().enumerate() {
+            node.aabb_min_x[i] = aabb_min.0[0];
+            node.aabb_min_y[i] = aabb_min.0[1];
+            node.aabb_min_z[i] = aabb_min.0[2];
+            node.aabb_max_x[i] = aabb_max.0[0];
+            node.aabb_max_y[i] = aabb_max.0[1];
+            node.aabb_max_z[i] = aabb_max.0[2];
+            node.value[i] = value;
+        }
+        node
+    }
+
+    // The AABB enclosing all values of the node
+    fn aabb(&self) -> (Vec3, Vec3) {
+        let mut aabb_min = Vec3([f64::INFINITY; 3]);
+        let mut aabb_max = Vec3([f64::NEG_INFINITY; 3]);
+        for i in 0..4 {
+            if !self.value[i].is_empty() {
+                let (slot_min, slot_max) = self.get_aabb(i);
+                aabb_min = aabb_min.min(slot_min);
+                aabb_max = aabb_max.max(slot_max);
+            }
+        }
+        (aabb_min, aabb_max)
+    }
 }
 
 #[derive(Debug)]
 pub struct Bvh<T: HasAABB + Debug + Clone> {
-    // root = 0
-    // child[i] = parent*4 + (i + 1)
-    nodes: Vec<Node<T>>,
+    // Children come before their parents, so the root is the last node
+    nodes: Vec<Node>,
+    // The values in the order in which a traversal meets them, so that values which are close in
+    // space are also close in memory. Keeping them out of the nodes keeps the nodes small.
+    values: Vec<T>,
+    layer_count: u32,
 }
 
 #[derive(Copy, Clone)]
@@ -64,7 +104,8 @@ pub struct BvhNode<'a, T: HasAABB + Debug + Clone> {
 #[derive(Copy, Clone)]
 pub enum BvhChild<'a, T: HasAABB + Debug + Clone> {
     Subtree(BvhNode<'a, T>),
-    Value(&'a T),
+    // The index of the value and the value
+    Value(usize, &'a T),
     Empty,
 }
 
@@ -96,127 +137,492 @@ impl<'a, T: HasAABB + Debug + Clone> BvhNode<'a, T> {
     pub fn value(&self, index: usize) -> BvhChild<'a, T> {
         match &self.bvh.nodes[self.index].value[index] {
             Value::Empty => BvhChild::Empty,
-            Value::Leaf(value) => BvhChild::Value(value),
-            Value::Node => {
-                BvhChild::Subtree(BvhNode { bvh: self.bvh, index: self.index * 4 + index + 1 })
-            }
+            Value::Leaf(index) => BvhChild::Value(*index, &self.bvh.values[*index]),
+            Value::Node(child) => BvhChild::Subtree(BvhNode { bvh: self.bvh, index: *child }),
         }
     }
 }
 
 impl<T: HasAABB + Clone + Debug> Bvh<T> {
     pub fn new(objects: &[T]) -> Bvh<T> {
-        let layer_count = (objects.len() as f64).log(4.0).ceil() as u32;
-        // node count = https://www.wolframalpha.com/input/?i=sum+4%5Ei+for+i+%3D+0+to+l-1
-        let node_count = (4usize.pow(layer_count) - 1) / 3;
-        let mut nodes = vec![
-            Node {
-                aabb_min_x: Simd4([INFINITY; 4]),
-                aabb_min_y: Simd4([INFINITY; 4]),
-                aabb_min_z: Simd4([INFINITY; 4]),
-                aabb_max_x: Simd4([NEG_INFINITY; 4]),
-                aabb_max_y: Simd4([NEG_INFINITY; 4]),
-                aabb_max_z: Simd4([NEG_INFINITY; 4]),
-                value: [Value::Empty, Value::Empty, Value::Empty, Value::Empty],
-            };
-            node_count
-        ];
+        // Each layer has a quarter of the nodes of the one below, so this is enough for all of them
+        let mut node_count = 1;
+        let mut layer_nodes = objects.len();
+        while layer_nodes > 4 {
+            layer_nodes = layer_nodes.div_ceil(4);
+            node_count += layer_nodes;
+        }
 
-        // init leaves
-        let leafes_start_index = (4usize.pow(layer_count - 1) - 1) / 3;
-        let leafes_end_index =
-            leafes_start_index + objects.len() / 4 + if objects.len() % 4 == 0 { 0 } else { 1 };
-        for (i, object) in objects.iter().enumerate() {
-            let node_i = i / 4 + leafes_start_index;
-            let leaf_i = i % 4;
-            let (aabb_min, aabb_max) = object.calculate_aabb();
-            nodes[node_i].aabb_min_x[leaf_i] = aabb_min.0[0];
-            nodes[node_i].aabb_min_y[leaf_i] = aabb_min.0[1];
-            nodes[node_i].aabb_min_z[leaf_i] = aabb_min.0[2];
-            nodes[node_i].aabb_max_x[leaf_i] = aabb_max.0[0];
-            nodes[node_i].aabb_max_y[leaf_i] = aabb_max.0[1];
-            nodes[node_i].aabb_max_z[leaf_i] = aabb_max.0[2];
-            nodes[node_i].value[leaf_i] = Value::Leaf(object.clone());
+        // The tree is built from the leaves up. Only nodes which get values are allocated.
+        let mut nodes = Vec::with_capacity(node_count);
+        let mut values: Vec<_> = objects
+            .iter()
+            .enumerate()
+            .map(|(i, object)| (object.calculate_aabb(), Value::Leaf(i)))
+            .collect();
+        let mut layer_count = 1;
+        while values.len() > 4 {
+            let mut parent_values = Vec::with_capacity(values.len().div_ceil(4));
+            for group in group_by_metric(values) {
+                if group.len() == 1 {
+                    // A node with a single child would only cost another box test
+                    parent_values.extend(group);
+                } else {
+                    let node = Node::new(group);
+                    parent_values.push((node.aabb(), Value::Node(nodes.len())));
+                    nodes.push(node);
+                }
+            }
+            values = parent_values;
+            layer_count += 1;
         }
-        sort_by_metric(&mut nodes, leafes_start_index, leafes_end_index);
-
-        // init parent layers
-        for layer in (0..(layer_count - 1)).rev() {
-            let layer_start = (4usize.pow(layer) - 1) / 3;
-            let layer_end = (4usize.pow(layer + 1) - 1) / 3;
-            let mut layer_real_end = layer_end;
-            'outer: for i in layer_start..layer_end {
-                let children = [4 * i + 1, 4 * i + 2, 4 * i + 3, 4 * i + 4];
-                match (
-                    &nodes[children[0]].value,
-                    &nodes[children[1]].value,
-                    &nodes[children[2]].value,
-                    &nodes[children[3]].value,
-                ) {
-                    (
-                        [Value::Empty, Value::Empty, Value::Empty, Value::Empty],
-                        [Value::Empty, Value::Empty, Value::Empty, Value::Empty],
-                        [Value::Empty, Value::Empty, Value::Empty, Value::Empty],
-                        [Value::Empty, Value::Empty, Value::Empty, Value::Empty],
-                    ) => {
-                        layer_real_end = i;
-                        break 'outer;
+        nodes.push(Node::new(values));
+
+        let mut bvh = Bvh { nodes, values: Vec::with_capacity(objects.len()), layer_count };
+        bvh.order_values(objects);
+        bvh
+    }
+
+    // Fills values with the objects in depth-first order of the leaves and points the leaves to
+    // their new index.
+    fn order_values(&mut self, objects: &[T]) {
+        let mut stack = vec![self.nodes.len() - 1];
+        while let Some(index) = stack.pop() {
+            // Push the last child first, so that the children are visited in order
+            for i in (0..4).rev() {
+                match self.nodes[index].value[i] {
+                    Value::Node(child) => stack.push(child),
+                    Value::Empty | Value::Leaf(_) => {}
+                }
+            }
+            for i in 0..4 {
+                if let Value::Leaf(object) = self.nodes[index].value[i] {
+                    self.nodes[index].value[i] = Value::Leaf(self.values.len());
+                    self.values.push(objects[object].clone());
+                }
+            }
+        }
+    }
+
+    // The values in the order of the indices given by the traversals
+    pub fn values(&self) -> &[T] {
+        &self.values
+    }
+
+    // The most candidates a traversal ever keeps on its stack. Each visited node replaces itself
+    // by at most four children, so every layer adds three of them except the last one, which adds
+    // four values.
+    pub fn traversal_stack_size(&self) -> usize {
+        3 * self.layer_count as usize + 1
+    }
+
+    // Number of layers below and including the root. The nodes are four wide to match the SIMD
+    // box tests, so this is fixed by the number of values.
+    pub fn layer_count(&self) -> u32 {
+        self.layer_count
+    }
+
+    // Number of allocated nodes and how many of them hold at least one value or subtree
+    pub fn node_counts(&self) -> (usize, usize) {
+        let used =
+            self.nodes.iter().filter(|node| node.value.iter().any(|v| !v.is_empty())).count();
+        (self.nodes.len(), used)
+    }
+
+    // Bytes taken by the nodes and values
+    pub fn memory_size(&self) -> usize {
+        self.nodes.len() * std::mem::size_of::<Node>()
+            + self.values.len() * std::mem::size_of::<T>()
+    }
+
+    pub fn root(&self) -> BvhNode<'_, T> {
+        BvhNode { bvh: self, index: self.nodes.len() - 1 }
+    }
+
+    // The AABB enclosing all values of the BVH
+    pub fn aabb(&self) -> (Vec3, Vec3) {
+        self.nodes[self.nodes.len() - 1].aabb()
+    }
+
+    // Walks the BVH along the ray. The stack is cleared before it is used.
+    pub fn traverse<'a, 's>(
+        &'a self,
+        ray_origin: Vec3,
+        ray: Vec3,
+        min_dist: f64,
+        max_dist: f64,
+        stack: &'s mut TraversalStack<'a, T>,
+    ) -> Traversal<'a, 's, T> {
+        stack.candidates.clear();
+        stack.push(Candidate::Node(self.root(), f64::NEG_INFINITY));
+        Traversal { ray: BoxTestRay::new(ray_origin, ray), min_dist, max_dist, stack }
+    }
+
+    // Walks the BVH along four rays at once and calls `f` for every value whose AABB is hit by at
+    // least one of them, together with its index. `f` gets the current max_dist of each ray and
+    // may lower it, e.g. after a hit was found. Rays whose max_dist falls below min_dist take no
+    // further part. The stack is cleared before it is used.
+    pub fn traverse_packet<'a, F: FnMut(usize, &'a T, &mut [f64; 4])>(
+        &'a self,
+        packet: &RayPacket,
+        min_dist: f64,
+        max_dists: &mut [f64; 4],
+        stack: &mut TraversalStack<'a, T>,
+        f: &mut F,
+    ) {
+        stack.candidates.clear();
+        stack.push(Candidate::Node(self.root(), f64::NEG_INFINITY));
+        while let Some(candidate) = stack.candidates.pop() {
+            if let Candidate::Node(node, _) = candidate {
+                stack.tests += 1;
+                // Push the last child first, so that the children are visited in order
+                for i in (0..4).rev() {
+                    let child = node.value(i);
+                    if let BvhChild::Empty = child {
+                        continue;
+                    }
+                    if !intersect_child_packet(&node, i, packet, min_dist, max_dists) {
+                        continue;
+                    }
+                    match child {
+                        BvhChild::Empty => {}
+                        BvhChild::Subtree(sub_bvh) => {
+                            stack.push(Candidate::Node(sub_bvh, f64::NEG_INFINITY))
+                        }
+                        BvhChild::Value(index, value) => {
+                            stack.tests += 1;
+                            f(index, value, max_dists)
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Calls `f` for all values whose AABB contains the given point.
+    pub fn find_containing<'a, F: FnMut(&'a T)>(&'a self, point: Vec3, f: &mut F) {
+        find_containing_rec(self.root(), point, f);
+    }
+}
+
+enum Candidate<'a, T: HasAABB + Debug + Clone> {
+    Node(BvhNode<'a, T>, f64),
+    Value(usize, &'a T, f64),
+}
+
+// Storage for the nodes still to be visited by a traversal. Keeping it around between traversals
+// avoids reallocations.
+pub struct TraversalStack<'a, T: HasAABB + Debug + Clone> {
+    candidates: Vec<Candidate<'a, T>>,
+    // Number of node tests and values handed out by all traversals since the last reset
+    tests: usize,
+}
+
+impl<'a, T: HasAABB + Debug + Clone> TraversalStack<'a, T> {
+    pub fn with_capacity(capacity: usize) -> TraversalStack<'a, T> {
+        TraversalStack { candidates: Vec::with_capacity(capacity), tests: 0 }
+    }
+
+    pub fn tests(&self) -> usize {
+        self.tests
+    }
+
+    pub fn reset_tests(&mut self) {
+        self.tests = 0;
+    }
+
+    #[inline(always)]
+    fn push(&mut self, candidate: Candidate<'a, T>) {
+        // A stack sized by Bvh::traversal_stack_size never has to grow
+        debug_assert!(self.candidates.len() < self.candidates.capacity());
+        self.candidates.push(candidate);
+    }
+}
+
+// Yields the values whose AABB is hit by a ray together with their index, roughly ordered from
+// near to far.
+pub struct Traversal<'a, 's, T: HasAABB + Debug + Clone> {
+    ray: BoxTestRay,
+    min_dist: f64,
+    max_dist: f64,
+    stack: &'s mut TraversalStack<'a, T>,
+}
+
+impl<'a, 's, T: HasAABB + Debug + Clone> Traversal<'a, 's, T> {
+    // Skips everything which is farther away than max_dist from now on, e.g. after a hit was found.
+    pub fn set_max_dist(&mut self, max_dist: f64) {
+        self.max_dist = max_dist;
+    }
+}
+
+impl<'a, 's, T: HasAABB + Debug + Clone> Iterator for Traversal<'a, 's, T> {
+    type Item = (usize, &'a T);
+
+    fn next(&mut self) -> Option<(usize, &'a T)> {
+        while let Some(candidate) = self.stack.candidates.pop() {
+            match candidate {
+                Candidate::Value(index, value, entry) => {
+                    if entry <= self.max_dist {
+                        self.stack.tests += 1;
+                        return Some((index, value));
                     }
-                    (
-                        _,
-                        [Value::Empty, Value::Empty, Value::Empty, Value::Empty],
-                        [Value::Empty, Value::Empty, Value::Empty, Value::Empty],
-                        [Value::Empty, Value::Empty, Value::Empty, Value::Empty],
-                    ) => {
-                        swap_tree_rec(&mut nodes, children[0], i);
-                        layer_real_end = i + 1;
-                        break 'outer;
+                }
+                Candidate::Node(node, entry) => {
+                    if entry > self.max_dist {
+                        continue;
                     }
-                    _ => {
-                        for child_i in 0..4 {
-                            for j in 0..4 {
-                                if !nodes[children[child_i]].value[j].is_empty() {
-                                    nodes[i].aabb_min_x[child_i] = nodes[i].aabb_min_x[child_i]
-                                        .min(nodes[children[child_i]].aabb_min_x[j]);
-                                    nodes[i].aabb_min_y[child_i] = nodes[i].aabb_min_y[child_i]
-                                        .min(nodes[children[child_i]].aabb_min_y[j]);
-                                    nodes[i].aabb_min_z[child_i] = nodes[i].aabb_min_z[child_i]
-                                        .min(nodes[children[child_i]].aabb_min_z[j]);
-                                    nodes[i].aabb_max_x[child_i] = nodes[i].aabb_max_x[child_i]
-                                        .max(nodes[children[child_i]].aabb_max_x[j]);
-                                    nodes[i].aabb_max_y[child_i] = nodes[i].aabb_max_y[child_i]
-                                        .max(nodes[children[child_i]].aabb_max_y[j]);
-                                    nodes[i].aabb_max_z[child_i] = nodes[i].aabb_max_z[child_i]
-                                        .max(nodes[children[child_i]].aabb_max_z[j]);
-                                    nodes[i].value[child_i] = Value::Node;
-                                } else {
-                                    layer_real_end = i + 1;
-                                    break 'outer;
-                                }
+                    self.stack.tests += 1;
+                    let (hits, entries) =
+                        intersect_children(&node, &self.ray, self.min_dist, self.max_dist);
+
+                    // Push the farthest child first, so that the nearest one is visited next
+                    let mut order = [0, 1, 2, 3];
+                    order.sort_by(|a, b| {
+                        entries[*b].partial_cmp(&entries[*a]).unwrap_or(Ordering::Equal)
+                    });
+                    for i in order.iter().cloned() {
+                        if hits[i] != 0 {
+                            continue;
+                        }
+                        match node.value(i) {
+                            BvhChild::Empty => {}
+                            BvhChild::Subtree(sub_bvh) => {
+                                self.stack.push(Candidate::Node(sub_bvh, entries[i]))
+                            }
+                            BvhChild::Value(index, value) => {
+                                self.stack.push(Candidate::Value(index, value, entries[i]))
                             }
                         }
                     }
                 }
             }
-            sort_by_metric(&mut nodes, layer_start, layer_real_end);
         }
+        None
+    }
+}
 
-        Bvh { nodes }
+// The ray prepared for testing it against the four AABBs of a BVH node at once
+struct BoxTestRay {
+    origin_x: __m256d,
+    origin_y: __m256d,
+    origin_z: __m256d,
+    inv_x: __m256d,
+    inv_y: __m256d,
+    inv_z: __m256d,
+}
+
+impl BoxTestRay {
+    fn new(ray_origin: Vec3, ray: Vec3) -> BoxTestRay {
+        unsafe {
+            BoxTestRay {
+                origin_x: _mm256_broadcast_sd(&ray_origin.0[0]),
+                origin_y: _mm256_broadcast_sd(&ray_origin.0[1]),
+                origin_z: _mm256_broadcast_sd(&ray_origin.0[2]),
+                inv_x: _mm256_broadcast_sd(&(1.0 / ray.0[0])),
+                inv_y: _mm256_broadcast_sd(&(1.0 / ray.0[1])),
+                inv_z: _mm256_broadcast_sd(&(1.0 / ray.0[2])),
+            }
+        }
     }
+}
 
-    pub fn root(&self) -> BvhNode<'_, T> {
-        BvhNode { bvh: self, index: 0 }
+// Four rays prepared for testing them against one AABB at once, one ray per lane
+pub struct RayPacket {
+    origin_x: __m256d,
+    origin_y: __m256d,
+    origin_z: __m256d,
+    inv_x: __m256d,
+    inv_y: __m256d,
+    inv_z: __m256d,
+}
+
+impl RayPacket {
+    pub fn new(ray_origins: [Vec3; 4], rays: [Vec3; 4]) -> RayPacket {
+        let lanes = |v: &[Vec3; 4], axis: usize, f: fn(f64) -> f64| unsafe {
+            _mm256_setr_pd(f(v[0].0[axis]), f(v[1].0[axis]), f(v[2].0[axis]), f(v[3].0[axis]))
+        };
+        RayPacket {
+            origin_x: lanes(&ray_origins, 0, |v| v),
+            origin_y: lanes(&ray_origins, 1, |v| v),
+            origin_z: lanes(&ray_origins, 2, |v| v),
+            inv_x: lanes(&rays, 0, |v| 1.0 / v),
+            inv_y: lanes(&rays, 1, |v| 1.0 / v),
+            inv_z: lanes(&rays, 2, |v| 1.0 / v),
+        }
     }
 }
 
-fn swap_tree_rec<T: HasAABB + Debug + Clone>(nodes: &mut [Node<T>], from: usize, to: usize) {
-    if from < nodes.len() && to < nodes.len() {
-        nodes.swap(from, to);
-        // This order is important!
-        swap_tree_rec(nodes, from * 4 + 4, to * 4 + 4);
-        swap_tree_rec(nodes, from * 4 + 3, to * 4 + 3);
-        swap_tree_rec(nodes, from * 4 + 2, to * 4 + 2);
-        swap_tree_rec(nodes, from * 4 + 1, to * 4 + 1);
+// Returns whether any ray of the packet hits the AABB of the node's i-th child between min_dist
+// and its max_dist. Same equations as in intersect_children, but with one AABB and four rays.
+fn intersect_child_packet<T: HasAABB + Debug + Clone>(
+    node: &BvhNode<T>,
+    i: usize,
+    packet: &RayPacket,
+    min_dist: f64,
+    max_dists: &[f64; 4],
+) -> bool {
+    unsafe {
+        // X
+        let a = _mm256_mul_pd(
+            _mm256_sub_pd(_mm256_broadcast_sd(&node.aabb_min_x()[i]), packet.origin_x),
+            packet.inv_x,
+        );
+        let b = _mm256_mul_pd(
+            _mm256_sub_pd(_mm256_broadcast_sd(&node.aabb_max_x()[i]), packet.origin_x),
+            packet.inv_x,
+        );
+        let mut lambda_min =
+            _mm256_max_pd(_mm256_blendv_pd(a, b, packet.inv_x), _mm256_broadcast_sd(&min_dist));
+        let mut lambda_max = _mm256_min_pd(
+            _mm256_blendv_pd(b, a, packet.inv_x),
+            _mm256_loadu_pd(max_dists.as_ptr()),
+        );
+
+        // Y
+        let a = _mm256_mul_pd(
+            _mm256_sub_pd(_mm256_broadcast_sd(&node.aabb_min_y()[i]), packet.origin_y),
+            packet.inv_y,
+        );
+        let b = _mm256_mul_pd(
+            _mm256_sub_pd(_mm256_broadcast_sd(&node.aabb_max_y()[i]), packet.origin_y),
+            packet.inv_y,
+        );
+        lambda_min = _mm256_max_pd(_mm256_blendv_pd(a, b, packet.inv_y), lambda_min);
+        lambda_max = _mm256_min_pd(_mm256_blendv_pd(b, a, packet.inv_y), lambda_max);
+
+        // Z
+        let a = _mm256_mul_pd(
+            _mm256_sub_pd(_mm256_broadcast_sd(&node.aabb_min_z()[i]), packet.origin_z),
+            packet.inv_z,
+        );
+        let b = _mm256_mul_pd(
+            _mm256_sub_pd(_mm256_broadcast_sd(&node.aabb_max_z()[i]), packet.origin_z),
+            packet.inv_z,
+        );
+        lambda_min = _mm256_max_pd(_mm256_blendv_pd(a, b, packet.inv_z), lambda_min);
+        lambda_max = _mm256_min_pd(_mm256_blendv_pd(b, a, packet.inv_z), lambda_max);
+
+        _mm256_movemask_pd(_mm256_cmp_pd(lambda_min, lambda_max, _CMP_LE_OQ)) != 0
+    }
+}
+
+// Returns zero for each child of the node whose AABB is hit by the ray between min_dist and
+// max_dist, together with the lambda at which the ray enters each AABB.
+fn intersect_children<T: HasAABB + Debug + Clone>(
+    node: &BvhNode<T>,
+    ray: &BoxTestRay,
+    min_dist: f64,
+    max_dist: f64,
+) -> ([u64; 4], Simd4) {
+    // These two equations describe all lambda for which the ray is inside an AABB:
+    //     aabb_min <= ray_origin + lambda * ray
+    //     ray_origin + lambda * ray <= aabb_max
+    // This can be rearranged to (rax > 0)
+    //     (aabb_min.x - ray_origin.x) / ray.x <= lambda
+    //     (aabb_min.y - ray_origin.y) / ray.y <= lambda
+    //     (aabb_min.z - ray_origin.z) / ray.z <= lambda
+    //     lambda <= (aabb_max.x - ray_origin.x) / ray.x
+    //     lambda <= (aabb_max.y - ray_origin.y) / ray.y
+    //     lambda <= (aabb_max.y - ray_origin.y) / ray.y
+    // (rax < 0)
+    //     (aabb_min.x - ray_origin.x) / ray.x >= lambda
+    //     (aabb_min.y - ray_origin.y) / ray.y >= lambda
+    //     (aabb_min.z - ray_origin.z) / ray.z >= lambda
+    //     lambda >= (aabb_max.x - ray_origin.x) / ray.x
+    //     lambda >= (aabb_max.y - ray_origin.y) / ray.y
+    //     lambda >= (aabb_max.y - ray_origin.y) / ray.y
+    // (ray = 0)
+    //     aabb_min.x - ray_origin.x <= 0
+    //     aabb_min.y - ray_origin.y <= 0
+    //     aabb_min.z - ray_origin.z <= 0
+    //     aabb_max.x - ray_origin.x >= 0
+    //     aabb_max.y - ray_origin.y >= 0
+    //     aabb_max.z - ray_origin.z >= 0
+    // Instead of branching on the sign of the ray, a and b are swapped with a blend which uses the
+    // sign bit of the reciprocal as its mask.
+    //
+    // The ray = 0 case falls out of the same code: the reciprocal is +-infinity, so a and b are
+    // infinities of opposite sign if the origin is inside the slab (no constraint) and of the same
+    // sign if it is outside (no hit). If the origin lies exactly on a face, 0 * infinity gives NaN.
+    // _mm256_max_pd and _mm256_min_pd return their second operand if either one is NaN, so the
+    // accumulated lambda is passed second and a NaN leaves it unchanged.
+    unsafe {
+        let mut lambda_min = _mm256_broadcast_sd(&f64::NEG_INFINITY);
+        let mut lambda_max = _mm256_broadcast_sd(&f64::INFINITY);
+
+        // X
+        let a = _mm256_mul_pd(
+            _mm256_sub_pd(_mm256_load_pd(node.aabb_min_x().as_ptr()), ray.origin_x),
+            ray.inv_x,
+        );
+        let b = _mm256_mul_pd(
+            _mm256_sub_pd(_mm256_load_pd(node.aabb_max_x().as_ptr()), ray.origin_x),
+            ray.inv_x,
+        );
+        lambda_min = _mm256_max_pd(_mm256_blendv_pd(a, b, ray.inv_x), lambda_min);
+        lambda_max = _mm256_min_pd(_mm256_blendv_pd(b, a, ray.inv_x), lambda_max);
+
+        // Y
+        let a = _mm256_mul_pd(
+            _mm256_sub_pd(_mm256_load_pd(node.aabb_min_y().as_ptr()), ray.origin_y),
+            ray.inv_y,
+        );
+        let b = _mm256_mul_pd(
+            _mm256_sub_pd(_mm256_load_pd(node.aabb_max_y().as_ptr()), ray.origin_y),
+            ray.inv_y,
+        );
+        lambda_min = _mm256_max_pd(_mm256_blendv_pd(a, b, ray.inv_y), lambda_min);
+        lambda_max = _mm256_min_pd(_mm256_blendv_pd(b, a, ray.inv_y), lambda_max);
+
+        // Z
+        let a = _mm256_mul_pd(
+            _mm256_sub_pd(_mm256_load_pd(node.aabb_min_z().as_ptr()), ray.origin_z),
+            ray.inv_z,
+        );
+        let b = _mm256_mul_pd(
+            _mm256_sub_pd(_mm256_load_pd(node.aabb_max_z().as_ptr()), ray.origin_z),
+            ray.inv_z,
+        );
+        lambda_min = _mm256_max_pd(_mm256_blendv_pd(a, b, ray.inv_z), lambda_min);
+        lambda_max = _mm256_min_pd(_mm256_blendv_pd(b, a, ray.inv_z), lambda_max);
+
+        let lambda_check = _mm256_castpd_si256(_mm256_cmp_pd(lambda_max, lambda_min, _CMP_LT_OQ));
+        let lambda_min_check = _mm256_castpd_si256(_mm256_cmp_pd(
+            lambda_min,
+            _mm256_broadcast_sd(&max_dist),
+            _CMP_GT_OQ,
+        ));
+        let lambda_max_check = _mm256_castpd_si256(_mm256_cmp_pd(
+            lambda_max,
+            _mm256_broadcast_sd(&min_dist),
+            _CMP_LT_OQ,
+        ));
+        let pred =
+            _mm256_or_si256(lambda_check, _mm256_or_si256(lambda_min_check, lambda_max_check));
+
+        let mut result = [0u64; 4];
+        _mm256_storeu_si256(result.as_mut_ptr() as *mut __m256i, pred);
+        let mut entry = Simd4([0.0; 4]);
+        _mm256_store_pd(entry.0.as_mut_ptr(), lambda_min);
+        (result, entry)
+    }
+}
+
+fn find_containing_rec<'a, T: HasAABB + Debug + Clone, F: FnMut(&'a T)>(
+    bvh: BvhNode<'a, T>,
+    point: Vec3,
+    f: &mut F,
+) {
+    for i in 0..4 {
+        let (aabb_min, aabb_max) = bvh.bvh.nodes[bvh.index].get_aabb(i);
+        if point.min(aabb_min) != aabb_min || point.max(aabb_max) != aabb_max {
+            continue;
+        }
+        match bvh.value(i) {
+            BvhChild::Empty => {}
+            BvhChild::Subtree(sub_bvh) => find_containing_rec(sub_bvh, point, f),
+            BvhChild::Value(_, value) => f(value),
+        }
     }
 }
 
@@ -227,77 +633,258 @@ fn calc_metric((a_min, a_max): (Vec3, Vec3), (b_min, b_max): (Vec3, Vec3)) -> f6
     v.x() * v.y() + v.x() * v.z() + v.y() * v.z()
 }
 
-fn sort_by_metric<T: HasAABB + Debug + Clone>(nodes: &mut [Node<T>], from: usize, to: usize) {
-    for slot in from..to {
-        let mut current_aabb = nodes[slot].get_aabb(0);
-
-        for neighbour in 1..4 {
-            let mut min_metric = std::f64::INFINITY;
+// Splits the values into groups of four. Each group starts with the first remaining value and is
+// filled up with the values which grow its AABB the least.
+fn group_by_metric(mut values: Vec<((Vec3, Vec3), Value)>) -> Vec<Vec<((Vec3, Vec3), Value)>> {
+    let mut groups = Vec::with_capacity(values.len().div_ceil(4));
+    while !values.is_empty() {
+        let first = values.remove(0);
+        let mut group_aabb = first.0;
+        let mut group = vec![first];
+        while group.len() < 4 && !values.is_empty() {
+            let mut min_metric = f64::INFINITY;
             let mut min_i = 0;
-            let mut min_j = 0;
-            for (i, node) in nodes[slot..to].iter().enumerate() {
-                for j in 0..4 {
-                    if i == 0 && j < neighbour {
-                        continue;
-                    }
-                    if node.value[j].is_empty() {
-                        assert!(i + slot == to - 1);
-                        continue;
-                    }
-                    let candidate_aabb = node.get_aabb(j);
-                    let metric = calc_metric(current_aabb, candidate_aabb);
-                    if metric < min_metric {
-                        min_metric = metric;
-                        min_i = i + slot;
-                        min_j = j;
-                    }
+            for (i, (aabb, _)) in values.iter().enumerate() {
+                let metric = calc_metric(group_aabb, *aabb);
+                if metric < min_metric {
+                    min_metric = metric;
+                    min_i = i;
                 }
             }
+            let value = values.remove(min_i);
+            group_aabb = (group_aabb.0.min((value.0).0), group_aabb.1.max((value.0).1));
+            group.push(value);
+        }
+        groups.push(group);
+    }
+    groups
+}
 
-            if min_metric.is_finite() {
-                current_aabb.0 = current_aabb.0.min(nodes[min_i].get_aabb(min_j).0);
-                current_aabb.1 = current_aabb.1.max(nodes[min_i].get_aabb(min_j).1);
-
-                swap_tree_rec(nodes, slot * 4 + neighbour + 1, min_i * 4 + min_j + 1);
-                if slot == min_i {
-                    let node = &mut nodes[slot];
-                    node.aabb_min_x.0.swap(neighbour, min_j);
-                    node.aabb_min_y.0.swap(neighbour, min_j);
-                    node.aabb_min_z.0.swap(neighbour, min_j);
-                    node.aabb_max_x.0.swap(neighbour, min_j);
-                    node.aabb_max_y.0.swap(neighbour, min_j);
-                    node.aabb_max_z.0.swap(neighbour, min_j);
-                    node.value.swap(neighbour, min_j);
-                } else {
-                    let (left, right) = nodes.split_at_mut(min_i);
-                    let node_a = &mut left[slot];
-                    let node_b = &mut right[0];
-                    std::mem::swap(
-                        &mut node_a.aabb_min_x[neighbour],
-                        &mut node_b.aabb_min_x[min_j],
-                    );
-                    std::mem::swap(
-                        &mut node_a.aabb_min_y[neighbour],
-                        &mut node_b.aabb_min_y[min_j],
-                    );
-                    std::mem::swap(
-                        &mut node_a.aabb_min_z[neighbour],
-                        &mut node_b.aabb_min_z[min_j],
-                    );
-                    std::mem::swap(
-                        &mut node_a.aabb_max_x[neighbour],
-                        &mut node_b.aabb_max_x[min_j],
-                    );
-                    std::mem::swap(
-                        &mut node_a.aabb_max_y[neighbour],
-                        &mut node_b.aabb_max_y[min_j],
-                    );
-                    std::mem::swap(
-                        &mut node_a.aabb_max_z[neighbour],
-                        &mut node_b.aabb_max_z[min_j],
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+
+    #[derive(Debug, Clone)]
+    struct Aabb(Vec3, Vec3);
+
+    impl HasAABB for Aabb {
+        fn calculate_aabb(&self) -> (Vec3, Vec3) {
+            (self.0, self.1)
+        }
+    }
+
+    // The slab test with a branch on the sign of each component of the ray, which the blends of
+    // intersect_children replace. Returns the entry lambda if the AABB is hit.
+    fn reference_box_test(
+        aabb: &Aabb,
+        ray_origin: Vec3,
+        ray: Vec3,
+        min_dist: f64,
+        max_dist: f64,
+    ) -> Option<f64> {
+        let (mut lambda_min, mut lambda_max) = (f64::NEG_INFINITY, f64::INFINITY);
+        for axis in 0..3 {
+            let inv = 1.0 / ray.0[axis];
+            let a = (aabb.0 .0[axis] - ray_origin.0[axis]) * inv;
+            let b = (aabb.1 .0[axis] - ray_origin.0[axis]) * inv;
+            if ray.0[axis] > 0.0 {
+                lambda_min = a.max(lambda_min);
+                lambda_max = b.min(lambda_max);
+            } else if ray.0[axis] < 0.0 {
+                lambda_min = b.max(lambda_min);
+                lambda_max = a.min(lambda_max);
+            } else if aabb.0 .0[axis] > ray_origin.0[axis] || ray_origin.0[axis] > aabb.1 .0[axis] {
+                return None;
+            }
+        }
+        if lambda_max < lambda_min || lambda_min > max_dist || lambda_max < min_dist {
+            None
+        } else {
+            Some(lambda_min)
+        }
+    }
+
+    // Checks intersect_children against reference_box_test for all children of the root and
+    // returns the entry lambdas of the hit values
+    fn check_box_test(
+        bvh: &Bvh<Aabb>,
+        ray_origin: Vec3,
+        ray: Vec3,
+        min_dist: f64,
+        max_dist: f64,
+    ) -> Vec<f64> {
+        let root = bvh.root();
+        let (hits, entries) =
+            intersect_children(&root, &BoxTestRay::new(ray_origin, ray), min_dist, max_dist);
+        let mut result = Vec::new();
+        for i in 0..4 {
+            if let BvhChild::Value(_, aabb) = root.value(i) {
+                let expected = reference_box_test(aabb, ray_origin, ray, min_dist, max_dist);
+                let hit = if hits[i] == 0 { Some(entries[i]) } else { None };
+                result.extend(hit);
+                // Bit for bit, so the blends change nothing about the order of the traversal
+                assert_eq!(
+                    hit.map(f64::to_bits),
+                    expected.map(f64::to_bits),
+                    "{:?} along {:?} from {:?}",
+                    aabb,
+                    ray,
+                    ray_origin
+                );
+            }
+        }
+        result
+    }
+
+    // Checks intersect_child_packet against reference_box_test for all children of the root
+    fn check_packet_box_test(
+        bvh: &Bvh<Aabb>,
+        ray_origins: [Vec3; 4],
+        rays: [Vec3; 4],
+        min_dist: f64,
+        max_dist: f64,
+    ) {
+        let root = bvh.root();
+        let packet = RayPacket::new(ray_origins, rays);
+        for i in 0..4 {
+            if let BvhChild::Value(_, aabb) = root.value(i) {
+                let expected = (0..4).any(|lane| {
+                    reference_box_test(aabb, ray_origins[lane], rays[lane], min_dist, max_dist)
+                        .is_some()
+                });
+                assert_eq!(
+                    intersect_child_packet(&root, i, &packet, min_dist, &[max_dist; 4]),
+                    expected,
+                    "{:?} along {:?} from {:?}",
+                    aabb,
+                    rays,
+                    ray_origins
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn box_test_matches_branching_slab_test() {
+        let mut rng = rand_pcg::Pcg32::seed_from_u64(1);
+        let mut random_vec3 = |min: f64, max: f64| {
+            Vec3([rng.gen_range(min, max), rng.gen_range(min, max), rng.gen_range(min, max)])
+        };
+        for _ in 0..1000 {
+            let aabbs: Vec<_> = (0..4)
+                .map(|_| {
+                    let corner = random_vec3(-2.0, 2.0);
+                    Aabb(corner, corner + random_vec3(0.0, 2.0))
+                })
+                .collect();
+            let bvh = Bvh::new(&aabbs);
+            for _ in 0..10 {
+                let ray_origins = [(); 4].map(|_| random_vec3(-4.0, 4.0));
+                let rays = [(); 4].map(|_| random_vec3(-1.0, 1.0));
+                check_box_test(&bvh, ray_origins[0], rays[0], 0.0, f64::INFINITY);
+                check_box_test(&bvh, ray_origins[0], rays[0], 1.0, 3.0);
+                check_packet_box_test(&bvh, ray_origins, rays, 0.0, f64::INFINITY);
+                check_packet_box_test(&bvh, ray_origins, rays, 1.0, 3.0);
+            }
+        }
+    }
+
+    #[test]
+    fn box_test_handles_axis_aligned_rays() {
+        let bvh = Bvh::new(&[Aabb(Vec3([0.0, 0.0, 0.0]), Vec3([1.0, 1.0, 1.0]))]);
+        let cases = [
+            // Through the middle of a face
+            ((-1.0, 0.5, 0.5), (1.0, 0.0, 0.0), Some(1.0)),
+            // Grazing a face, an edge and the opposite edge
+            ((-1.0, 0.0, 0.5), (1.0, 0.0, 0.0), Some(1.0)),
+            ((-1.0, 0.0, 0.0), (1.0, 0.0, 0.0), Some(1.0)),
+            ((-1.0, 1.0, 1.0), (1.0, 0.0, 0.0), Some(1.0)),
+            // Just outside the slabs of the other axes
+            ((-1.0, 1.5, 0.5), (1.0, 0.0, 0.0), None),
+            ((-1.0, -1e-9, 0.0), (1.0, 0.0, 0.0), None),
+            // Negative zeros have a reciprocal of negative infinity
+            ((2.0, 0.0, 1.0), (-1.0, -0.0, 0.0), Some(1.0)),
+            ((2.0, 0.0, -1e-9), (-1.0, -0.0, -0.0), None),
+            // Starting on a face or an edge
+            ((0.5, 0.5, 0.0), (0.0, 0.0, 1.0), Some(0.0)),
+            ((0.5, 0.0, 0.0), (0.0, 0.0, -1.0), Some(-1.0)),
+            ((1.0, 0.5, 0.5), (0.0, 1.0, 0.0), Some(-0.5)),
+        ];
+        for &((ox, oy, oz), (x, y, z), expected) in &cases {
+            let (ray_origin, ray) = (Vec3([ox, oy, oz]), Vec3([x, y, z]));
+            let hit = check_box_test(&bvh, ray_origin, ray, 0.0, f64::INFINITY);
+            assert_eq!(hit.first().copied(), expected, "along {:?} from {:?}", ray, ray_origin);
+            check_packet_box_test(&bvh, [ray_origin; 4], [ray; 4], 0.0, f64::INFINITY);
+        }
+    }
+
+    #[test]
+    fn traversals_match_brute_force() {
+        let mut rng = rand_pcg::Pcg32::seed_from_u64(2);
+        let mut random_vec3 = |min: f64, max: f64| {
+            Vec3([rng.gen_range(min, max), rng.gen_range(min, max), rng.gen_range(min, max)])
+        };
+        for count in (0..=40).chain(vec![100, 333, 1000]) {
+            let aabbs: Vec<_> = (0..count)
+                .map(|_| {
+                    let corner = random_vec3(-10.0, 10.0);
+                    Aabb(corner, corner + random_vec3(0.0, 3.0))
+                })
+                .collect();
+            let bvh = Bvh::new(&aabbs);
+            // Every object is kept once and only nodes holding values are allocated
+            assert_eq!(bvh.values().len(), count);
+            assert!(aabbs.iter().all(|a| bvh.values().iter().any(|b| a.0 == b.0 && a.1 == b.1)));
+            let (allocated, used) = bvh.node_counts();
+            assert!(allocated == used || count == 0, "{} of {} nodes used", used, allocated);
+
+            let index_of = |value: &Aabb| bvh.values().iter().position(|v| std::ptr::eq(v, value));
+            let mut stack = TraversalStack::with_capacity(bvh.traversal_stack_size());
+            for _ in 0..20 {
+                let ray_origins = [(); 4].map(|_| random_vec3(-15.0, 15.0));
+                let rays = [(); 4].map(|_| random_vec3(-1.0, 1.0));
+                for &(min_dist, max_dist) in &[(0.0, f64::INFINITY), (1.0, 5.0)] {
+                    let expected = |lanes: &[usize]| -> Vec<usize> {
+                        (0..count)
+                            .filter(|&i| {
+                                lanes.iter().any(|&lane| {
+                                    let (origin, ray) = (ray_origins[lane], rays[lane]);
+                                    let aabb = &bvh.values()[i];
+                                    reference_box_test(aabb, origin, ray, min_dist, max_dist)
+                                        .is_some()
+                                })
+                            })
+                            .collect()
+                    };
+
+                    let traversal =
+                        bvh.traverse(ray_origins[0], rays[0], min_dist, max_dist, &mut stack);
+                    let mut found: Vec<_> = traversal.map(|(index, _)| index).collect();
+                    found.sort();
+                    assert_eq!(found, expected(&[0]));
+
+                    let mut found = vec![];
+                    bvh.traverse_packet(
+                        &RayPacket::new(ray_origins, rays),
+                        min_dist,
+                        &mut [max_dist; 4],
+                        &mut stack,
+                        &mut |index, _, _| found.push(index),
                     );
-                    std::mem::swap(&mut node_a.value[neighbour], &mut node_b.value[min_j]);
+                    found.sort();
+                    assert_eq!(found, expected(&[0, 1, 2, 3]));
                 }
+
+                let point = random_vec3(-10.0, 10.0);
+                let mut found = vec![];
+                bvh.find_containing(point, &mut |value| found.extend(index_of(value)));
+                found.sort();
+                let contained =
+                    |aabb: &Aabb| point.min(aabb.0) == aabb.0 && point.max(aabb.1) == aabb.1;
+                let expected: Vec<_> =
+                    (0..count).filter(|&i| contained(&bvh.values()[i])).collect();
+                assert_eq!(found, expected);
             }
         }
     }
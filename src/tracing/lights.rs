@@ -0,0 +1,26 @@
+use crate::math::{HasAABB, Vec3};
+use crate::scene::PointLight;
+
+// A light together with the distance up to which it contributes noticeably to the scene.
+#[derive(Debug, Clone)]
+pub struct LightReach {
+    pub light: PointLight,
+    reach: f64,
+}
+
+impl LightReach {
+    // The light is attenuated by 1 / (1 + d²), so it falls below `cutoff` at
+    //     d = sqrt(intensity / cutoff - 1)
+    pub fn new(light: PointLight, cutoff: f64) -> LightReach {
+        let intensity = (light.color.x() + light.color.y() + light.color.z()) / 3.0;
+        let reach = (intensity / cutoff - 1.0).max(0.0).sqrt();
+        LightReach { light, reach }
+    }
+}
+
+impl HasAABB for LightReach {
+    fn calculate_aabb(&self) -> (Vec3, Vec3) {
+        let extent = Vec3([self.light.radius + self.reach; 3]);
+        (self.light.position - extent, self.light.position + extent)
+    }
+}
@@ -1,157 +1,844 @@
 use crate::math::{Vec3, Vec4};
-use crate::scene::{Geometry, Scene};
+use crate::scene::{Camera, Geometry, Scene};
 use bvh::Bvh;
-use crossbeam_channel::Sender;
-use rand::SeedableRng;
-use rendering::render_subpixel;
-use std::cmp::Ordering;
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender};
+use lights::LightReach;
+use rand::Rng;
+use raytracer::RayStats;
+use rendering::{primary_hit, render_subpixel, render_subpixel_packet, SampleContext};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::str::FromStr;
 use std::sync::atomic::AtomicBool;
-use std::sync::{atomic, Arc};
+use std::sync::{atomic, Arc, Mutex};
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 mod bvh;
+mod lights;
 mod raytracer;
 mod rendering;
+mod sampling;
+mod scene_bvh;
 
-pub fn main(
-    scene: Arc<Scene>,
-    antialiasing: u32,
+// Stops a render when cancelled. Clones share the same flag, so whoever holds one, e.g. the GUI
+// or a timer, can stop the render from any thread.
+#[derive(Debug, Clone, Default)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    pub fn new() -> Cancel {
+        Cancel::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, atomic::Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(atomic::Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RenderSettings {
+    // Number of shadow rays shot towards each light that has a radius
+    pub shadow_samples: u32,
+    // Treat every light as a point, shooting a single shadow ray towards its center
+    pub hard_shadows: bool,
+    // Number of lights picked by importance per shading point, 0 means all lights are used
+    pub light_samples: u32,
+    // Lights are ignored where their attenuated intensity falls below this, 0 disables the cutoff
+    pub light_cutoff: f64,
+    // Trace the primary rays of each RGSS group as one packet
+    pub packet_tracing: bool,
+    // Where in its cell each subpixel is sampled
+    pub aa_pattern: AaPattern,
+    // Replaces the shading of primary rays by a visualization of the hit
+    pub debug: Option<DebugMode>,
+    // Count the samples that are not finite or black by the material they hit and log the worst
+    pub report_bad_samples: bool,
+    // Each pixel gets 4^antialiasing samples
+    pub antialiasing: u32,
+    // Renders an image which is this many times larger along each side and averages it down
+    pub supersample: usize,
+    // Start with a pass of one sample per pixel and hard shadows before the full pass
+    pub preview: bool,
+    // Debug modes rendered alongside the full passes, in the order their messages are indexed by
+    pub aovs: Vec<DebugMode>,
+    // Seed of the random number generators of the workers
+    pub seed: u128,
+    // Number of workers rendering in parallel
+    pub thread_count: usize,
+}
+
+// What the renderer sends to the GUI and the output image
+#[derive(Debug, Copy, Clone)]
+pub enum RenderMessage {
+    // A sample for the pixel (x, y), whose color is weighted by its w component. The flag is
+    // false if the primary ray missed the scene.
+    Pixel(usize, usize, Vec4, bool),
+    // A quick sample for the pixel (x, y) which is replaced by the first real one
+    Preview(usize, usize, Vec4),
+    // A sample like Pixel for the pixel (x, y) of the AOV with the given index
+    Aov(usize, usize, usize, Vec4, bool),
+    // The camera moved, so all samples received so far are stale
+    Restart,
+}
+
+// Counters collected over a whole render, meant for benchmarking
+#[derive(Debug, Serialize)]
+pub struct RenderStats {
+    pub primary_rays: u64,
+    pub reflection_rays: u64,
+    pub shadow_rays: u64,
+    pub hits: u64,
+    // Fraction of all rays which hit something
+    pub hit_rate: f64,
+    // Subpixels which were rendered in all frames, the last one may have been stopped early
+    pub samples: u64,
+    pub bvh_ms: u64,
+    pub render_ms: u64,
+    pub rays_per_second: f64,
+    // Time each worker spent rendering, summed over all passes
+    pub worker_busy_ms: Vec<u64>,
+    // The longest any single work item took
+    pub slowest_item_us: u64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AaPattern {
+    // Rotated grid supersampling, fixed offsets within each 2x2 group of subpixels
+    Rgss,
+    // A random position within each subpixel, drawn from the subpixel's random numbers
+    Jitter,
+}
+
+impl FromStr for AaPattern {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<AaPattern, String> {
+        match s {
+            "rgss" => Ok(AaPattern::Rgss),
+            "jitter" => Ok(AaPattern::Jitter),
+            _ => Err(format!("Unknown antialiasing pattern {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DebugMode {
+    // The world space normal mapped from [-1, 1] to [0, 1]
+    Normals,
+    // The barycentric coordinates as RGB
+    Barycentric,
+    // The world space tangent, the direction of growing u, mapped from [-1, 1] to [0, 1]
+    Tangents,
+    // The distance to the hit relative to the size of the scene, darker is farther away
+    Depth,
+    // The number of BVH node and primitive tests of the primary ray, from blue (1) to red (1024)
+    Heat,
+    // The base color of the material, white for lights
+    Albedo,
+}
+
+impl FromStr for DebugMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<DebugMode, String> {
+        match s {
+            "normals" => Ok(DebugMode::Normals),
+            "bary" => Ok(DebugMode::Barycentric),
+            "tangents" => Ok(DebugMode::Tangents),
+            "depth" => Ok(DebugMode::Depth),
+            "heat" => Ok(DebugMode::Heat),
+            "albedo" => Ok(DebugMode::Albedo),
+            _ => Err(format!("Unknown debug mode {}", s)),
+        }
+    }
+}
+
+// What a render pass needs to know besides the scene
+#[derive(Debug, Clone)]
+struct Pass {
+    settings: RenderSettings,
+    // Preview samples are only shown until the first real sample of their pixel arrives
+    preview: bool,
+}
+
+impl Pass {
+    // Supersampling renders an image which is larger by the given factor. Each of its pixels is
+    // sent for the output pixel it lies in, so the receivers average them like subpixels, which
+    // amounts to a box filter.
+    fn sample_size(&self, w: usize, h: usize) -> (usize, usize) {
+        (w * self.settings.supersample, h * self.settings.supersample)
+    }
+
+    // Packets need the four subpixels of an RGSS group, so each group is a single work item then
+    fn packet_tracing(&self) -> bool {
+        self.settings.packet_tracing && self.settings.antialiasing > 0
+    }
+
+    // The work items of the pass, coarse to fine, so that the image is refined progressively.
+    fn positions(&self, w: usize, h: usize) -> Positions {
+        let antialiasing = self.settings.antialiasing;
+        let (w, h) = self.sample_size(w, h);
+        Positions::new(w << antialiasing, h << antialiasing, self.packet_tracing())
+    }
+
+    fn sample_count(&self, w: usize, h: usize) -> u64 {
+        let (w, h) = self.sample_size(w, h);
+        (w * h * 4usize.pow(self.settings.antialiasing)) as u64
+    }
+}
+
+// The subpixels of a w x h grid, ordered by the number of trailing zero bits of their coordinates
+// from most to fewest, then by x and then by y. The first ones form a coarse grid over the whole
+// image which the later ones refine. They are generated as the workers take them, as there can be
+// far too many to keep around.
+struct Positions {
     w: usize,
     h: usize,
-    thread_count: usize,
-    seed: u128,
-    want_quit: Arc<AtomicBool>,
-    pixel_sender: Sender<(usize, usize, Vec4)>,
-) {
-    let start_time = Instant::now();
-    let geometry = {
-        let mut geometry = vec![];
-        for triangle in &scene.triangles {
-            geometry.push(Geometry::Triangle(*triangle));
+    // The spacing of the grid whose points are returned next. Points of coarser grids were
+    // returned before.
+    step: usize,
+    // The spacing of the first grid, which only has the origin
+    max_step: usize,
+    // The spacing of the last grid. Packets only start at even coordinates, so they stop at 2.
+    min_step: usize,
+    // The next point to consider
+    x: usize,
+    y: usize,
+}
+
+impl Positions {
+    fn new(w: usize, h: usize, packet_tracing: bool) -> Positions {
+        let max_step = w.max(h).next_power_of_two();
+        let min_step = if packet_tracing { 2 } else { 1 };
+        Positions { w, h, step: max_step, max_step, min_step, x: 0, y: 0 }
+    }
+}
+
+impl Iterator for Positions {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        while self.step >= self.min_step {
+            if self.x >= self.w {
+                self.step /= 2;
+                self.x = 0;
+                self.y = 0;
+                continue;
+            }
+            if self.y >= self.h {
+                self.x += self.step;
+                self.y = 0;
+                continue;
+            }
+            let (x, y) = (self.x, self.y);
+            self.y += self.step;
+            let coarse_step = 2 * self.step;
+            if self.step < self.max_step && x % coarse_step == 0 && y % coarse_step == 0 {
+                continue;
+            }
+            return Some((x, y));
         }
-        for point_light in &scene.point_lights {
-            geometry.push(Geometry::PointLight(*point_light));
+        None
+    }
+}
+
+// The state shared by all passes of all frames
+struct Renderer {
+    scene: Arc<Scene>,
+    bvh: Arc<scene_bvh::SceneBvh>,
+    light_bvh: Option<Arc<Bvh<LightReach>>>,
+    w: usize,
+    h: usize,
+    cancel: Cancel,
+    camera_receiver: Receiver<Camera>,
+    pixel_sender: Sender<RenderMessage>,
+}
+
+// Samples whose color is not finite, or black although their primary ray hit something
+#[derive(Debug, Default)]
+struct BadSamples {
+    non_finite: u64,
+    black: u64,
+    // A pixel with one of these samples
+    example: (usize, usize),
+}
+
+impl BadSamples {
+    fn add(&mut self, other: &BadSamples) {
+        if self.non_finite + self.black == 0 {
+            self.example = other.example;
         }
-        geometry
+        self.non_finite += other.non_finite;
+        self.black += other.black;
+    }
+}
+
+// Bad samples by the material their primary ray hit, None for lights
+type BadSampleMap = BTreeMap<Option<usize>, BadSamples>;
+
+// The outcome of a pass
+struct PassResult {
+    ray_stats: RayStats,
+    samples: u64,
+    time: Duration,
+    // Indexed by worker
+    busy_times: Vec<Duration>,
+    slowest_item: Duration,
+    bad_samples: BadSampleMap,
+    // Set if the pass was stopped because the camera moved
+    new_camera: Option<Camera>,
+}
+
+// Builds the BVHs for the scene and its lights like a render would, but renders nothing.
+// Returns how long it took.
+pub fn build_only(scene: &Scene, settings: &RenderSettings) -> Duration {
+    build_bvhs(scene, settings).2
+}
+
+fn build_bvhs(
+    scene: &Scene,
+    settings: &RenderSettings,
+) -> (scene_bvh::SceneBvh, Option<Bvh<LightReach>>, Duration) {
+    let start_time = Instant::now();
+    let bvh = scene_bvh::SceneBvh::new(scene);
+    let light_bvh = if settings.light_cutoff > 0.0 && !scene.point_lights.is_empty() {
+        let reaches: Vec<_> = scene
+            .point_lights
+            .iter()
+            .map(|point_light| LightReach::new(*point_light, settings.light_cutoff))
+            .collect();
+        Some(Bvh::new(&reaches))
+    } else {
+        None
     };
-    let bvh = Arc::new(Bvh::new(&geometry));
-    eprintln!("Building BVH: {} ms", (Instant::now() - start_time).as_millis());
-
-    let (render_sender, render_receiver) = crossbeam_channel::unbounded();
-    {
-        let mut positions = vec![];
-        for x in 0..w {
-            for y in 0..h {
-                for xaa in 0..2usize.pow(antialiasing) {
-                    for yaa in 0..2usize.pow(antialiasing) {
-                        positions.push(((x << antialiasing) + xaa, (y << antialiasing) + yaa));
-                    }
-                }
+    let bvh_time = Instant::now() - start_time;
+    log_info!("Building BVH: {} ms", bvh_time.as_millis());
+    bvh.log_stats();
+    (bvh, light_bvh, bvh_time)
+}
+
+pub fn main(
+    scene: Arc<Scene>,
+    settings: RenderSettings,
+    w: usize,
+    h: usize,
+    cancel: Cancel,
+    camera_receiver: Receiver<Camera>,
+    pixel_sender: Sender<RenderMessage>,
+) -> RenderStats {
+    let RenderSettings { antialiasing, supersample, thread_count, .. } = settings.clone();
+    // Without workers nobody would ever render a pixel
+    assert!(thread_count > 0);
+
+    let (bvh, light_bvh, bvh_time) = build_bvhs(&scene, &settings);
+    let (bvh, light_bvh) = (Arc::new(bvh), light_bvh.map(Arc::new));
+    log_verbose!(
+        "Rendering {}x{} pixels with {} samples each on {} threads",
+        w,
+        h,
+        4usize.pow(antialiasing) * supersample * supersample,
+        thread_count
+    );
+
+    // One sample per pixel and hard shadows. Previews are only shown, so they skip the AOVs.
+    let preview_pass = Pass {
+        settings: RenderSettings {
+            hard_shadows: true,
+            packet_tracing: false,
+            antialiasing: 0,
+            supersample: 1,
+            aovs: vec![],
+            ..settings.clone()
+        },
+        preview: true,
+    };
+    let full_pass = Pass { settings: settings.clone(), preview: false };
+    let passes = if settings.preview { vec![preview_pass, full_pass] } else { vec![full_pass] };
+
+    let mut camera = scene.camera;
+    let renderer = Renderer { scene, bvh, light_bvh, w, h, cancel, camera_receiver, pixel_sender };
+    let mut ray_stats = RayStats::default();
+    let mut samples = 0;
+    let mut render_time = Duration::from_secs(0);
+    let mut busy_times = vec![Duration::from_secs(0); thread_count];
+    let mut slowest_item = Duration::from_secs(0);
+    let mut bad_samples = BadSampleMap::new();
+    // Each frame renders the whole image with one camera. A new frame is started whenever the
+    // GUI moves the camera.
+    'frames: loop {
+        for pass in &passes {
+            let result = renderer.render_pass(camera, pass);
+            ray_stats.add(&result.ray_stats);
+            samples += result.samples;
+            render_time += result.time;
+            for (busy_time, pass_busy_time) in busy_times.iter_mut().zip(&result.busy_times) {
+                *busy_time += *pass_busy_time;
             }
-        }
-        positions.sort_by(|a, b| {
-            let a_zeros = a.0.trailing_zeros().min(a.1.trailing_zeros());
-            let b_zeros = b.0.trailing_zeros().min(b.1.trailing_zeros());
-            if a_zeros > b_zeros {
-                Ordering::Less
-            } else if a_zeros < b_zeros {
-                Ordering::Greater
-            } else if a.0 < b.0 {
-                Ordering::Less
-            } else if a.0 > b.0 {
-                Ordering::Greater
-            } else if a.1 < b.1 {
-                Ordering::Less
-            } else if a.1 > b.1 {
-                Ordering::Greater
+            slowest_item = slowest_item.max(result.slowest_item);
+            for (material, pass_bad_samples) in &result.bad_samples {
+                bad_samples.entry(*material).or_default().add(pass_bad_samples);
+            }
+            if !pass.preview {
+                log_info!("Raytracing: {} ms", result.time.as_millis());
+            }
+
+            let moved_camera = if result.new_camera.is_some() {
+                result.new_camera
+            } else if result.samples < pass.sample_count(w, h) {
+                log_warning!(
+                    "Rendering was stopped after {} of {} samples.",
+                    result.samples,
+                    pass.sample_count(w, h)
+                );
+                None
+            } else if pass.preview {
+                continue;
             } else {
-                Ordering::Equal
+                // Keep the image until the camera moves or the GUI is closed
+                wait_for_camera(&renderer.camera_receiver, &renderer.cancel)
+            };
+            match moved_camera {
+                Some(moved_camera) => camera = moved_camera,
+                None => break 'frames,
             }
-        });
-        assert_eq!(positions.len(), w * h * 4usize.pow(antialiasing));
-        for p in positions {
-            render_sender.send(p).unwrap();
+            // All pixels of the old frame are sent by now, so the receivers can drop them
+            renderer.pixel_sender.send(RenderMessage::Restart).unwrap();
+            continue 'frames;
         }
     }
 
-    let start_time = Instant::now();
-    let mut worker_threads = Vec::with_capacity(thread_count);
-    for t in 0..thread_count {
-        let scene = Arc::clone(&scene);
-        let bvh = Arc::clone(&bvh);
-        let want_quit = Arc::clone(&want_quit);
-        let render_receiver = render_receiver.clone();
-        let pixel_sender = pixel_sender.clone();
-        let worker_thread = thread::Builder::new()
-            .name(format!("Worker {}", t + 1))
-            .spawn(move || {
-                let mut rng = rand_pcg::Pcg32::from_seed(
-                    seed.overflowing_mul(t as u128 + 123).0.to_be_bytes(),
-                );
-                let mut ray_tracer = raytracer::RayTracer::new(&bvh);
+    if settings.report_bad_samples {
+        log_bad_samples(&bad_samples);
+    }
 
-                while let Ok((my_x, my_y)) = render_receiver.try_recv() {
-                    if want_quit.load(atomic::Ordering::Relaxed) {
-                        break;
-                    }
+    let rays = ray_stats.rays();
+    RenderStats {
+        primary_rays: ray_stats.primary_rays,
+        reflection_rays: ray_stats.reflection_rays,
+        shadow_rays: ray_stats.shadow_rays,
+        hits: ray_stats.hits,
+        hit_rate: if rays > 0 { ray_stats.hits as f64 / rays as f64 } else { 0.0 },
+        samples,
+        bvh_ms: bvh_time.as_millis() as u64,
+        render_ms: render_time.as_millis() as u64,
+        rays_per_second: rays as f64 * 1e6 / render_time.as_micros().max(1) as f64,
+        worker_busy_ms: busy_times.iter().map(|time| time.as_millis() as u64).collect(),
+        slowest_item_us: slowest_item.as_micros() as u64,
+    }
+}
 
-                    let (render_x, render_y) = if antialiasing == 0 {
-                        // Use pixel center
-                        (my_x as f64 + 0.5, my_y as f64 + 0.5)
-                    } else {
-                        // Use RGSS around the second-to-last (!!!) subpixel center
-
-                        // First find the subpixel center
-                        // pixel_left + subpixel_index * subpixel_size + subpixel_size / 2
-                        // Hint: For x = 1 and aa = 1 this leads to 0.75.
-                        //       For x = 0 and aa = 1 this leads to 0.25.
-                        //       For x = 0 and aa = 2 this leads to 0.125.
-                        //       For x = 1 and aa = 2 this leads to 0.25.
-                        let subpixel_size = 1.0 / f64::from(1 << antialiasing);
-                        let rgss_center_x = (my_x >> antialiasing) as f64
-                            + (my_x & ((1 << antialiasing) - 1)) as f64 * subpixel_size
-                            + subpixel_size / 2.0;
-                        let rgss_center_y = (my_y >> antialiasing) as f64
-                            + (my_y & ((1 << antialiasing) - 1)) as f64 * subpixel_size
-                            + subpixel_size / 2.0;
-
-                        // Pick one offset for each of the four remaining subpixels. Note that these
-                        // offsets are relative to the subpixel center, *not* relative to the
-                        // second-to-last subpixel center.
-                        let (rgss_offset_x, rgss_offset_y) = [
-                            (-1.0 / 8.0, 1.0 / 8.0),  // x % 2 == 0 && y % 2 == 0  =>  top-left
-                            (-1.0 / 8.0, -1.0 / 8.0), // x % 2 == 1 && y % 2 == 0  =>  top-right
-                            (1.0 / 8.0, 1.0 / 8.0),   // x % 2 == 0 && y % 2 == 1  =>  bottom-left
-                            (1.0 / 8.0, -1.0 / 8.0),  // x % 2 == 1 && y % 2 == 1  =>  bottom-right
-                        ][(my_x % 2) + 2 * (my_y % 2)];
-
-                        // Divide the offsets to the correct subpixel size
-                        let rgss_offset_x = rgss_offset_x / f64::from(1 << (antialiasing - 1));
-                        let rgss_offset_y = rgss_offset_y / f64::from(1 << (antialiasing - 1));
-
-                        (rgss_center_x + rgss_offset_x, rgss_center_y + rgss_offset_y)
+impl Renderer {
+    // Renders the work items of a pass with all workers. Stops early if the render is cancelled or
+    // the GUI moves the camera.
+    fn render_pass(&self, camera: Camera, pass: &Pass) -> PassResult {
+        let positions = Arc::new(Mutex::new(pass.positions(self.w, self.h)));
+        // Set when the camera moved, which makes the rest of the pass pointless
+        let restart = Arc::new(AtomicBool::new(false));
+        // Disconnected as soon as all workers are done
+        let (done_sender, done_receiver) = crossbeam_channel::bounded::<()>(0);
+
+        let preview = pass.preview;
+        let RenderSettings { antialiasing, supersample, seed, thread_count, .. } = pass.settings;
+        let packet_tracing = pass.packet_tracing();
+        let (w, h) = pass.sample_size(self.w, self.h);
+
+        let start_time = Instant::now();
+        let mut worker_threads = Vec::with_capacity(thread_count);
+        for t in 0..thread_count {
+            let scene = Arc::clone(&self.scene);
+            let bvh = Arc::clone(&self.bvh);
+            let light_bvh = self.light_bvh.as_ref().map(Arc::clone);
+            let cancel = self.cancel.clone();
+            let restart = Arc::clone(&restart);
+            let done_sender = done_sender.clone();
+            let positions = Arc::clone(&positions);
+            let pixel_sender = self.pixel_sender.clone();
+            let settings = pass.settings.clone();
+            let worker_thread = thread::Builder::new()
+                .name(format!("Worker {}", t + 1))
+                .spawn(move || {
+                    let ctx = SampleContext {
+                        scene: &scene,
+                        camera: &camera,
+                        width: w as f64,
+                        height: h as f64,
+                        settings: &settings,
+                    };
+                    let mut ray_tracer =
+                        raytracer::RayTracer::new(&bvh, light_bvh.as_deref(), &scene.point_lights);
+                    let mut samples = 0;
+                    let mut busy_time = Duration::from_secs(0);
+                    let mut slowest_item = Duration::from_secs(0);
+                    let mut bad_samples = BadSampleMap::new();
+                    // Debug colors are black on purpose and previews are only a rough guess
+                    let check_samples =
+                        settings.report_bad_samples && settings.debug.is_none() && !preview;
+                    let pixel = |x: usize, y: usize| {
+                        ((x >> antialiasing) / supersample, (y >> antialiasing) / supersample)
+                    };
+                    let send_aov = |aov: usize, x: usize, y: usize, color: Option<Vec3>| {
+                        let (x, y) = pixel(x, y);
+                        let hit = color.is_some();
+                        let color = color.unwrap_or(Vec3([0.0, 0.0, 0.0])).xyz1();
+                        pixel_sender.send(RenderMessage::Aov(aov, x, y, color, hit)).unwrap();
+                    };
+                    let send = |x: usize, y: usize, color: Option<Vec3>| {
+                        let (x, y) = pixel(x, y);
+                        let hit = color.is_some();
+                        let color = color.unwrap_or(Vec3([0.0, 0.0, 0.0])).xyz1();
+                        let message = if preview {
+                            RenderMessage::Preview(x, y, color)
+                        } else {
+                            RenderMessage::Pixel(x, y, color, hit)
+                        };
+                        pixel_sender.send(message).unwrap();
                     };
 
-                    let color = render_subpixel(
-                        &scene,
-                        &mut rng,
-                        render_x,
-                        render_y,
-                        w as f64,
-                        h as f64,
-                        &mut ray_tracer,
-                    );
-                    let color = color.unwrap_or(Vec3([0.0, 0.0, 0.0])).xyz1();
-
-                    pixel_sender.send((my_x >> antialiasing, my_y >> antialiasing, color)).unwrap();
+                    // Cancellation is only checked between work items, so every item taken from
+                    // the queue is rendered and sent before the worker exits.
+                    while !cancel.is_cancelled() && !restart.load(atomic::Ordering::Relaxed) {
+                        let position = positions.lock().unwrap().next();
+                        let (my_x, my_y) = match position {
+                            Some(position) => position,
+                            None => break,
+                        };
+                        let item_start = Instant::now();
+                        let rng = |x, y| subpixel_rng(seed, x, y, w << antialiasing);
+
+                        if packet_tracing {
+                            let subpixels = [
+                                (my_x, my_y),
+                                (my_x + 1, my_y),
+                                (my_x, my_y + 1),
+                                (my_x + 1, my_y + 1),
+                            ];
+                            // The same random numbers as if the subpixels were rendered one by one
+                            let mut rngs = [
+                                rng(my_x, my_y),
+                                rng(my_x + 1, my_y),
+                                rng(my_x, my_y + 1),
+                                rng(my_x + 1, my_y + 1),
+                            ];
+                            let mut positions = [(0.0, 0.0); 4];
+                            for ((position, (x, y)), rng) in
+                                positions.iter_mut().zip(subpixels.iter()).zip(rngs.iter_mut())
+                            {
+                                *position = subpixel_position(
+                                    *x,
+                                    *y,
+                                    antialiasing,
+                                    settings.aa_pattern,
+                                    rng,
+                                );
+                            }
+                            let (colors, aov_colors) =
+                                render_subpixel_packet(&ctx, &mut rngs, positions, &mut ray_tracer);
+                            for (aov, aov_colors) in aov_colors.iter().enumerate() {
+                                for ((x, y), color) in subpixels.iter().zip(aov_colors.iter()) {
+                                    send_aov(aov, *x, *y, *color);
+                                }
+                            }
+                            for (((x, y), color), (render_x, render_y)) in
+                                subpixels.iter().zip(colors.iter()).zip(positions.iter())
+                            {
+                                if check_samples {
+                                    check_sample(&mut bad_samples, *color, pixel(*x, *y), || {
+                                        primary_hit(&ctx, *render_x, *render_y, &mut ray_tracer)
+                                    });
+                                }
+                                send(*x, *y, *color);
+                            }
+                            samples += 4;
+                        } else {
+                            let mut rng = rng(my_x, my_y);
+                            let (render_x, render_y) = subpixel_position(
+                                my_x,
+                                my_y,
+                                antialiasing,
+                                settings.aa_pattern,
+                                &mut rng,
+                            );
+
+                            let (color, aov_colors) = render_subpixel(
+                                &ctx,
+                                &mut rng,
+                                render_x,
+                                render_y,
+                                &mut ray_tracer,
+                            );
+                            for (aov, color) in aov_colors.into_iter().enumerate() {
+                                send_aov(aov, my_x, my_y, color);
+                            }
+                            if check_samples {
+                                check_sample(&mut bad_samples, color, pixel(my_x, my_y), || {
+                                    primary_hit(&ctx, render_x, render_y, &mut ray_tracer)
+                                });
+                            }
+                            send(my_x, my_y, color);
+                            samples += 1;
+                        }
+                        let item_time = item_start.elapsed();
+                        busy_time += item_time;
+                        slowest_item = slowest_item.max(item_time);
+                    }
+
+                    drop(done_sender);
+                    (ray_tracer.stats(), samples, busy_time, slowest_item, bad_samples)
+                })
+                .unwrap();
+            worker_threads.push(worker_thread);
+        }
+        drop(done_sender);
+
+        // Wait for the workers while watching out for camera movements
+        let mut new_camera = None;
+        while let Err(RecvTimeoutError::Timeout) =
+            done_receiver.recv_timeout(Duration::from_millis(10))
+        {
+            if let Some(moved_camera) = self.camera_receiver.try_iter().last() {
+                new_camera = Some(moved_camera);
+                restart.store(true, atomic::Ordering::Relaxed);
+            }
+        }
+        let mut ray_stats = RayStats::default();
+        let mut samples = 0;
+        let mut busy_times = Vec::with_capacity(worker_threads.len());
+        let mut slowest_item = Duration::from_secs(0);
+        let mut bad_samples = BadSampleMap::new();
+        for worker_thread in worker_threads {
+            let (worker_ray_stats, worker_samples, busy_time, worker_slowest_item, worker_bad) =
+                worker_thread.join().unwrap();
+            for (material, worker_bad_samples) in &worker_bad {
+                bad_samples.entry(*material).or_default().add(worker_bad_samples);
+            }
+            ray_stats.add(&worker_ray_stats);
+            samples += worker_samples;
+            busy_times.push(busy_time);
+            slowest_item = slowest_item.max(worker_slowest_item);
+        }
+
+        PassResult {
+            ray_stats,
+            samples,
+            time: Instant::now() - start_time,
+            busy_times,
+            slowest_item,
+            bad_samples,
+            new_camera,
+        }
+    }
+}
+
+// Counts the sample if its color is not finite, or black although its primary ray hit something.
+// Only then primary_hit is called to find out what the ray hit, as tracing it again is cheap for
+// the few bad samples.
+fn check_sample(
+    bad_samples: &mut BadSampleMap,
+    color: Option<Vec3>,
+    pixel: (usize, usize),
+    primary_hit: impl FnOnce() -> Option<Geometry>,
+) {
+    let color = match color {
+        Some(color) => color,
+        None => return,
+    };
+    let non_finite = color.0.iter().any(|c| !c.is_finite());
+    if !non_finite && color.0.iter().any(|c| *c != 0.0) {
+        return;
+    }
+    let material = match primary_hit() {
+        Some(Geometry::Triangle(triangle)) => Some(triangle.material()),
+        Some(Geometry::PointLight(_)) => None,
+        // Tracing is deterministic, so this only guards against a miss being counted
+        None => return,
+    };
+    bad_samples.entry(material).or_default().add(&BadSamples {
+        non_finite: non_finite as u64,
+        black: !non_finite as u64,
+        example: pixel,
+    });
+}
+
+// Logs the materials with the most bad samples
+fn log_bad_samples(bad_samples: &BadSampleMap) {
+    if bad_samples.is_empty() {
+        log_info!("No samples were black or not finite.");
+        return;
+    }
+    let mut worst: Vec<_> = bad_samples.iter().collect();
+    worst.sort_by_key(|(_, samples)| std::cmp::Reverse((samples.non_finite, samples.black)));
+    log_warning!("Samples that were black or not finite, by the material their primary ray hit:");
+    for (material, samples) in worst.iter().take(10) {
+        let material = match material {
+            Some(material) => format!("Material {}", material),
+            None => "Lights".to_owned(),
+        };
+        log_warning!(
+            "    {}: {} not finite, {} black, e.g. at pixel ({}, {})",
+            material,
+            samples.non_finite,
+            samples.black,
+            samples.example.0,
+            samples.example.1
+        );
+    }
+}
+
+// Blocks until the camera is moved. Returns None once the GUI is gone or the render is cancelled,
+// which is right away without a GUI.
+fn wait_for_camera(camera_receiver: &Receiver<Camera>, cancel: &Cancel) -> Option<Camera> {
+    while !cancel.is_cancelled() {
+        match camera_receiver.recv_timeout(Duration::from_millis(50)) {
+            Ok(camera) => return Some(camera_receiver.try_iter().last().unwrap_or(camera)),
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => return None,
+        }
+    }
+    None
+}
+
+// Each subpixel draws from its own random sequence, so its samples do not depend on which worker
+// renders it or on what that worker rendered before, or on whether it is traced in a packet.
+// subpixel_w is the number of subpixels per row.
+fn subpixel_rng(seed: u128, x: usize, y: usize, subpixel_w: usize) -> rand_pcg::Pcg32 {
+    let index = (y * subpixel_w + x) as u64;
+    // PCG streams which start in the same state are correlated, so the states are scrambled too
+    let state =
+        ((seed ^ (seed >> 64)) as u64).wrapping_add(index.wrapping_mul(0x9e37_79b9_7f4a_7c15));
+    rand_pcg::Pcg32::new(state, index)
+}
+
+// Returns the position within the image to sample for the subpixel (x, y). Only jitter draws
+// from rng.
+fn subpixel_position<R: Rng>(
+    x: usize,
+    y: usize,
+    antialiasing: u32,
+    pattern: AaPattern,
+    rng: &mut R,
+) -> (f64, f64) {
+    if pattern == AaPattern::Jitter {
+        let subpixel_size = 1.0 / f64::from(1 << antialiasing);
+        let mask = (1 << antialiasing) - 1;
+        (
+            (x >> antialiasing) as f64 + ((x & mask) as f64 + rng.gen::<f64>()) * subpixel_size,
+            (y >> antialiasing) as f64 + ((y & mask) as f64 + rng.gen::<f64>()) * subpixel_size,
+        )
+    } else if antialiasing == 0 {
+        // Use pixel center
+        (x as f64 + 0.5, y as f64 + 0.5)
+    } else {
+        // Use RGSS around the second-to-last (!!!) subpixel center
+
+        // First find the subpixel center
+        // pixel_left + subpixel_index * subpixel_size + subpixel_size / 2
+        // Hint: For x = 1 and aa = 1 this leads to 0.75.
+        //       For x = 0 and aa = 1 this leads to 0.25.
+        //       For x = 0 and aa = 2 this leads to 0.125.
+        //       For x = 1 and aa = 2 this leads to 0.25.
+        let subpixel_size = 1.0 / f64::from(1 << antialiasing);
+        let rgss_center_x = (x >> antialiasing) as f64
+            + (x & ((1 << antialiasing) - 1)) as f64 * subpixel_size
+            + subpixel_size / 2.0;
+        let rgss_center_y = (y >> antialiasing) as f64
+            + (y & ((1 << antialiasing) - 1)) as f64 * subpixel_size
+            + subpixel_size / 2.0;
+
+        // Pick one offset for each of the four remaining subpixels. Note that these
+        // offsets are relative to the subpixel center, *not* relative to the
+        // second-to-last subpixel center.
+        let (rgss_offset_x, rgss_offset_y) = sampling::rgss_offset(x, y);
+
+        // Divide the offsets to the correct subpixel size
+        let rgss_offset_x = rgss_offset_x / f64::from(1 << (antialiasing - 1));
+        let rgss_offset_y = rgss_offset_y / f64::from(1 << (antialiasing - 1));
+
+        (rgss_center_x + rgss_offset_x, rgss_center_y + rgss_offset_y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::rendering::tests::{mirror_scene, settings};
+    use super::*;
+    use std::cmp::Reverse;
+
+    // Renders the mirror scene at its size of 8x6 pixels and returns the bits of the samples of
+    // each pixel. The workers send them in no particular order, so they are sorted.
+    fn render(settings: &RenderSettings, thread_count: usize) -> Vec<Vec<u64>> {
+        let settings = RenderSettings { thread_count, ..settings.clone() };
+        let (w, h) = (8, 6);
+        let (pixel_sender, pixel_receiver) = crossbeam_channel::unbounded();
+        // Nobody can move the camera, so the render ends after the first frame
+        let (_, camera_receiver) = crossbeam_channel::unbounded();
+        main(
+            Arc::new(mirror_scene()),
+            settings,
+            w,
+            h,
+            Cancel::new(),
+            camera_receiver,
+            pixel_sender,
+        );
+        let mut pixels = vec![vec![]; w * h];
+        for message in pixel_receiver.try_iter() {
+            if let RenderMessage::Pixel(x, y, color, _) = message {
+                pixels[y * w + x].extend(color.0.iter().map(|c| c.to_bits()));
+            }
+        }
+        for samples in &mut pixels {
+            samples.sort();
+        }
+        pixels
+    }
+
+    // Pass::positions as it was before the work items were generated lazily
+    fn sorted_positions(pass: &Pass, w: usize, h: usize) -> Vec<(usize, usize)> {
+        let antialiasing = pass.settings.antialiasing;
+        let (w, h) = (w << antialiasing, h << antialiasing);
+        let mut positions: Vec<_> = (0..w).flat_map(|x| (0..h).map(move |y| (x, y))).collect();
+        let zeros = |x: usize, y: usize| x.trailing_zeros().min(y.trailing_zeros());
+        positions.sort_by_key(|&(x, y)| (Reverse(zeros(x, y)), x, y));
+        positions.retain(|&(x, y)| !pass.packet_tracing() || (x % 2 == 0 && y % 2 == 0));
+        positions
+    }
+
+    #[test]
+    fn positions_are_ordered_coarse_to_fine() {
+        for &(w, h) in [(1, 1), (8, 6), (5, 13), (16, 1)].iter() {
+            for antialiasing in 0..3 {
+                for &packet_tracing in [false, true].iter() {
+                    let settings = RenderSettings { antialiasing, packet_tracing, ..settings() };
+                    let pass = Pass { settings, preview: false };
+                    let positions: Vec<_> = pass.positions(w, h).collect();
+                    assert_eq!(positions, sorted_positions(&pass, w, h), "{:?}", pass);
                 }
-            })
-            .unwrap();
-        worker_threads.push(worker_thread);
+            }
+        }
+    }
+
+    #[test]
+    fn thread_count_does_not_change_the_image() {
+        let settings = settings();
+        let one_thread = render(&settings, 1);
+        // Four samples with four channels each
+        assert!(one_thread.iter().all(|samples| samples.len() == 16));
+        assert_eq!(one_thread, render(&settings, 4));
     }
-    for worker_thread in worker_threads {
-        worker_thread.join().unwrap();
+
+    #[test]
+    fn thread_count_does_not_change_the_packet_traced_image() {
+        let settings = RenderSettings { packet_tracing: true, ..settings() };
+        let one_thread = render(&settings, 1);
+        assert!(one_thread.iter().all(|samples| samples.len() == 16));
+        assert_eq!(one_thread, render(&settings, 4));
+        // The packets trace the same primary rays as the scalar path
+        assert_eq!(one_thread, render(&RenderSettings { packet_tracing: false, ..settings }, 1));
     }
-    eprintln!("Raytracing: {} ms", (Instant::now() - start_time).as_millis());
 }
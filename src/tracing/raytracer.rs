@@ -1,247 +1,589 @@
-use super::bvh::{Bvh, BvhChild, BvhNode};
-use crate::math::{AlmostEq, Plane, Vec2, Vec3};
-use crate::scene::Geometry;
-use std::arch::x86_64::*;
-use std::f64::{INFINITY, NEG_INFINITY};
+use super::bvh::{Bvh, RayPacket, TraversalStack};
+use super::lights::LightReach;
+use super::rendering::Surface;
+use super::scene_bvh::{Object, ObjectTransform, SceneBvh, TriangleData};
+use crate::math::{Vec2, Vec3};
+use crate::scene::{Geometry, PointLight, Triangle};
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RayKind {
+    // Rays shot from the camera through the image plane
+    Primary,
+    // Rays following a specular bounce
+    Reflection,
+    // Rays checking whether a light is visible; any blocking triangle ends the search early
+    Shadow,
+}
+
+// Number of rays traced by a RayTracer, by kind
+#[derive(Debug, Copy, Clone, Default)]
+pub struct RayStats {
+    pub primary_rays: u64,
+    pub reflection_rays: u64,
+    pub shadow_rays: u64,
+    // Rays of any kind which hit something
+    pub hits: u64,
+}
+
+impl RayStats {
+    pub fn add(&mut self, other: &RayStats) {
+        self.primary_rays += other.primary_rays;
+        self.reflection_rays += other.reflection_rays;
+        self.shadow_rays += other.shadow_rays;
+        self.hits += other.hits;
+    }
+
+    pub fn rays(&self) -> u64 {
+        self.primary_rays + self.reflection_rays + self.shadow_rays
+    }
+
+    fn count(&mut self, kind: RayKind, rays: u64) {
+        match kind {
+            RayKind::Primary => self.primary_rays += rays,
+            RayKind::Reflection => self.reflection_rays += rays,
+            RayKind::Shadow => self.shadow_rays += rays,
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
 pub struct RayShootResult {
     pub geometry: Geometry,
     pub position: Vec3,
     pub normal: Vec3,
     pub lambda: f64,
     pub tex_coord: Vec2,
+    // Weights of the triangle's vertices at the hit, zero for lights
+    pub barycentric: Vec3,
+    // Unit directions of growing u and v texture coordinates at the hit, zero for lights
+    pub tangent: Vec3,
+    pub bitangent: Vec3,
 }
 
 pub struct RayTracer<'a> {
-    bvh: &'a Bvh<Geometry>,
-    todo_stack: Vec<BvhNode<'a, Geometry>>,
+    scene_bvh: &'a SceneBvh,
+    light_bvh: Option<&'a Bvh<LightReach>>,
+    point_lights: &'a [PointLight],
+    object_stack: TraversalStack<'a, Object>,
+    todo_stack: TraversalStack<'a, Triangle>,
+    light_stack: TraversalStack<'a, PointLight>,
+    // The lists handed out by find_lights and take_surfaces, kept so that shading points do not
+    // allocate
+    light_buffer: Vec<&'a PointLight>,
+    surface_buffer: Vec<Surface>,
+    stats: RayStats,
 }
 
 impl<'a> RayTracer<'a> {
-    pub fn new(bvh: &Bvh<Geometry>) -> RayTracer {
-        RayTracer { bvh, todo_stack: Vec::with_capacity(1024) }
+    pub fn new(
+        scene_bvh: &'a SceneBvh,
+        light_bvh: Option<&'a Bvh<LightReach>>,
+        point_lights: &'a [PointLight],
+    ) -> RayTracer<'a> {
+        RayTracer {
+            scene_bvh,
+            light_bvh,
+            point_lights,
+            object_stack: TraversalStack::with_capacity(scene_bvh.objects.traversal_stack_size()),
+            // The same stack is used for all meshes
+            todo_stack: TraversalStack::with_capacity(
+                scene_bvh.meshes.iter().map(Bvh::traversal_stack_size).max().unwrap_or(0),
+            ),
+            light_stack: TraversalStack::with_capacity(
+                scene_bvh.lights.as_ref().map_or(0, Bvh::traversal_stack_size),
+            ),
+            light_buffer: vec![],
+            surface_buffer: vec![],
+            stats: RayStats::default(),
+        }
+    }
+
+    // Number of BVH node tests and primitive tests since the last reset
+    pub fn test_count(&self) -> usize {
+        self.object_stack.tests() + self.todo_stack.tests() + self.light_stack.tests()
+    }
+
+    pub fn reset_test_count(&mut self) {
+        self.object_stack.reset_tests();
+        self.todo_stack.reset_tests();
+        self.light_stack.reset_tests();
+    }
+
+    pub fn stats(&self) -> RayStats {
+        self.stats
+    }
+
+    // The AABB enclosing the whole scene
+    pub fn scene_aabb(&self) -> (Vec3, Vec3) {
+        self.scene_bvh.aabb()
+    }
+
+    // Collects the lights which may contribute noticeably to the given position. Without a light
+    // BVH this is every light in the scene. Hand the list back with recycle_lights, so that the
+    // next call can reuse its memory.
+    pub fn find_lights(&mut self, position: Vec3) -> Vec<&'a PointLight> {
+        let mut lights = std::mem::take(&mut self.light_buffer);
+        lights.clear();
+        if let Some(light_bvh) = self.light_bvh {
+            light_bvh.find_containing(position, &mut |reach| lights.push(&reach.light));
+        } else {
+            lights.extend(self.point_lights.iter());
+        }
+        lights
+    }
+
+    pub fn recycle_lights(&mut self, lights: Vec<&'a PointLight>) {
+        self.light_buffer = lights;
+    }
+
+    // An empty list for the surfaces along a chain of reflections. Hand it back with
+    // recycle_surfaces, so that the next chain can reuse its memory.
+    pub fn take_surfaces(&mut self) -> Vec<Surface> {
+        let mut surfaces = std::mem::take(&mut self.surface_buffer);
+        surfaces.clear();
+        surfaces
+    }
+
+    pub fn recycle_surfaces(&mut self, surfaces: Vec<Surface>) {
+        self.surface_buffer = surfaces;
+    }
+
+    // Returns whether any triangle blocks the ray between min_dist and max_dist. The BVH walk is
+    // stopped as soon as the first blocker is found.
+    pub fn trace_ray_any(
+        &mut self,
+        ray_origin: Vec3,
+        ray: Vec3,
+        min_dist: f64,
+        max_dist: f64,
+    ) -> bool {
+        self.trace_ray(RayKind::Shadow, ray_origin, ray, min_dist, max_dist).is_some()
+    }
+
+    // Like trace_ray, but for a ray which was traced and counted before, so it is left out of the
+    // statistics
+    pub fn retrace_ray(
+        &mut self,
+        kind: RayKind,
+        ray_origin: Vec3,
+        ray: Vec3,
+        min_dist: f64,
+        max_dist: f64,
+    ) -> Option<RayShootResult> {
+        let stats = self.stats;
+        let result = self.trace_ray(kind, ray_origin, ray, min_dist, max_dist);
+        self.stats = stats;
+        result
     }
 
     pub fn trace_ray(
         &mut self,
+        kind: RayKind,
         ray_origin: Vec3,
         ray: Vec3,
         min_dist: f64,
         mut max_dist: f64,
     ) -> Option<RayShootResult> {
         let mut result: Option<RayShootResult> = None;
+        self.stats.count(kind, 1);
 
-        let ray_origin_x = unsafe { _mm256_broadcast_sd(&ray_origin.0[0]) };
-        let ray_origin_y = unsafe { _mm256_broadcast_sd(&ray_origin.0[1]) };
-        let ray_origin_z = unsafe { _mm256_broadcast_sd(&ray_origin.0[2]) };
-        let ray_x = unsafe { _mm256_broadcast_sd(&(1.0 / ray.0[0])) };
-        let ray_y = unsafe { _mm256_broadcast_sd(&(1.0 / ray.0[1])) };
-        let ray_z = unsafe { _mm256_broadcast_sd(&(1.0 / ray.0[2])) };
-
-        self.todo_stack.clear();
-        self.todo_stack.push(self.bvh.root());
-        while let Some(bvh) = self.todo_stack.pop() {
-            // These two equations describe all lambda for which the ray is inside an AABB:
-            //     aabb_min <= ray_origin + lambda * ray
-            //     ray_origin + lambda * ray <= aabb_max
-            // This can be rearranged to (rax > 0)
-            //     (aabb_min.x - ray_origin.x) / ray.x <= lambda
-            //     (aabb_min.y - ray_origin.y) / ray.y <= lambda
-            //     (aabb_min.z - ray_origin.z) / ray.z <= lambda
-            //     lambda <= (aabb_max.x - ray_origin.x) / ray.x
-            //     lambda <= (aabb_max.y - ray_origin.y) / ray.y
-            //     lambda <= (aabb_max.y - ray_origin.y) / ray.y
-            // (rax < 0)
-            //     (aabb_min.x - ray_origin.x) / ray.x >= lambda
-            //     (aabb_min.y - ray_origin.y) / ray.y >= lambda
-            //     (aabb_min.z - ray_origin.z) / ray.z >= lambda
-            //     lambda >= (aabb_max.x - ray_origin.x) / ray.x
-            //     lambda >= (aabb_max.y - ray_origin.y) / ray.y
-            //     lambda >= (aabb_max.y - ray_origin.y) / ray.y
-            // (ray = 0)
-            //     aabb_min.x - ray_origin.x <= 0
-            //     aabb_min.y - ray_origin.y <= 0
-            //     aabb_min.z - ray_origin.z <= 0
-            //     aabb_max.x - ray_origin.x >= 0
-            //     aabb_max.y - ray_origin.y >= 0
-            //     aabb_max.z - ray_origin.z >= 0
-            let hits = unsafe {
-                let mut lambda_min = _mm256_broadcast_sd(&NEG_INFINITY);
-                let mut lambda_max = _mm256_broadcast_sd(&INFINITY);
-
-                // X
-                let a = _mm256_mul_pd(
-                    _mm256_sub_pd(_mm256_load_pd(bvh.aabb_min_x().as_ptr()), ray_origin_x),
-                    ray_x,
-                );
-                let b = _mm256_mul_pd(
-                    _mm256_sub_pd(_mm256_load_pd(bvh.aabb_max_x().as_ptr()), ray_origin_x),
-                    ray_x,
-                );
-                if ray.0[0] > 0.0 {
-                    lambda_min = _mm256_max_pd(lambda_min, a);
-                    lambda_max = _mm256_min_pd(lambda_max, b);
-                } else if ray.0[0] < 0.0 {
-                    lambda_min = _mm256_max_pd(lambda_min, b);
-                    lambda_max = _mm256_min_pd(lambda_max, a);
+        let scene_bvh = self.scene_bvh;
+        let todo_stack = &mut self.todo_stack;
+        let mut traversal =
+            scene_bvh.objects.traverse(ray_origin, ray, min_dist, max_dist, &mut self.object_stack);
+        while let Some((_, object)) = traversal.next() {
+            let mesh = MeshRef::new(scene_bvh, object.mesh);
+            let object_result = match &object.transform {
+                None => trace_mesh(todo_stack, &mesh, kind, ray_origin, ray, min_dist, max_dist),
+                Some(transform) => {
+                    // The transformation is affine, so lambda stays the same in the
+                    // object's coordinate system as long as the ray is not normalized
+                    let local_origin = (transform.inverse * ray_origin.xyz1()).xyz();
+                    let local_ray = (transform.inverse * ray.xyz0()).xyz();
+                    trace_mesh(todo_stack, &mesh, kind, local_origin, local_ray, min_dist, max_dist)
+                        .map(|local_result| transform_result(transform, local_result))
                 }
+            };
 
-                // Y
-                let a = _mm256_mul_pd(
-                    _mm256_sub_pd(_mm256_load_pd(bvh.aabb_min_y().as_ptr()), ray_origin_y),
-                    ray_y,
-                );
-                let b = _mm256_mul_pd(
-                    _mm256_sub_pd(_mm256_load_pd(bvh.aabb_max_y().as_ptr()), ray_origin_y),
-                    ray_y,
-                );
-                if ray.0[1] > 0.0 {
-                    lambda_min = _mm256_max_pd(lambda_min, a);
-                    lambda_max = _mm256_min_pd(lambda_max, b);
-                } else if ray.0[1] < 0.0 {
-                    lambda_min = _mm256_max_pd(lambda_min, b);
-                    lambda_max = _mm256_min_pd(lambda_max, a);
+            if let Some(object_result) = object_result {
+                max_dist = object_result.lambda;
+                traversal.set_max_dist(max_dist);
+                result = Some(object_result);
+                if kind == RayKind::Shadow {
+                    break;
                 }
+            }
+        }
 
-                // Z
-                let a = _mm256_mul_pd(
-                    _mm256_sub_pd(_mm256_load_pd(bvh.aabb_min_z().as_ptr()), ray_origin_z),
-                    ray_z,
-                );
-                let b = _mm256_mul_pd(
-                    _mm256_sub_pd(_mm256_load_pd(bvh.aabb_max_z().as_ptr()), ray_origin_z),
-                    ray_z,
-                );
-                if ray.0[2] > 0.0 {
-                    lambda_min = _mm256_max_pd(lambda_min, a);
-                    lambda_max = _mm256_min_pd(lambda_max, b);
-                } else if ray.0[2] < 0.0 {
-                    lambda_min = _mm256_max_pd(lambda_min, b);
-                    lambda_max = _mm256_min_pd(lambda_max, a);
+        // Lights do not cast shadows
+        if kind != RayKind::Shadow {
+            if let Some(lights) = &scene_bvh.lights {
+                let mut traversal =
+                    lights.traverse(ray_origin, ray, min_dist, max_dist, &mut self.light_stack);
+                while let Some((_, point_light)) = traversal.next() {
+                    if let Some(light_result) =
+                        intersect_light(point_light, ray_origin, ray, min_dist, max_dist)
+                    {
+                        max_dist = light_result.lambda;
+                        traversal.set_max_dist(max_dist);
+                        result = Some(light_result);
+                    }
                 }
+            }
+        }
 
-                let lambda_check =
-                    _mm256_castpd_si256(_mm256_cmp_pd(lambda_max, lambda_min, _CMP_LT_OQ));
-                let lambda_min_check = _mm256_castpd_si256(_mm256_cmp_pd(
-                    lambda_min,
-                    _mm256_broadcast_sd(&max_dist),
-                    _CMP_GT_OQ,
-                ));
-                let lambda_max_check = _mm256_castpd_si256(_mm256_cmp_pd(
-                    lambda_max,
-                    _mm256_broadcast_sd(&min_dist),
-                    _CMP_LT_OQ,
-                ));
-                let pred = _mm256_or_si256(
-                    lambda_check,
-                    _mm256_or_si256(lambda_min_check, lambda_max_check),
-                );
-
-                let mut result = std::mem::uninitialized();
-                _mm256_store_si256(&mut result, pred);
-                std::mem::transmute::<__m256i, [u64; 4]>(result)
-            };
+        if result.is_some() {
+            self.stats.hits += 1;
+        }
+        result
+    }
 
-            for (i, hit) in hits.iter().enumerate() {
-                if *hit == 0 {
-                    match bvh.value(i) {
-                        BvhChild::Empty => {}
-                        BvhChild::Subtree(sub_bvh) => {
-                            self.todo_stack.push(sub_bvh);
-                        }
-                        BvhChild::Value(Geometry::Triangle(triangle)) => {
-                            let Plane { a, b, c, d } = *triangle.plane();
-                            // Ray equation:  ray_origin + lambda * ray
-
-                            // Plug the ray equation(s) into the plane equation:
-                            //     dot([a, b, c], ray_origin + lambda * ray) = d
-                            //     dot([a, b, c], ray_origin) + lambda * dot([a, b, c], ray) = d
-                            //     lambda = (d - dot([a, b, c], ray_origin)) / dot([a, b, c], ray)
-                            let lambda =
-                                (d - Vec3([a, b, c]).dot(ray_origin)) / Vec3([a, b, c]).dot(ray);
-                            if !lambda.is_finite() || lambda < min_dist || lambda > max_dist {
-                                continue;
-                            }
-                            let intersection = ray_origin + lambda * ray;
-
-                            // Get the barycentric coordinates
-                            let area_triangle = Vec3([a, b, c]).len();
-                            let area_triangle_abi = (triangle.a().position - intersection)
-                                .cross(triangle.b().position - intersection)
-                                .len();
-                            let area_triangle_aci = (triangle.a().position - intersection)
-                                .cross(triangle.c().position - intersection)
-                                .len();
-                            let area_triangle_bci = (triangle.b().position - intersection)
-                                .cross(triangle.c().position - intersection)
-                                .len();
-                            let gamma = area_triangle_abi / area_triangle;
-                            let beta = area_triangle_aci / area_triangle;
-                            let alpha = area_triangle_bci / area_triangle;
-                            if !(alpha + beta + gamma).almost_eq(1.0) {
-                                continue;
-                            }
-
-                            let normal = triangle.a().normal * alpha
-                                + triangle.b().normal * beta
-                                + triangle.c().normal * gamma;
-                            if normal.dot(ray) > 0.0 {
-                                continue;
-                            }
-                            let normal = normal.normalize();
-
-                            let tex_coord = triangle.a().tex_coord * alpha
-                                + triangle.b().tex_coord * beta
-                                + triangle.c().tex_coord * gamma;
-
-                            result = Some(RayShootResult {
-                                geometry: Geometry::Triangle(*triangle),
-                                position: intersection,
-                                normal,
-                                lambda,
-                                tex_coord,
-                            });
-                            max_dist = lambda;
+    // Traces four rays at once. All rays share each visit of a BVH node, which pays off for
+    // coherent rays like the primary rays of one RGSS group.
+    pub fn trace_packet(
+        &mut self,
+        kind: RayKind,
+        ray_origins: [Vec3; 4],
+        rays: [Vec3; 4],
+        min_dist: f64,
+        max_dist: f64,
+    ) -> [Option<RayShootResult>; 4] {
+        let mut results = [None; 4];
+        let mut max_dists = [max_dist; 4];
+        self.stats.count(kind, 4);
+
+        let scene_bvh = self.scene_bvh;
+        let todo_stack = &mut self.todo_stack;
+        let packet = RayPacket::new(ray_origins, rays);
+        scene_bvh.objects.traverse_packet(
+            &packet,
+            min_dist,
+            &mut max_dists,
+            &mut self.object_stack,
+            &mut |_, object, max_dists| {
+                let mesh = MeshRef::new(scene_bvh, object.mesh);
+                let hits = match &object.transform {
+                    None => trace_mesh_packet(
+                        todo_stack,
+                        &mesh,
+                        kind,
+                        ray_origins,
+                        rays,
+                        min_dist,
+                        max_dists,
+                    ),
+                    Some(transform) => {
+                        let mut local_origins = ray_origins;
+                        let mut local_rays = rays;
+                        for lane in 0..4 {
+                            local_origins[lane] =
+                                (transform.inverse * ray_origins[lane].xyz1()).xyz();
+                            local_rays[lane] = (transform.inverse * rays[lane].xyz0()).xyz();
                         }
-                        BvhChild::Value(Geometry::PointLight(pl)) => {
-                            // sphere:
-                            //     (x-x0)² + (y-y0)² + (z-z0)² = r²
-                            //     dot([x-x0, y-y0, z-z0], [x-x0, y-y0, z-z0]) = r²
-                            //     dot([x, y, z], [x-x0, y-y0, z-z0]) - dot([x0, y0, z0], [x-x0, y-y0, z-z0]) = r²
-                            //     dot([x, y, z], [x, y, z]) - 2 * dot([x, y, z], [x0, y0, z0]) + dot([x0, y0, z0], [x0, y0, z0]) = r²
-                            //
-                            // ray: ray_origin + lambda * ray
-                            //     ray_origin = [xo,yo,zo]
-                            //     ray = [xr,yr,zr]
-                            //     pl.position = [x0,y0,z0]
-                            //     (xo-lambda*xr-x0)² + (yo-lambda*yr-x0)² + (zo-lambda*zr-x0)² = r²
-                            //     (xo-x0)² - 2*(xo-x0)*lambda*xr - lambda²*xr² + ... + ... = r²
-                            //     lambda² * (xr² + yr² + zr²) + lambda * 2 * ((xo-x0)*xr + (yo-y0)*yr + (zo-z0)*zr) - r² + (xo-x0)² + (yo-y0)² + (zo-z0)² = 0
-                            let a = ray.dot(ray);
-                            let b = 2.0 * (ray_origin - pl.position).dot(ray);
-                            let c = -pl.radius * pl.radius + (ray_origin - pl.position).sqlen();
-                            // (-b +/- sqrt(b²-4ac)) / 2a
-                            let lambda1 = (-b + (b * b - 4.0 * a * c).sqrt()) / (2.0 * a);
-                            let lambda2 = (-b - (b * b - 4.0 * a * c).sqrt()) / (2.0 * a);
-                            let lambda = lambda1.min(lambda2);
-
-                            if lambda <= max_dist && lambda >= min_dist {
-                                let position = ray_origin + lambda * ray;
-                                result = Some(RayShootResult {
-                                    geometry: Geometry::PointLight(*pl),
-                                    position,
-                                    normal: (position - pl.position).normalize(),
-                                    lambda,
-                                    tex_coord: Vec2([0.0, 0.0]),
-                                });
-                                max_dist = lambda;
-                            }
+                        let mut hits = trace_mesh_packet(
+                            todo_stack,
+                            &mesh,
+                            kind,
+                            local_origins,
+                            local_rays,
+                            min_dist,
+                            max_dists,
+                        );
+                        for hit in hits.iter_mut() {
+                            *hit =
+                                hit.map(|local_result| transform_result(transform, local_result));
                         }
+                        hits
+                    }
+                };
+                for (result, hit) in results.iter_mut().zip(hits.iter()) {
+                    if hit.is_some() {
+                        *result = *hit;
                     }
                 }
+            },
+        );
+
+        let lights = if kind == RayKind::Shadow { None } else { scene_bvh.lights.as_ref() };
+        if let Some(lights) = lights {
+            lights.traverse_packet(
+                &packet,
+                min_dist,
+                &mut max_dists,
+                &mut self.light_stack,
+                &mut |_, point_light, max_dists| {
+                    for lane in 0..4 {
+                        if let Some(hit) = intersect_light(
+                            point_light,
+                            ray_origins[lane],
+                            rays[lane],
+                            min_dist,
+                            max_dists[lane],
+                        ) {
+                            max_dists[lane] = hit.lambda;
+                            results[lane] = Some(hit);
+                        }
+                    }
+                },
+            );
+        }
+
+        self.stats.hits += results.iter().filter(|result| result.is_some()).count() as u64;
+        results
+    }
+}
+
+// A mesh of the scene BVH with what intersecting its triangles needs
+struct MeshRef<'a> {
+    bvh: &'a Bvh<Triangle>,
+    triangles: &'a TriangleData,
+    // Whether the material with the same index is double-sided
+    double_sided: &'a [bool],
+}
+
+impl<'a> MeshRef<'a> {
+    fn new(scene_bvh: &'a SceneBvh, mesh: usize) -> MeshRef<'a> {
+        MeshRef {
+            bvh: &scene_bvh.meshes[mesh],
+            triangles: &scene_bvh.mesh_triangles[mesh],
+            double_sided: &scene_bvh.double_sided,
+        }
+    }
+}
+
+fn trace_mesh<'a>(
+    todo_stack: &mut TraversalStack<'a, Triangle>,
+    mesh: &MeshRef<'a>,
+    kind: RayKind,
+    ray_origin: Vec3,
+    ray: Vec3,
+    min_dist: f64,
+    mut max_dist: f64,
+) -> Option<RayShootResult> {
+    let mut result: Option<RayShootResult> = None;
+
+    let mut traversal = mesh.bvh.traverse(ray_origin, ray, min_dist, max_dist, todo_stack);
+    while let Some((i, _)) = traversal.next() {
+        if let Some(hit) = intersect_triangle(mesh, i, kind, ray_origin, ray, min_dist, max_dist) {
+            if kind == RayKind::Shadow {
+                // Any blocker will do, so stop the walk
+                return Some(hit);
+            }
+            max_dist = hit.lambda;
+            traversal.set_max_dist(max_dist);
+            result = Some(hit);
+        }
+    }
+
+    result
+}
+
+// Traces the four rays of a packet through a mesh, updating the max_dists of the rays which hit
+// something closer. Returns the closest hit of each ray in the mesh's coordinate system, or None
+// if the ray hit nothing closer than it had before.
+fn trace_mesh_packet<'a>(
+    todo_stack: &mut TraversalStack<'a, Triangle>,
+    mesh: &MeshRef<'a>,
+    kind: RayKind,
+    ray_origins: [Vec3; 4],
+    rays: [Vec3; 4],
+    min_dist: f64,
+    max_dists: &mut [f64; 4],
+) -> [Option<RayShootResult>; 4] {
+    let mut results = [None; 4];
+    let packet = RayPacket::new(ray_origins, rays);
+    mesh.bvh.traverse_packet(&packet, min_dist, max_dists, todo_stack, &mut |i, _, max_dists| {
+        for lane in 0..4 {
+            if max_dists[lane] < min_dist {
+                continue;
+            }
+            if let Some(hit) = intersect_triangle(
+                mesh,
+                i,
+                kind,
+                ray_origins[lane],
+                rays[lane],
+                min_dist,
+                max_dists[lane],
+            ) {
+                // A shadow ray is done after the first blocker
+                max_dists[lane] =
+                    if kind == RayKind::Shadow { f64::NEG_INFINITY } else { hit.lambda };
+                results[lane] = Some(hit);
             }
         }
+    });
+    results
+}
 
-        result
+// Moves a hit on an instanced mesh from the mesh's coordinate system into the world. The tangent
+// frame is rebuilt around the world normal, so it stays orthonormal under non-uniform scaling, with
+// the bitangent on the side the mesh's texture coordinates and the mirroring of the matrix give.
+fn transform_result(transform: &ObjectTransform, local_result: RayShootResult) -> RayShootResult {
+    let RayShootResult { normal, tangent, bitangent, .. } = local_result;
+    let local_sign = normal.cross(tangent).dot(bitangent).signum();
+    let normal = (transform.normal_matrix * normal.xyz0()).xyz().normalize();
+    let tangent = (transform.matrix * tangent.xyz0()).xyz();
+    let tangent = (tangent - normal * normal.dot(tangent)).normalize();
+    RayShootResult {
+        position: (transform.matrix * local_result.position.xyz1()).xyz(),
+        normal,
+        tangent,
+        bitangent: normal.cross(tangent) * (local_sign * transform.bitangent_sign),
+        ..local_result
+    }
+}
+
+// Intersects the ray with the i-th triangle of the mesh
+fn intersect_triangle(
+    mesh: &MeshRef,
+    i: usize,
+    kind: RayKind,
+    ray_origin: Vec3,
+    ray: Vec3,
+    min_dist: f64,
+    max_dist: f64,
+) -> Option<RayShootResult> {
+    let (lambda, Vec3([alpha, beta, gamma])) = mesh.triangles.intersect(i, ray_origin, ray)?;
+    if lambda < min_dist || lambda > max_dist {
+        return None;
+    }
+    let intersection = ray_origin + lambda * ray;
+    // Only read for a hit, so a miss never touches the full triangle
+    let triangle = &mesh.bvh.values()[i];
+
+    let normal = triangle.normal(Vec3([alpha, beta, gamma]));
+    let normal = if normal.dot(ray) <= 0.0 {
+        normal
+    } else if mesh.double_sided[triangle.material()] {
+        // Shade the backface like the front, with the normal facing the ray
+        -normal
+    } else {
+        return None;
+    };
+    if kind == RayKind::Shadow {
+        // Any blocker will do, so skip the shading data
+        return Some(RayShootResult {
+            geometry: Geometry::Triangle(*triangle),
+            position: intersection,
+            normal,
+            lambda,
+            tex_coord: Vec2([0.0, 0.0]),
+            barycentric: Vec3([alpha, beta, gamma]),
+            tangent: Vec3([0.0; 3]),
+            bitangent: Vec3([0.0; 3]),
+        });
+    }
+    let normal = normal.normalize();
+    let (tangent, bitangent) = triangle.tangent_frame(Vec3([alpha, beta, gamma]), normal);
+
+    let tex_coord = triangle.a().tex_coord * alpha
+        + triangle.b().tex_coord * beta
+        + triangle.c().tex_coord * gamma;
+
+    Some(RayShootResult {
+        geometry: Geometry::Triangle(*triangle),
+        position: intersection,
+        normal,
+        lambda,
+        tex_coord,
+        barycentric: Vec3([alpha, beta, gamma]),
+        tangent,
+        bitangent,
+    })
+}
+
+fn intersect_light(
+    pl: &PointLight,
+    ray_origin: Vec3,
+    ray: Vec3,
+    min_dist: f64,
+    max_dist: f64,
+) -> Option<RayShootResult> {
+    // sphere:
+    //     (x-x0)² + (y-y0)² + (z-z0)² = r²
+    //     dot([x-x0, y-y0, z-z0], [x-x0, y-y0, z-z0]) = r²
+    //     dot([x, y, z], [x-x0, y-y0, z-z0]) - dot([x0, y0, z0], [x-x0, y-y0, z-z0]) = r²
+    //     dot([x, y, z], [x, y, z]) - 2 * dot([x, y, z], [x0, y0, z0]) + dot([x0, y0, z0], [x0, y0, z0]) = r²
+    //
+    // ray: ray_origin + lambda * ray
+    //     ray_origin = [xo,yo,zo]
+    //     ray = [xr,yr,zr]
+    //     pl.position = [x0,y0,z0]
+    //     (xo-lambda*xr-x0)² + (yo-lambda*yr-x0)² + (zo-lambda*zr-x0)² = r²
+    //     (xo-x0)² - 2*(xo-x0)*lambda*xr - lambda²*xr² + ... + ... = r²
+    //     lambda² * (xr² + yr² + zr²) + lambda * 2 * ((xo-x0)*xr + (yo-y0)*yr + (zo-z0)*zr) - r² + (xo-x0)² + (yo-y0)² + (zo-z0)² = 0
+    let a = ray.dot(ray);
+    let b = 2.0 * (ray_origin - pl.position).dot(ray);
+    let c = -pl.radius * pl.radius + (ray_origin - pl.position).sqlen();
+    // (-b +/- sqrt(b²-4ac)) / 2a
+    let lambda1 = (-b + (b * b - 4.0 * a * c).sqrt()) / (2.0 * a);
+    let lambda2 = (-b - (b * b - 4.0 * a * c).sqrt()) / (2.0 * a);
+    let lambda = lambda1.min(lambda2);
+
+    if lambda <= max_dist && lambda >= min_dist {
+        let position = ray_origin + lambda * ray;
+        Some(RayShootResult {
+            geometry: Geometry::PointLight(*pl),
+            position,
+            normal: (position - pl.position).normalize(),
+            lambda,
+            tex_coord: Vec2([0.0, 0.0]),
+            barycentric: Vec3([0.0; 3]),
+            tangent: Vec3([0.0; 3]),
+            bitangent: Vec3([0.0; 3]),
+        })
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::tests::assert_vec3_eq;
+    use crate::math::Mat4;
+
+    #[test]
+    fn mirrored_instances_keep_the_texture_orientation() {
+        // Mirrors x and shears y towards it, column major
+        let matrix = Mat4([
+            [-1.0, 0.0, 0.0, 0.0],
+            [1.0, 3.0, 0.0, 0.0],
+            [0.0, 0.0, 1.0, 0.0],
+            [0.0, 0.0, 0.0, 1.0],
+        ]);
+        let inverse = matrix.inv();
+        let transform = ObjectTransform {
+            matrix,
+            inverse,
+            normal_matrix: inverse.transpose(),
+            bitangent_sign: matrix.det().signum(),
+        };
+        // u grows along x, and v along y or, for mirrored texture coordinates, along -y
+        for sign in [1.0, -1.0].iter() {
+            let local_result = RayShootResult {
+                geometry: Geometry::PointLight(PointLight {
+                    position: Vec3([0.0; 3]),
+                    color: Vec3([0.0; 3]),
+                    radius: 0.0,
+                    a: 0.0,
+                    b: 0.0,
+                    c: 1.0,
+                }),
+                position: Vec3([0.0; 3]),
+                normal: Vec3([0.0, 0.0, 1.0]),
+                lambda: 1.0,
+                tex_coord: Vec2([0.0; 2]),
+                barycentric: Vec3([0.0; 3]),
+                tangent: Vec3([1.0, 0.0, 0.0]),
+                bitangent: Vec3([0.0, *sign, 0.0]),
+            };
+            let result = transform_result(&transform, local_result);
+            assert_vec3_eq(result.normal, Vec3([0.0, 0.0, 1.0]), 1e-9);
+            assert_vec3_eq(result.tangent, Vec3([-1.0, 0.0, 0.0]), 1e-9);
+            // Orthogonal to the tangent despite the shear, on the side v grows to in the world
+            assert_vec3_eq(result.bitangent, Vec3([0.0, *sign, 0.0]), 1e-9);
+        }
     }
 }
@@ -1,109 +1,362 @@
-use super::raytracer::{RayShootResult, RayTracer};
-use crate::math::{Mat4, Vec3, EPS};
-use crate::scene::{Bsdf, Camera, Geometry, Scene};
+use super::raytracer::{RayKind, RayShootResult, RayTracer};
+use super::sampling::uniform_disk;
+use super::{DebugMode, RenderSettings};
+use crate::math::{AlmostEq, Vec3, EPS, RAY_BIAS};
+use crate::scene::{Bsdf, Camera, Geometry, PointLight, Scene};
 use rand::Rng;
 use std::f64::consts::PI;
-use std::f64::INFINITY;
 
+// What rendering a subpixel needs to know besides its position
+pub struct SampleContext<'a> {
+    pub scene: &'a Scene,
+    pub camera: &'a Camera,
+    // Size of the image in pixels, which the positions of the subpixels are relative to
+    pub width: f64,
+    pub height: f64,
+    pub settings: &'a RenderSettings,
+}
+
+// Returns the color of the subpixel and the value of each AOV of the settings, which are filled
+// from the same primary hit.
 pub fn render_subpixel<R: Rng>(
-    scene: &Scene,
+    ctx: &SampleContext,
     rng: &mut R,
     x: f64,
     y: f64,
-    width: f64,
-    height: f64,
     ray_tracer: &mut RayTracer,
+) -> (Option<Vec3>, Vec<Option<Vec3>>) {
+    let SampleContext { scene, camera, width, height, settings } = *ctx;
+    let ray = calc_ray(camera, x, y, width, height);
+    let (lambda_min, lambda_max) = camera.clip_range();
+    if settings.debug.is_some() || !settings.aovs.is_empty() {
+        ray_tracer.reset_test_count();
+    }
+    let hit = ray_tracer.trace_ray(RayKind::Primary, camera.position, ray, lambda_min, lambda_max);
+    // Before shading, which would add its tests to the heat
+    let aov_colors =
+        settings.aovs.iter().map(|aov| shade_debug(scene, *aov, ray, hit, ray_tracer)).collect();
+    let color = match settings.debug {
+        Some(mode) => shade_debug(scene, mode, ray, hit, ray_tracer),
+        None => shade_hit(scene, rng, settings, ray, hit, 1024, ray_tracer),
+    };
+    (color, aov_colors)
+}
+
+// Renders the four subpixels of an RGSS group, tracing their primary rays as one packet. Each
+// subpixel is shaded with its own random numbers from rngs. The AOVs hold the values of the four
+// subpixels each.
+pub fn render_subpixel_packet<R: Rng>(
+    ctx: &SampleContext,
+    rngs: &mut [R; 4],
+    positions: [(f64, f64); 4],
+    ray_tracer: &mut RayTracer,
+) -> ([Option<Vec3>; 4], Vec<[Option<Vec3>; 4]>) {
+    let SampleContext { scene, camera, width, height, settings } = *ctx;
+    let mut rays = [Vec3([0.0; 3]); 4];
+    for (ray, (x, y)) in rays.iter_mut().zip(positions.iter()) {
+        *ray = calc_ray(camera, *x, *y, width, height);
+    }
+    ray_tracer.reset_test_count();
+    let (lambda_min, lambda_max) = camera.clip_range();
+    let hits = ray_tracer.trace_packet(
+        RayKind::Primary,
+        [camera.position; 4],
+        rays,
+        lambda_min,
+        lambda_max,
+    );
+
+    let mut aov_colors = vec![[None; 4]; settings.aovs.len()];
+    for (aov, aov_colors) in settings.aovs.iter().zip(aov_colors.iter_mut()) {
+        for i in 0..4 {
+            aov_colors[i] = shade_debug(scene, *aov, rays[i], hits[i], ray_tracer);
+        }
+    }
+    let mut colors = [None; 4];
+    for i in 0..4 {
+        colors[i] = match settings.debug {
+            Some(mode) => shade_debug(scene, mode, rays[i], hits[i], ray_tracer),
+            None => shade_hit(scene, &mut rngs[i], settings, rays[i], hits[i], 1024, ray_tracer),
+        };
+    }
+    (colors, aov_colors)
+}
+
+// Traces the primary ray of the subpixel at (x, y) again and returns what it hit, without
+// counting the ray a second time
+pub fn primary_hit(
+    ctx: &SampleContext,
+    x: f64,
+    y: f64,
+    ray_tracer: &mut RayTracer,
+) -> Option<Geometry> {
+    let SampleContext { camera, width, height, .. } = *ctx;
+    let ray = calc_ray(camera, x, y, width, height);
+    let (lambda_min, lambda_max) = camera.clip_range();
+    let hit =
+        ray_tracer.retrace_ray(RayKind::Primary, camera.position, ray, lambda_min, lambda_max);
+    hit.map(|hit| hit.geometry)
+}
+
+fn shade_debug(
+    scene: &Scene,
+    mode: DebugMode,
+    ray: Vec3,
+    hit: Option<RayShootResult>,
+    ray_tracer: &RayTracer,
 ) -> Option<Vec3> {
-    let ray = calc_ray(&scene.camera, x, y, width, height);
-    handle_ray(scene, rng, scene.camera.position, ray, 1.0, 1024, ray_tracer)
+    if mode == DebugMode::Heat {
+        // Misses can be expensive as well, so they are colored too
+        let heat = ((ray_tracer.test_count().max(1) as f64).log2() / 10.0).min(1.0);
+        return Some(Vec3([heat, 0.0, 1.0 - heat]));
+    }
+
+    let hit = hit?;
+    match mode {
+        DebugMode::Normals => Some(hit.normal * 0.5 + Vec3([0.5; 3])),
+        DebugMode::Barycentric => Some(hit.barycentric),
+        DebugMode::Tangents => Some(hit.tangent * 0.5 + Vec3([0.5; 3])),
+        DebugMode::Albedo => match hit.geometry {
+            Geometry::Triangle(triangle) => Some(
+                scene
+                    .evaluate_material(
+                        &triangle,
+                        hit.tex_coord,
+                        hit.barycentric,
+                        hit.tangent,
+                        hit.bitangent,
+                    )
+                    .color,
+            ),
+            // Lights have no material
+            Geometry::PointLight(_) => Some(Vec3([1.0; 3])),
+        },
+        DebugMode::Depth => {
+            let (aabb_min, aabb_max) = ray_tracer.scene_aabb();
+            let scene_size = (aabb_max - aabb_min).len();
+            let distance = hit.lambda * ray.len();
+            Some(Vec3([(1.0 - distance / scene_size).max(0.0); 3]))
+        }
+        DebugMode::Heat => unreachable!(),
+    }
 }
 
-fn handle_ray<'a, R: Rng>(
-    scene: &'a Scene,
+// Returns the color seen along the ray which led to the given hit. Chains of reflections are
+// followed in a loop rather than recursively, so mirror corridors cannot overflow the stack.
+fn shade_hit<R: Rng>(
+    scene: &Scene,
     rng: &mut R,
-    origin: Vec3,
+    settings: &RenderSettings,
     ray: Vec3,
-    lambda_min: f64,
+    hit: Option<RayShootResult>,
     max_bounces: usize,
     ray_tracer: &mut RayTracer,
 ) -> Option<Vec3> {
-    assert!(max_bounces != std::usize::MAX);
-
-    if let Some(RayShootResult { geometry, normal: n, position: p, tex_coord, .. }) =
-        ray_tracer.trace_ray(origin, ray, lambda_min, INFINITY)
-    {
-        match geometry {
-            Geometry::Triangle(triangle) => {
-                let r = reflect_ray(ray.normalize(), n);
-                let bsdf = scene.evaluate_material(&triangle, tex_coord);
-                let bsdf = if max_bounces == 0 { anti_bounce_material(&bsdf) } else { bsdf };
-                let mut result_color = Vec3([0.0; 3]);
-
-                let mut specular = bsdf.specular;
-                if specular > EPS || bsdf.metallic > EPS {
-                    if let Some(color) =
-                        handle_ray(scene, rng, p, r, EPS, max_bounces - 1, ray_tracer)
-                    {
-                        let cos_n_ray = n.dot(r);
-                        specular = (specular + (1.0 - specular) * (1.0 - cos_n_ray).powi(5))
-                            * (1.0 - bsdf.metallic);
-                        result_color += color * (Vec3([specular; 3]) + bsdf.color * bsdf.metallic);
-                    }
-                }
-
-                let diffuse = 1.0 - bsdf.metallic - specular;
-                if diffuse > EPS {
-                    for point_light in &scene.point_lights {
-                        let (light_ray, light_dist) = (point_light.position - p).normalize_len();
-                        let cos_n_light_ray = n.dot(light_ray);
-                        if cos_n_light_ray <= 0.0 {
-                            continue;
-                        }
-
-                        let sample_size = 20;
-                        for _ in 0..sample_size {
-                            // sample from circle
-                            let (r, phi) = (
-                                rng.sample(rand::distributions::Uniform::new_inclusive(
-                                    0.0f64, 1.0,
-                                ))
-                                .sqrt()
-                                    * point_light.radius,
-                                rng.sample(rand::distributions::Uniform::new(0.0, 2.0 * PI)),
-                            );
-
-                            let circle_radius_vec =
-                                Vec3([light_ray.0[1], -light_ray.0[0], light_ray.0[2]]);
-                            let sample_dest = point_light.position
-                                + r * (Mat4::rotation_around_vector(light_ray, phi)
-                                    * circle_radius_vec.xyz0())
-                                .xyz();
-
-                            let light_shoot_result =
-                                ray_tracer.trace_ray(p, sample_dest - p, EPS, 1.0);
-                            if let Some(RayShootResult {
-                                geometry: Geometry::Triangle(_), ..
-                            }) = light_shoot_result
-                            {
-                                continue;
-                            }
-
-                            let attenuation = 1.0 + light_dist * light_dist;
-                            result_color += (bsdf.color * point_light.color)
-                                * (cos_n_light_ray * diffuse
-                                    / attenuation
-                                    / f64::from(sample_size));
-                        }
-                    }
-                }
-
-                Some(result_color)
+    assert!(max_bounces != usize::MAX);
+
+    // Reused across calls, taken so that the ray tracer can trace while the chain is collected
+    let mut surfaces = ray_tracer.take_surfaces();
+    let (mut ray, mut hit, mut bounces) = (ray, hit, max_bounces);
+    // The color seen by the reflection of the last surface, or by the ray itself if it hit none
+    let mut reflected = loop {
+        let RayShootResult {
+            geometry,
+            normal: n,
+            position: p,
+            tex_coord,
+            barycentric,
+            tangent,
+            bitangent,
+            ..
+        } = match hit {
+            Some(hit) => hit,
+            None => break None,
+        };
+        let triangle = match geometry {
+            Geometry::Triangle(triangle) => triangle,
+            Geometry::PointLight(point_light) => break Some(light_radiance(&point_light)),
+        };
+
+        let r = reflect_ray(ray.normalize(), n);
+        let bsdf = scene.evaluate_material(&triangle, tex_coord, barycentric, tangent, bitangent);
+        let bsdf = if bounces == 0 { anti_bounce_material(&bsdf) } else { bsdf };
+        let reflects = bsdf.specular > EPS || bsdf.metallic > EPS;
+        surfaces.push(Surface { bsdf, position: p, normal: n, reflected_ray: r });
+        if !reflects {
+            break None;
+        }
+        hit = ray_tracer.trace_ray(RayKind::Reflection, p, r, RAY_BIAS, f64::INFINITY);
+        ray = r;
+        bounces -= 1;
+    };
+
+    // How much of each surface is diffuse depends on whether its reflection hit anything, so the
+    // surfaces are shaded from the last one back to the first. This also samples the lights in
+    // the same order as a recursive implementation would.
+    for surface in surfaces.drain(..).rev() {
+        reflected = Some(shade_surface(rng, settings, ray_tracer, &surface, reflected));
+    }
+    ray_tracer.recycle_surfaces(surfaces);
+    reflected
+}
+
+// A surface along a chain of reflections, waiting to be shaded until the color seen by its
+// reflection is known
+pub struct Surface {
+    bsdf: Bsdf,
+    position: Vec3,
+    normal: Vec3,
+    reflected_ray: Vec3,
+}
+
+// Returns the color of the surface. reflected is the color seen by its reflection, None if the
+// surface does not reflect or its reflection hit nothing.
+fn shade_surface<R: Rng>(
+    rng: &mut R,
+    settings: &RenderSettings,
+    ray_tracer: &mut RayTracer,
+    surface: &Surface,
+    reflected: Option<Vec3>,
+) -> Vec3 {
+    let Surface { bsdf, position: p, normal: n, reflected_ray: r } = *surface;
+    let mut result_color = bsdf.emission;
+
+    let mut specular = bsdf.specular;
+    if let Some(color) = reflected {
+        let cos_n_ray = n.dot(r);
+        specular =
+            (specular + (1.0 - specular) * (1.0 - cos_n_ray).powi(5)) * (1.0 - bsdf.metallic);
+        result_color += color * (specular_color(&bsdf) * specular + bsdf.color * bsdf.metallic);
+    }
+
+    let diffuse = 1.0 - bsdf.metallic - specular;
+    if diffuse > EPS {
+        let lights = ray_tracer.find_lights(p);
+
+        let light_samples = settings.light_samples as usize;
+        let irradiance = if light_samples == 0 || lights.len() <= light_samples {
+            let mut irradiance = Vec3([0.0; 3]);
+            for point_light in &lights {
+                irradiance += sample_light(rng, settings, ray_tracer, p, n, point_light);
+            }
+            irradiance
+        } else {
+            sample_lights_by_importance(rng, settings, &lights, ray_tracer, p, n)
+        };
+        ray_tracer.recycle_lights(lights);
+        result_color += bsdf.color * irradiance * diffuse;
+    }
+    result_color
+}
+
+// Picks settings.light_samples lights with a probability proportional to their estimated
+// unoccluded contribution and weights each by the inverse of its pick probability. The weights are
+// recomputed for every pick rather than collected, as they are cheap and this runs per shading
+// point.
+fn sample_lights_by_importance<R: Rng>(
+    rng: &mut R,
+    settings: &RenderSettings,
+    lights: &[&PointLight],
+    ray_tracer: &mut RayTracer,
+    p: Vec3,
+    n: Vec3,
+) -> Vec3 {
+    let weight = |point_light: &PointLight| {
+        let to_light = point_light.position - p;
+        let color = point_light.color;
+        (color.x() + color.y() + color.z()) / (1.0 + to_light.sqlen())
+    };
+    let weight_sum: f64 = lights.iter().map(|point_light| weight(point_light)).sum();
+    if weight_sum <= 0.0 {
+        return Vec3([0.0; 3]);
+    }
+
+    let mut irradiance = Vec3([0.0; 3]);
+    for _ in 0..settings.light_samples {
+        let mut pick = rng.sample(rand::distributions::Uniform::new(0.0, weight_sum));
+        let mut picked = lights.len() - 1;
+        for (i, point_light) in lights.iter().enumerate() {
+            let light_weight = weight(point_light);
+            if pick < light_weight {
+                picked = i;
+                break;
             }
-            Geometry::PointLight(point_light) => Some(point_light.color),
+            pick -= light_weight;
+        }
+        let picked_weight = weight(lights[picked]);
+        if picked_weight <= 0.0 {
+            continue;
         }
-    } else {
-        None
+
+        let probability = picked_weight / weight_sum;
+        irradiance += sample_light(rng, settings, ray_tracer, p, n, lights[picked])
+            / (probability * f64::from(settings.light_samples));
     }
+    irradiance
+}
+
+// Returns the light arriving at p from the given light, taking soft shadows into account.
+fn sample_light<R: Rng>(
+    rng: &mut R,
+    settings: &RenderSettings,
+    ray_tracer: &mut RayTracer,
+    p: Vec3,
+    n: Vec3,
+    point_light: &PointLight,
+) -> Vec3 {
+    let (light_ray, light_dist) = (point_light.position - p).normalize_len();
+    // This also rejects every light whose bounding sphere lies completely behind the surface
+    let cos_n_light_ray = n.dot(light_ray);
+    if cos_n_light_ray <= 0.0 {
+        return Vec3([0.0; 3]);
+    }
+
+    let attenuation = 1.0 + light_dist * light_dist;
+    let color = point_light.color;
+    if (color.x() + color.y() + color.z()) / 3.0 / attenuation < settings.light_cutoff {
+        // Same estimate as the light BVH, whose AABBs are only a conservative bound
+        return Vec3([0.0; 3]);
+    }
+    let light_color = point_light.color * (cos_n_light_ray / attenuation);
+
+    if settings.hard_shadows || point_light.radius.almost_zero() {
+        // A true point light casts hard shadows, so a single ray towards its center suffices
+        if ray_tracer.trace_ray_any(p, point_light.position - p, RAY_BIAS, 1.0) {
+            return Vec3([0.0; 3]);
+        }
+        return light_color;
+    }
+
+    let mut result = Vec3([0.0; 3]);
+    let sample_size = settings.shadow_samples;
+    // The disk of the light facing p
+    let (disk_x, disk_y) = light_ray.orthonormal_basis();
+    for _ in 0..sample_size {
+        let (x, y) = uniform_disk(rng);
+        let sample_dest = point_light.position + (disk_x * x + disk_y * y) * point_light.radius;
+
+        // For lights grazing the surface, part of the disk is below it and cannot light p
+        if n.dot(sample_dest - p) <= 0.0 {
+            continue;
+        }
+        if ray_tracer.trace_ray_any(p, sample_dest - p, RAY_BIAS, 1.0) {
+            continue;
+        }
+
+        result += light_color / f64::from(sample_size);
+    }
+    result
+}
+
+// The light leaving each point of the light's sphere. Its color is the intensity which lights the
+// scene in sample_light, and seen from afar the sphere is a disk of area πr², so the surface has
+// to be dimmer by that area to give off the same amount of light.
+fn light_radiance(point_light: &PointLight) -> Vec3 {
+    if point_light.radius.almost_zero() {
+        // Rays cannot hit a true point light anyway
+        return point_light.color;
+    }
+    point_light.color / (PI * point_light.radius * point_light.radius)
 }
 
 fn reflect_ray(ray: Vec3, n: Vec3) -> Vec3 {
@@ -111,7 +364,15 @@ fn reflect_ray(ray: Vec3, n: Vec3) -> Vec3 {
 }
 
 fn anti_bounce_material(bsdf: &Bsdf) -> Bsdf {
-    Bsdf { color: bsdf.color, specular: 0.0, metallic: 0.0 }
+    Bsdf { specular: 0.0, specular_tint: 0.0, metallic: 0.0, ..*bsdf }
+}
+
+// White, blended towards the hue of the base color by the specular tint like Blender does
+fn specular_color(bsdf: &Bsdf) -> Vec3 {
+    let white = Vec3([1.0; 3]);
+    let luminance = 0.2126 * bsdf.color.x() + 0.7152 * bsdf.color.y() + 0.0722 * bsdf.color.z();
+    let tint = if luminance > 0.0 { bsdf.color * (1.0 / luminance) } else { white };
+    white * (1.0 - bsdf.specular_tint) + tint * bsdf.specular_tint
 }
 
 fn calc_ray(camera: &Camera, x: f64, y: f64, width: f64, height: f64) -> Vec3 {
@@ -126,3 +387,245 @@ fn calc_ray(camera: &Camera, x: f64, y: f64, width: f64, height: f64) -> Vec3 {
     };
     point_on_plane - camera.position
 }
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::super::scene_bvh::SceneBvh;
+    use super::super::AaPattern;
+    use super::*;
+    use crate::math::{Vec2, Vec4};
+    use crate::scene::{
+        bsdf_principled, FovAxis, GraphBuilder, Link, Projection, Triangle, Vertex,
+    };
+    use rand::SeedableRng;
+
+    pub fn settings() -> RenderSettings {
+        RenderSettings {
+            shadow_samples: 4,
+            hard_shadows: false,
+            light_samples: 0,
+            light_cutoff: 0.0,
+            packet_tracing: false,
+            aa_pattern: AaPattern::Rgss,
+            debug: None,
+            report_bad_samples: false,
+            antialiasing: 1,
+            supersample: 1,
+            preview: false,
+            aovs: vec![],
+            seed: 42,
+            thread_count: 1,
+        }
+    }
+
+    // Renders the scene at its size of 8x6 pixels
+    fn context<'a>(scene: &'a Scene, settings: &'a RenderSettings) -> SampleContext<'a> {
+        SampleContext { scene, camera: &scene.camera, width: 8.0, height: 6.0, settings }
+    }
+
+    // A corridor between a floor at z = 0 and a ceiling at z = 2, both half mirrors, reaching
+    // from -10 to 10 along x and y. A soft light hangs in the middle. A board at z = 0.5 between
+    // the light and the floor in front of the camera casts a penumbra, where the color depends on
+    // the random shadow samples. The camera looks down the corridor along x.
+    pub fn mirror_scene() -> Scene {
+        let mut scene = Scene {
+            camera: Camera::new(
+                Vec3([-9.0, 0.0, 1.0]),
+                Vec3([1.0, 0.0, 0.0]),
+                Vec3([0.0, 0.0, 1.0]),
+                Vec3([0.0, 1.0, 0.0]),
+                Projection {
+                    fov: 90f64.to_radians(),
+                    fov_axis: FovAxis::Horizontal,
+                    znear: 0.1,
+                    zfar: f64::INFINITY,
+                    pixel_aspect: 1.0,
+                },
+                8,
+                6,
+            ),
+            triangles: vec![],
+            meshes: vec![],
+            instances: vec![],
+            point_lights: vec![PointLight {
+                position: Vec3([0.0, 0.0, 1.0]),
+                color: Vec3([20.0, 20.0, 20.0]),
+                radius: 0.3,
+                a: 0.0,
+                b: 0.0,
+                c: 1.0,
+            }],
+            light_names: vec!["light".to_owned()],
+            materials: vec![],
+            images: vec![],
+        };
+        // A rectangle at height z from (x0, y0) to (x1, y1), facing up or down
+        let add_rectangle = |scene: &mut Scene, (x0, y0), (x1, y1), z, normal_z, material| {
+            let corner = |x: f64, y: f64| Vertex {
+                position: Vec3([x, y, z]),
+                normal: Vec3([0.0, 0.0, normal_z]),
+                tex_coord: Vec2([x, y]),
+                object_position: Vec3([x, y, z]),
+                tangent: None,
+            };
+            let corners = [corner(x0, y0), corner(x1, y0), corner(x1, y1), corner(x0, y1)];
+            scene
+                .triangles
+                .push(Triangle::new(corners[0], corners[1], corners[2], material, false));
+            scene
+                .triangles
+                .push(Triangle::new(corners[0], corners[2], corners[3], material, false));
+        };
+        for (z, normal_z, color) in [(0.0, 1.0, 0.8), (2.0, -1.0, 0.5)].iter() {
+            let mut graph = GraphBuilder::new();
+            let bsdf = graph.add(bsdf_principled::Node {
+                base_color: Link::Constant(Vec4([*color, *color, *color, 1.0])),
+                specular: Link::Constant(0.5),
+                specular_tint: Link::Constant(0.0),
+                metallic: Link::Constant(0.5),
+                emission: Link::Constant(Vec4([0.0, 0.0, 0.0, 1.0])),
+                emission_strength: Link::Constant(1.0),
+            });
+            let material = scene.materials.len();
+            scene.materials.push(graph.material(bsdf.bsdf(), false));
+            add_rectangle(&mut scene, (-10.0, -10.0), (10.0, 10.0), *z, *normal_z, material);
+        }
+        // Facing the floor, whose shadow rays would pass through its back. It stays clear of the
+        // ray of shade_hit_matches_recursion, which is at y > 1 there.
+        add_rectangle(&mut scene, (-3.8, -0.8), (-1.0, 0.8), 0.5, -1.0, 0);
+        scene
+    }
+
+    // shade_hit as it was before it followed reflections in a loop
+    fn shade_hit_recursive<R: Rng>(
+        scene: &Scene,
+        rng: &mut R,
+        settings: &RenderSettings,
+        ray: Vec3,
+        hit: Option<RayShootResult>,
+        max_bounces: usize,
+        ray_tracer: &mut RayTracer,
+    ) -> Option<Vec3> {
+        let hit = hit?;
+        let triangle = match hit.geometry {
+            Geometry::Triangle(triangle) => triangle,
+            Geometry::PointLight(point_light) => return Some(light_radiance(&point_light)),
+        };
+        let r = reflect_ray(ray.normalize(), hit.normal);
+        let bsdf = scene.evaluate_material(
+            &triangle,
+            hit.tex_coord,
+            hit.barycentric,
+            hit.tangent,
+            hit.bitangent,
+        );
+        let bsdf = if max_bounces == 0 { anti_bounce_material(&bsdf) } else { bsdf };
+        let reflected = if bsdf.specular > EPS || bsdf.metallic > EPS {
+            let reflected_hit =
+                ray_tracer.trace_ray(RayKind::Reflection, hit.position, r, RAY_BIAS, f64::INFINITY);
+            shade_hit_recursive(scene, rng, settings, r, reflected_hit, max_bounces - 1, ray_tracer)
+        } else {
+            None
+        };
+        let surface =
+            Surface { bsdf, position: hit.position, normal: hit.normal, reflected_ray: r };
+        Some(shade_surface(rng, settings, ray_tracer, &surface, reflected))
+    }
+
+    #[test]
+    fn shade_hit_matches_recursion() {
+        let scene = mirror_scene();
+        let bvh = SceneBvh::new(&scene);
+        let settings = settings();
+        let origin = Vec3([-9.5, 0.0, 1.0]);
+        // Hits floor and ceiling every two units along x, ten times until it leaves the corridor
+        let ray = Vec3([1.0, 0.2, -1.0]);
+        for max_bounces in [0, 3, 1024].iter() {
+            let mut colors = vec![];
+            for recursive in [false, true].iter() {
+                let mut ray_tracer = RayTracer::new(&bvh, None, &scene.point_lights);
+                let mut rng = rand_pcg::Pcg32::seed_from_u64(7);
+                let hit = ray_tracer.trace_ray(RayKind::Primary, origin, ray, 0.0, f64::INFINITY);
+                let color = if *recursive {
+                    shade_hit_recursive(
+                        &scene,
+                        &mut rng,
+                        &settings,
+                        ray,
+                        hit,
+                        *max_bounces,
+                        &mut ray_tracer,
+                    )
+                } else {
+                    shade_hit(&scene, &mut rng, &settings, ray, hit, *max_bounces, &mut ray_tracer)
+                };
+                let reflections = ray_tracer.stats().reflection_rays;
+                assert_eq!(reflections as usize, (*max_bounces).min(10));
+                colors.push((color, reflections));
+            }
+            assert!(colors[0].0.is_some());
+            assert_eq!(colors[0], colors[1]);
+        }
+    }
+
+    // Which pixels of the middle row see a wall that recedes from a depth of 5 - y / 2 at the left
+    // to 5 + y / 2 at the right, with the camera at the origin looking along x
+    fn visible_wall(znear: f64, zfar: f64) -> Vec<bool> {
+        let mut scene = mirror_scene();
+        scene.camera = Camera::new(
+            Vec3([0.0, 0.0, 0.0]),
+            Vec3([1.0, 0.0, 0.0]),
+            Vec3([0.0, 0.0, 1.0]),
+            Vec3([0.0, 1.0, 0.0]),
+            Projection {
+                fov: 90f64.to_radians(),
+                fov_axis: FovAxis::Horizontal,
+                znear,
+                zfar,
+                pixel_aspect: 1.0,
+            },
+            8,
+            6,
+        );
+        let corner = |y: f64, z: f64| Vertex {
+            position: Vec3([5.0 - y / 2.0, y, z]),
+            normal: Vec3([-1.0, 0.0, 0.0]),
+            tex_coord: Vec2([y, z]),
+            object_position: Vec3([5.0 - y / 2.0, y, z]),
+            tangent: None,
+        };
+        let corners = [corner(-20.0, -20.0), corner(20.0, -20.0), corner(0.0, 20.0)];
+        scene.triangles = vec![Triangle::new(corners[0], corners[1], corners[2], 0, true)];
+        scene.point_lights.clear();
+        let bvh = SceneBvh::new(&scene);
+        let mut ray_tracer = RayTracer::new(&bvh, None, &scene.point_lights);
+        let settings = settings();
+        (0..8)
+            .map(|x| primary_hit(&context(&scene, &settings), x as f64, 3.0, &mut ray_tracer))
+            .map(|hit| hit.is_some())
+            .collect()
+    }
+
+    #[test]
+    fn primary_hit_is_not_counted() {
+        let scene = mirror_scene();
+        let bvh = SceneBvh::new(&scene);
+        let mut ray_tracer = RayTracer::new(&bvh, None, &scene.point_lights);
+        // The bottom row looks at the floor
+        let settings = settings();
+        let hit = primary_hit(&context(&scene, &settings), 4.0, 5.0, &mut ray_tracer);
+        assert!(matches!(hit, Some(Geometry::Triangle(_))));
+        let stats = ray_tracer.stats();
+        assert_eq!((stats.rays(), stats.hits), (0, 0));
+    }
+
+    #[test]
+    fn primary_rays_are_clipped() {
+        // The pixel centers see the wall at depths of 3.5, 3.8, 4.2, 4.7, 5.3, 6.2, 7.3 and 8.9
+        assert_eq!(visible_wall(0.1, f64::INFINITY), vec![true; 8]);
+        // The wall straddles the far plane in the middle of the image
+        assert_eq!(visible_wall(0.1, 5.0), [vec![true; 4], vec![false; 4]].concat());
+        assert_eq!(visible_wall(5.0, f64::INFINITY), [vec![false; 4], vec![true; 4]].concat());
+        assert_eq!(visible_wall(4.0, 6.0), [false, false, true, true, true, false, false, false]);
+    }
+}
@@ -0,0 +1,144 @@
+// Samples shared by the renderer. Directions are given in a local frame whose z axis is the normal,
+// see Vec3::orthonormal_basis for turning them into world space.
+use crate::math::Vec3;
+use rand::Rng;
+use std::f64::consts::PI;
+
+// The offset of the subpixel (x, y) from the center of its subpixel when using RGSS, in units of a
+// subpixel of the next coarser level
+pub fn rgss_offset(x: usize, y: usize) -> (f64, f64) {
+    [
+        (-1.0 / 8.0, 1.0 / 8.0),  // x % 2 == 0 && y % 2 == 0  =>  top-left
+        (-1.0 / 8.0, -1.0 / 8.0), // x % 2 == 1 && y % 2 == 0  =>  top-right
+        (1.0 / 8.0, 1.0 / 8.0),   // x % 2 == 0 && y % 2 == 1  =>  bottom-left
+        (1.0 / 8.0, -1.0 / 8.0),  // x % 2 == 1 && y % 2 == 1  =>  bottom-right
+    ][(x % 2) + 2 * (y % 2)]
+}
+
+// A point uniformly distributed on the unit disk
+pub fn uniform_disk<R: Rng>(rng: &mut R) -> (f64, f64) {
+    let r = rng.gen::<f64>().sqrt();
+    let phi = 2.0 * PI * rng.gen::<f64>();
+    (r * phi.cos(), r * phi.sin())
+}
+
+// A direction on the hemisphere around z with a density proportional to its cosine with z. Unused
+// so far, like the samplers below, as there is neither GI nor glossy reflection yet.
+#[allow(dead_code)]
+pub fn cosine_hemisphere<R: Rng>(rng: &mut R) -> Vec3 {
+    let (x, y) = uniform_disk(rng);
+    Vec3([x, y, (1.0 - x * x - y * y).max(0.0).sqrt()])
+}
+
+// A direction uniformly distributed over all directions
+#[allow(dead_code)]
+pub fn uniform_sphere<R: Rng>(rng: &mut R) -> Vec3 {
+    let z = 1.0 - 2.0 * rng.gen::<f64>();
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = 2.0 * PI * rng.gen::<f64>();
+    Vec3([r * phi.cos(), r * phi.sin(), z])
+}
+
+// A microfacet normal of the GGX distribution with roughness alpha, as seen from the unit
+// direction view above the surface (Heitz, "Sampling the GGX Distribution of Visible Normals")
+#[allow(dead_code)]
+pub fn ggx_visible_normal<R: Rng>(rng: &mut R, view: Vec3, alpha: f64) -> Vec3 {
+    // Stretch the view into the configuration where the microfacets form a hemisphere
+    let view = Vec3([alpha * view.x(), alpha * view.y(), view.z()]).normalize();
+    let len_xy = (view.x() * view.x() + view.y() * view.y()).sqrt();
+    let t1 = if len_xy > 0.0 {
+        Vec3([-view.y() / len_xy, view.x() / len_xy, 0.0])
+    } else {
+        Vec3([1.0, 0.0, 0.0])
+    };
+    let t2 = view.cross(t1);
+    // Sample the projection of the hemisphere, part of which is hidden by its own horizon
+    let (x, y) = uniform_disk(rng);
+    let s = 0.5 * (1.0 + view.z());
+    let y = (1.0 - s) * (1.0 - x * x).sqrt() + s * y;
+    let normal = t1 * x + t2 * y + view * (1.0 - x * x - y * y).max(0.0).sqrt();
+    // And unstretch
+    Vec3([alpha * normal.x(), alpha * normal.y(), normal.z().max(0.0)]).normalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::math::AlmostEq;
+    use rand::SeedableRng;
+
+    #[test]
+    fn uniform_disk_stays_within_radius() {
+        let mut rng = rand_pcg::Pcg32::seed_from_u64(3);
+        let points: Vec<_> = (0..10000).map(|_| uniform_disk(&mut rng)).collect();
+        assert!(points.iter().all(|(x, y)| x * x + y * y <= 1.0));
+        // Uniform over the area, so half of the points lie within a radius of sqrt(0.5)
+        let inner = points.iter().filter(|(x, y)| x * x + y * y <= 0.5).count();
+        assert!((4800..5200).contains(&inner), "{} points within sqrt(0.5)", inner);
+    }
+
+    fn mean(directions: &[Vec3]) -> Vec3 {
+        directions.iter().fold(Vec3([0.0; 3]), |sum, v| sum + *v) * (1.0 / directions.len() as f64)
+    }
+
+    #[test]
+    fn cosine_hemisphere_is_cosine_weighted() {
+        let mut rng = rand_pcg::Pcg32::seed_from_u64(5);
+        let directions: Vec<_> = (0..10000).map(|_| cosine_hemisphere(&mut rng)).collect();
+        assert!(directions.iter().all(|v| v.len().almost_eq_within(1.0, 1e-9) && v.z() >= 0.0));
+        // The mean of cos over the density cos / pi is 2 / 3, a uniform hemisphere would give 1 / 2
+        let z = mean(&directions).z();
+        assert!(z.almost_eq_within(2.0 / 3.0, 0.01), "mean z is {}", z);
+    }
+
+    #[test]
+    fn uniform_sphere_is_balanced() {
+        let mut rng = rand_pcg::Pcg32::seed_from_u64(6);
+        let directions: Vec<_> = (0..10000).map(|_| uniform_sphere(&mut rng)).collect();
+        assert!(directions.iter().all(|v| v.len().almost_eq_within(1.0, 1e-9)));
+        let mean = mean(&directions);
+        assert!(mean.len() < 0.02, "mean is {:?}", mean);
+        // Each half along z holds half of the directions
+        let upper = directions.iter().filter(|v| v.z() > 0.0).count();
+        assert!((4800..5200).contains(&upper), "{} directions above", upper);
+    }
+
+    #[test]
+    fn ggx_normals_face_up() {
+        let mut rng = rand_pcg::Pcg32::seed_from_u64(7);
+        for alpha in [0.01, 0.3, 1.0].iter() {
+            // Straight down onto the surface, at a grazing angle and in between
+            let views = [Vec3([0.0, 0.0, 1.0]), Vec3([0.999, 0.0, 0.045]), Vec3([-0.5, 0.5, 0.7])];
+            for view in views.iter() {
+                let view = view.normalize();
+                for _ in 0..1000 {
+                    let normal = ggx_visible_normal(&mut rng, view, *alpha);
+                    assert!(normal.len().almost_eq_within(1.0, 1e-9), "{:?}", normal);
+                    assert!(normal.z() >= 0.0, "{:?} for {:?} and {}", normal, view, alpha);
+                }
+            }
+        }
+        // Nearly smooth surfaces are hardly tilted
+        let normal = ggx_visible_normal(&mut rng, Vec3([0.6, 0.0, 0.8]), 1e-6);
+        assert!(normal.z().almost_eq_within(1.0, 1e-9), "{:?}", normal);
+    }
+
+    #[test]
+    fn orthonormal_basis_is_orthonormal() {
+        let mut rng = rand_pcg::Pcg32::seed_from_u64(4);
+        // Including the axes, around which the helper vector switches
+        let axes = [Vec3([1.0, 0.0, 0.0]), Vec3([0.0, 1.0, 0.0]), Vec3([0.0, 0.0, -1.0])];
+        let normals = axes.iter().cloned().chain((0..1000).map(|_| uniform_sphere(&mut rng)));
+        for normal in normals {
+            let (tangent, bitangent) = normal.orthonormal_basis();
+            for v in [normal, tangent, bitangent].iter() {
+                assert!(v.len().almost_eq_within(1.0, 1e-9), "{:?} from {:?}", v, normal);
+            }
+            for (a, b) in [(normal, tangent), (normal, bitangent), (tangent, bitangent)].iter() {
+                assert!(a.dot(*b).almost_zero_within(1e-9), "{:?} and {:?}", a, b);
+            }
+            // Right-handed, so local directions keep their orientation in world space
+            assert!((tangent.cross(bitangent) - normal).len().almost_zero_within(1e-9));
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use super::bvh::Bvh;
+use crate::math::{HasAABB, Mat4, Vec3};
+use crate::scene::{PointLight, Scene, Triangle};
+
+// Two-level BVH: a top-level BVH over the objects of the scene, each of which references a
+// bottom-level BVH over the triangles of a mesh. The few lights get a BVH of their own, so that
+// the leaves of the meshes only hold triangles.
+#[derive(Debug)]
+pub struct SceneBvh {
+    pub objects: Bvh<Object>,
+    pub meshes: Vec<Bvh<Triangle>>,
+    // The intersection data of the triangles of the mesh with the same index
+    pub mesh_triangles: Vec<TriangleData>,
+    // None if the scene has no lights
+    pub lights: Option<Bvh<PointLight>>,
+    // Whether the material with the same index is double-sided
+    pub double_sided: Vec<bool>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Object {
+    pub mesh: usize,
+    // None if the mesh is already in world space
+    pub transform: Option<ObjectTransform>,
+    aabb: (Vec3, Vec3),
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct ObjectTransform {
+    pub matrix: Mat4,
+    pub inverse: Mat4,
+    pub normal_matrix: Mat4,
+    // -1 if the matrix mirrors, which flips the bitangent relative to normal x tangent
+    pub bitangent_sign: f64,
+}
+
+// What the intersection test needs of a mesh's triangles, indexed like the values of the mesh's
+// BVH. Stored as arrays apart from the triangles, so that testing a leaf only touches the few
+// cache lines the test needs and the full Triangle is only read for a hit.
+#[derive(Debug, Default)]
+pub struct TriangleData {
+    // The corner a of each triangle
+    base: Vec<Vec3>,
+    // b - a and c - a
+    edge_ab: Vec<Vec3>,
+    edge_ac: Vec<Vec3>,
+    // edge_ab x edge_ac, normal to the plane with a length of twice the area
+    normal: Vec<Vec3>,
+}
+
+impl TriangleData {
+    fn new(triangles: &[Triangle]) -> TriangleData {
+        let mut data = TriangleData::default();
+        for triangle in triangles {
+            let a = triangle.a().position;
+            let (edge_ab, edge_ac) = (triangle.b().position - a, triangle.c().position - a);
+            data.base.push(a);
+            data.edge_ab.push(edge_ab);
+            data.edge_ac.push(edge_ac);
+            data.normal.push(edge_ab.cross(edge_ac));
+        }
+        data
+    }
+
+    // Triangle::intersect_edges for the i-th triangle
+    #[inline(always)]
+    pub fn intersect(&self, i: usize, ray_origin: Vec3, ray: Vec3) -> Option<(f64, Vec3)> {
+        Triangle::intersect_edges(
+            self.base[i],
+            self.edge_ab[i],
+            self.edge_ac[i],
+            self.normal[i],
+            ray_origin,
+            ray,
+        )
+    }
+}
+
+impl HasAABB for Object {
+    fn calculate_aabb(&self) -> (Vec3, Vec3) {
+        self.aabb
+    }
+}
+
+impl SceneBvh {
+    pub fn new(scene: &Scene) -> SceneBvh {
+        let mut objects = vec![];
+        let mut meshes = vec![];
+
+        // Everything which is not instanced forms a single object in world space
+        if !scene.triangles.is_empty() {
+            let bvh = Bvh::new(&scene.triangles);
+            objects.push(Object { mesh: meshes.len(), transform: None, aabb: bvh.aabb() });
+            meshes.push(bvh);
+        }
+
+        let mesh_offset = meshes.len();
+        for mesh in &scene.meshes {
+            meshes.push(Bvh::new(mesh));
+        }
+        for instance in &scene.instances {
+            let mesh = mesh_offset + instance.mesh;
+            let matrix = instance.transform;
+            let inverse = matrix.inv();
+            let (local_min, local_max) = meshes[mesh].aabb();
+            let mut aabb_min = Vec3([f64::INFINITY; 3]);
+            let mut aabb_max = Vec3([f64::NEG_INFINITY; 3]);
+            for corner in 0..8 {
+                let local_corner = Vec3([
+                    if corner & 1 == 0 { local_min.x() } else { local_max.x() },
+                    if corner & 2 == 0 { local_min.y() } else { local_max.y() },
+                    if corner & 4 == 0 { local_min.z() } else { local_max.z() },
+                ]);
+                let world_corner = (matrix * local_corner.xyz1()).xyz();
+                aabb_min = aabb_min.min(world_corner);
+                aabb_max = aabb_max.max(world_corner);
+            }
+            objects.push(Object {
+                mesh,
+                transform: Some(ObjectTransform {
+                    matrix,
+                    inverse,
+                    normal_matrix: inverse.transpose(),
+                    bitangent_sign: matrix.det().signum(),
+                }),
+                aabb: (aabb_min, aabb_max),
+            });
+        }
+
+        let double_sided = scene.materials.iter().map(|material| material.double_sided).collect();
+
+        let lights =
+            if scene.point_lights.is_empty() { None } else { Some(Bvh::new(&scene.point_lights)) };
+
+        let mesh_triangles = meshes.iter().map(|mesh| TriangleData::new(mesh.values())).collect();
+
+        SceneBvh { objects: Bvh::new(&objects), meshes, mesh_triangles, lights, double_sided }
+    }
+
+    // Logs the depth and the memory use of all BVHs
+    pub fn log_stats(&self) {
+        let mut bvhs = vec![(
+            "objects",
+            self.objects.layer_count(),
+            self.objects.node_counts(),
+            self.objects.memory_size(),
+        )];
+        // All meshes in one line, with the deepest one's layer count
+        bvhs.push((
+            "meshes",
+            self.meshes.iter().map(Bvh::layer_count).max().unwrap_or(0),
+            self.meshes.iter().map(Bvh::node_counts).fold((0, 0), |a, b| (a.0 + b.0, a.1 + b.1)),
+            self.meshes.iter().map(Bvh::memory_size).sum(),
+        ));
+        if let Some(lights) = &self.lights {
+            bvhs.push(("lights", lights.layer_count(), lights.node_counts(), lights.memory_size()));
+        }
+        for (name, layer_count, (allocated, used), memory_size) in bvhs {
+            log_verbose!(
+                "BVH {}: {} layers, {} of {} nodes used, {:.1} MiB",
+                name,
+                layer_count,
+                used,
+                allocated,
+                memory_size as f64 / (1024.0 * 1024.0)
+            );
+        }
+    }
+
+    // The AABB enclosing all triangles and lights
+    pub fn aabb(&self) -> (Vec3, Vec3) {
+        let (mut aabb_min, mut aabb_max) = self.objects.aabb();
+        if let Some(lights) = &self.lights {
+            let (lights_min, lights_max) = lights.aabb();
+            aabb_min = aabb_min.min(lights_min);
+            aabb_max = aabb_max.max(lights_max);
+        }
+        (aabb_min, aabb_max)
+    }
+}